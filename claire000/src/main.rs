@@ -3,6 +3,7 @@ mod models;
 mod templates;
 
 use axum::{
+    extract::DefaultBodyLimit,
     routing::{get, post},
     Router,
 };
@@ -22,7 +23,8 @@ async fn main() {
         .route("/new", post(handlers::new_thread))
         .route("/reply/{id}", post(handlers::reply))
         .nest_service("/uploads", ServeDir::new("uploads"))
-        .nest_service("/static", ServeDir::new("static"));
+        .nest_service("/static", ServeDir::new("static"))
+        .layer(DefaultBodyLimit::max(handlers::MAX_BODY_SIZE));
 
     let addr = SocketAddr::from(([127, 0, 0, 1], 8080)); // Port 8080
     println!("Listening on http://{}", addr);
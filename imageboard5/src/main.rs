@@ -1,8 +1,8 @@
 use actix_files::Files;
 use actix_web::{
-    error::{ErrorBadRequest, ErrorInternalServerError},
+    error::{ErrorBadRequest, ErrorInternalServerError, ErrorNotFound},
     middleware::{DefaultHeaders, Logger},
-    web, App, Error, HttpResponse, HttpServer, Responder,
+    web, App, Error, HttpRequest, HttpResponse, HttpServer, Responder,
 };
 use chrono::Utc;
 use futures_util::StreamExt;
@@ -11,30 +11,52 @@ use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::{params, Connection, OpenFlags};
 use serde::Deserialize;
-use std::{fs, io::Write, path::{Path, PathBuf}};
+use std::{fs, io::Write, path::{Component, Path, PathBuf}, time::Duration};
 use uuid::Uuid;
 
+mod markup;
 mod templates;
-use templates::{BoardTemplate, PostFormTemplate};
+use templates::{BoardTemplate, PostFormTemplate, TrashTemplate, TrashedReply, TrashedThread};
 
 const MAX_FILE_BYTES: usize = 50 * 1024 * 1024;
 const PREVIEW_CHARS: usize = 250;
 const ADMIN_PASSWORD: &str = "yoursecretadminpassword"; // CHANGE THIS
+const TRASH_DAYS: i64 = 30; // Default; the TRASH_DAYS env var overrides it
 
 type SqlitePool = Pool<SqliteConnectionManager>;
 
+// Every admin deletion is written here, with a copy of what was deleted.
+// Nothing ever updates or deletes these rows.
+const MOD_LOG_SCHEMA: &str = r#"
+    CREATE TABLE IF NOT EXISTS mod_log (
+        id          INTEGER PRIMARY KEY AUTOINCREMENT,
+        created_at  INTEGER NOT NULL,
+        actor       TEXT NOT NULL,
+        action      TEXT NOT NULL,
+        target_id   INTEGER NOT NULL,
+        subject     TEXT,
+        message     TEXT,
+        filename    TEXT,
+        replies     INTEGER
+    );
+"#;
+
 struct BoardPaths {
     dir: PathBuf,
     db: PathBuf,
     uploads: PathBuf,
+    // Files of deleted threads wait here, out of public view, until they
+    // are restored or purged.
+    trash: PathBuf,
 }
 
 fn get_board_paths(board: &str) -> BoardPaths {
-    let safe_board = board.replace('.', "").replace('/', "");
+    let safe_board = board.replace(['.', '/'], "");
     let dir = PathBuf::from(format!("chess/{}", safe_board));
     let db = dir.join("db.sqlite");
     let uploads = dir.join("uploads");
-    BoardPaths { dir, db, uploads }
+    let trash = dir.join("trash");
+    BoardPaths { dir, db, uploads, trash }
 }
 
 fn ensure_board_init(paths: &BoardPaths) -> Result<(), std::io::Error> {
@@ -44,6 +66,9 @@ fn ensure_board_init(paths: &BoardPaths) -> Result<(), std::io::Error> {
     if !paths.uploads.exists() {
         fs::create_dir_all(&paths.uploads)?;
     }
+    if !paths.trash.exists() {
+        fs::create_dir_all(&paths.trash)?;
+    }
     if !paths.db.exists() {
         let conn = Connection::open(&paths.db).expect("create db");
         conn.execute_batch(
@@ -70,9 +95,51 @@ fn ensure_board_init(paths: &BoardPaths) -> Result<(), std::io::Error> {
             "#,
         ).expect("init schema");
     }
+    let conn = Connection::open(&paths.db).map_err(std::io::Error::other)?;
+    upgrade_schema(&conn).map_err(std::io::Error::other)?;
+    Ok(())
+}
+
+// Boards created by older versions lack later tables and columns;
+// `user_version` records which of these steps a board's database has had.
+fn upgrade_schema(conn: &Connection) -> rusqlite::Result<()> {
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |r| r.get(0))?;
+    if version < 1 {
+        conn.execute_batch(MOD_LOG_SCHEMA)?;
+        // Deleted posts and replies keep their rows, marked with the time
+        // of deletion, until the purge removes them.
+        conn.execute_batch(
+            r#"
+            BEGIN;
+            ALTER TABLE posts ADD COLUMN deleted_at INTEGER;
+            ALTER TABLE replies ADD COLUMN deleted_at INTEGER;
+            PRAGMA user_version = 1;
+            COMMIT;
+            "#,
+        )?;
+    }
     Ok(())
 }
 
+fn trash_days() -> i64 {
+    std::env::var("TRASH_DAYS").ok().and_then(|d| d.parse().ok()).unwrap_or(TRASH_DAYS)
+}
+
+fn format_time(ts: i64) -> String {
+    chrono::DateTime::from_timestamp(ts, 0)
+        .map(|t| t.format("%Y-%m-%d %H:%M UTC").to_string())
+        .unwrap_or_default()
+}
+
+fn preview(message: &str) -> String {
+    if message.chars().count() > PREVIEW_CHARS {
+        let truncated: String = message.chars().take(PREVIEW_CHARS).collect();
+        format!("{}…", truncated)
+    } else {
+        message.to_string()
+    }
+}
+
 fn make_pool(db_path: &Path) -> SqlitePool {
     let mgr = SqliteConnectionManager::file(db_path)
         .with_flags(
@@ -120,9 +187,9 @@ async fn board_view(
     let conn = pool.get().map_err(ErrorInternalServerError)?;
 
     let total: i64 = conn
-        .query_row("SELECT COUNT(*) FROM posts", [], |r| r.get(0))
+        .query_row("SELECT COUNT(*) FROM posts WHERE deleted_at IS NULL", [], |r| r.get(0))
         .map_err(ErrorInternalServerError)?;
-    let total_pages = ((total as usize + per_page - 1) / per_page).max(1);
+    let total_pages = (total as usize).div_ceil(per_page).max(1);
 
     let mut stmt = conn
         .prepare(
@@ -130,8 +197,9 @@ async fn board_view(
                     subject,
                     message,
                     filename,
-                    (SELECT COUNT(*) FROM replies WHERE post_id = posts.id)
+                    (SELECT COUNT(*) FROM replies WHERE post_id = posts.id AND deleted_at IS NULL)
              FROM posts
+             WHERE deleted_at IS NULL
              ORDER BY last_activity DESC
              LIMIT ?1 OFFSET ?2",
         )
@@ -140,16 +208,10 @@ async fn board_view(
     let threads = stmt
         .query_map(params![per_page as i64, offset as i64], |row| {
             let full_message: String = row.get(2)?;
-            let preview = if full_message.chars().count() > PREVIEW_CHARS {
-                let truncated: String = full_message.chars().take(PREVIEW_CHARS).collect();
-                format!("{}…", truncated)
-            } else {
-                full_message.clone()
-            };
             Ok(templates::ThreadInfo {
                 id: row.get(0)?,
                 subject: row.get(1)?,
-                preview,
+                preview: preview(&full_message),
                 filename: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
                 reply_count: row.get(4)?,
             })
@@ -293,6 +355,17 @@ async fn reply_handler(
     let conn = pool.get().map_err(ErrorInternalServerError)?;
     let now = Utc::now().timestamp();
 
+    let open: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM posts WHERE id = ?1 AND deleted_at IS NULL",
+            params![form.post_id],
+            |r| r.get(0),
+        )
+        .map_err(ErrorInternalServerError)?;
+    if open == 0 {
+        return Err(ErrorNotFound("No such thread."));
+    }
+
     conn.execute(
         "INSERT INTO replies (post_id, message, created_at) VALUES (?1, ?2, ?3)",
         params![form.post_id, form.message.clone(), now],
@@ -316,8 +389,10 @@ struct DeleteRequest {
     id: i64,
 }
 
-// No admin_mode needed!
+// No admin_mode needed! Deleting only moves a post to the board's trash;
+// see trash_view and purge_trash.
 async fn admin_delete_handler(
+    req: HttpRequest,
     path: web::Path<String>,
     form: web::Form<DeleteRequest>,
 ) -> Result<HttpResponse, Error> {
@@ -334,27 +409,44 @@ async fn admin_delete_handler(
     let mut conn = pool.get().map_err(ErrorInternalServerError)?;
 
     let tx = conn.transaction().map_err(ErrorInternalServerError)?;
+    // There are no admin accounts; the address is the best record of who. It
+    // is the connection's own, since forwarded headers can be forged.
+    let actor = req.peer_addr().map_or_else(|| "unknown".to_string(), |addr| addr.ip().to_string());
+    let now = Utc::now().timestamp();
+    let mut trashed_file = None;
 
     match form.target.as_str() {
         "post" => {
-            let filename: Option<String> = tx.query_row(
-                "SELECT filename FROM posts WHERE id = ?1",
+            let (subject, message, filename, replies): (String, String, Option<String>, i64) = tx.query_row(
+                "SELECT subject, message, filename,
+                        (SELECT COUNT(*) FROM replies WHERE post_id = posts.id AND deleted_at IS NULL)
+                 FROM posts WHERE id = ?1 AND deleted_at IS NULL",
                 params![form.id],
-                |row| row.get(0),
-            ).ok().flatten();
-
-            tx.execute("DELETE FROM posts WHERE id = ?1", params![form.id])
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            ).map_err(|_| ErrorBadRequest("No such post."))?;
+
+            tx.execute(
+                "INSERT INTO mod_log (created_at, actor, action, target_id, subject, message, filename, replies)
+                 VALUES (?1, ?2, 'delete_post', ?3, ?4, ?5, ?6, ?7)",
+                params![now, actor, form.id, subject, message, filename, replies],
+            ).map_err(ErrorInternalServerError)?;
+            tx.execute("UPDATE posts SET deleted_at = ?1 WHERE id = ?2", params![now, form.id])
                 .map_err(ErrorInternalServerError)?;
-
-            if let Some(fname) = filename {
-                if !fname.is_empty() {
-                    let file_path = paths.uploads.join(fname);
-                    let _ = fs::remove_file(file_path);
-                }
-            }
+            trashed_file = filename.filter(|f| !f.is_empty());
         }
         "reply" => {
-            tx.execute("DELETE FROM replies WHERE id = ?1", params![form.id])
+            let message: String = tx.query_row(
+                "SELECT message FROM replies WHERE id = ?1 AND deleted_at IS NULL",
+                params![form.id],
+                |row| row.get(0),
+            ).map_err(|_| ErrorBadRequest("No such reply."))?;
+
+            tx.execute(
+                "INSERT INTO mod_log (created_at, actor, action, target_id, message)
+                 VALUES (?1, ?2, 'delete_reply', ?3, ?4)",
+                params![now, actor, form.id, message],
+            ).map_err(ErrorInternalServerError)?;
+            tx.execute("UPDATE replies SET deleted_at = ?1 WHERE id = ?2", params![now, form.id])
                 .map_err(ErrorInternalServerError)?;
         }
         _ => return Err(ErrorBadRequest("Bad target type.")),
@@ -362,11 +454,229 @@ async fn admin_delete_handler(
 
     tx.commit().map_err(ErrorInternalServerError)?;
 
+    if let Some(fname) = trashed_file {
+        let _ = fs::rename(paths.uploads.join(&fname), paths.trash.join(&fname));
+    }
+
     Ok(HttpResponse::SeeOther()
         .append_header(("Location", format!("/chess/{}/", board)))
         .finish())
 }
 
+#[derive(Deserialize)]
+struct TrashForm {
+    password: String,
+}
+
+// Deleted threads and replies, for the admin to restore before the purge
+// takes them. Asks for the admin password first.
+async fn trash_page(path: web::Path<String>) -> Result<TrashTemplate, Error> {
+    Ok(TrashTemplate {
+        board: path.into_inner(),
+        unlocked: false,
+        threads: Vec::new(),
+        replies: Vec::new(),
+        trash_days: trash_days(),
+    })
+}
+
+async fn trash_view(
+    path: web::Path<String>,
+    form: web::Form<TrashForm>,
+) -> Result<TrashTemplate, Error> {
+    let board = path.into_inner();
+    let paths = get_board_paths(&board);
+    ensure_board_init(&paths)
+        .map_err(|_| ErrorInternalServerError("Could not initialize board directory/files"))?;
+
+    if form.password != ADMIN_PASSWORD {
+        return Err(ErrorBadRequest("Incorrect admin password."));
+    }
+
+    let pool = make_pool(&paths.db);
+    let conn = pool.get().map_err(ErrorInternalServerError)?;
+    load_trash(&conn, board).map_err(ErrorInternalServerError)
+}
+
+// The trash page with everything in it. The password isn't written into the
+// page; every restore asks for it again.
+fn load_trash(conn: &Connection, board: String) -> rusqlite::Result<TrashTemplate> {
+    let trash_days = trash_days();
+    let purge_time = |deleted_at: i64| format_time(deleted_at + trash_days * 86400);
+
+    let threads = conn
+        .prepare(
+            "SELECT id, subject, message, COALESCE(filename,''), deleted_at,
+                    (SELECT COUNT(*) FROM replies WHERE post_id = posts.id AND deleted_at IS NULL)
+             FROM posts
+             WHERE deleted_at IS NOT NULL
+             ORDER BY deleted_at DESC",
+        )?
+        .query_map([], |row| {
+            let deleted_at: i64 = row.get(4)?;
+            Ok(TrashedThread {
+                id: row.get(0)?,
+                subject: row.get(1)?,
+                preview: preview(&row.get::<_, String>(2)?),
+                filename: row.get(3)?,
+                reply_count: row.get(5)?,
+                deleted: format_time(deleted_at),
+                purged: purge_time(deleted_at),
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+
+    // Replies in a trashed thread come back with it, so only those deleted
+    // on their own are listed.
+    let replies = conn
+        .prepare(
+            "SELECT r.id, r.post_id, p.subject, r.message, r.deleted_at
+             FROM replies r JOIN posts p ON p.id = r.post_id
+             WHERE r.deleted_at IS NOT NULL AND p.deleted_at IS NULL
+             ORDER BY r.deleted_at DESC",
+        )?
+        .query_map([], |row| {
+            let deleted_at: i64 = row.get(4)?;
+            Ok(TrashedReply {
+                id: row.get(0)?,
+                post_id: row.get(1)?,
+                subject: row.get(2)?,
+                preview: preview(&row.get::<_, String>(3)?),
+                deleted: format_time(deleted_at),
+                purged: purge_time(deleted_at),
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+
+    Ok(TrashTemplate {
+        board,
+        unlocked: true,
+        threads,
+        replies,
+        trash_days,
+    })
+}
+
+#[derive(Deserialize)]
+struct RestoreRequest {
+    password: String,
+    item: String, // "post:<id>" or "reply:<id>", from the button pressed
+}
+
+async fn restore_handler(
+    req: HttpRequest,
+    path: web::Path<String>,
+    form: web::Form<RestoreRequest>,
+) -> Result<TrashTemplate, Error> {
+    let board = path.into_inner();
+    let paths = get_board_paths(&board);
+    ensure_board_init(&paths)
+        .map_err(|_| ErrorInternalServerError("Could not initialize board directory/files"))?;
+
+    if form.password != ADMIN_PASSWORD {
+        return Err(ErrorBadRequest("Incorrect admin password."));
+    }
+
+    let (target, id) = form
+        .item
+        .split_once(':')
+        .and_then(|(target, id)| Some((target, id.parse::<i64>().ok()?)))
+        .ok_or_else(|| ErrorBadRequest("Bad target type."))?;
+
+    let pool = make_pool(&paths.db);
+    let mut conn = pool.get().map_err(ErrorInternalServerError)?;
+
+    let tx = conn.transaction().map_err(ErrorInternalServerError)?;
+    let actor = req.peer_addr().map_or_else(|| "unknown".to_string(), |addr| addr.ip().to_string());
+    let now = Utc::now().timestamp();
+    let mut restored_file = None;
+
+    match target {
+        "post" => {
+            let (subject, filename): (String, Option<String>) = tx.query_row(
+                "SELECT subject, filename FROM posts WHERE id = ?1 AND deleted_at IS NOT NULL",
+                params![id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            ).map_err(|_| ErrorBadRequest("No such post in the trash."))?;
+
+            tx.execute(
+                "INSERT INTO mod_log (created_at, actor, action, target_id, subject)
+                 VALUES (?1, ?2, 'restore_post', ?3, ?4)",
+                params![now, actor, id, subject],
+            ).map_err(ErrorInternalServerError)?;
+            tx.execute("UPDATE posts SET deleted_at = NULL WHERE id = ?1", params![id])
+                .map_err(ErrorInternalServerError)?;
+            restored_file = filename.filter(|f| !f.is_empty());
+        }
+        "reply" => {
+            tx.query_row(
+                "SELECT id FROM replies WHERE id = ?1 AND deleted_at IS NOT NULL",
+                params![id],
+                |row| row.get::<_, i64>(0),
+            ).map_err(|_| ErrorBadRequest("No such reply in the trash."))?;
+
+            tx.execute(
+                "INSERT INTO mod_log (created_at, actor, action, target_id)
+                 VALUES (?1, ?2, 'restore_reply', ?3)",
+                params![now, actor, id],
+            ).map_err(ErrorInternalServerError)?;
+            tx.execute("UPDATE replies SET deleted_at = NULL WHERE id = ?1", params![id])
+                .map_err(ErrorInternalServerError)?;
+        }
+        _ => return Err(ErrorBadRequest("Bad target type.")),
+    }
+
+    tx.commit().map_err(ErrorInternalServerError)?;
+
+    if let Some(fname) = restored_file {
+        let _ = fs::rename(paths.trash.join(&fname), paths.uploads.join(&fname));
+    }
+
+    load_trash(&conn, board).map_err(ErrorInternalServerError)
+}
+
+// Remove posts and replies that have been in the trash longer than
+// trash_days(), on every board, along with their files.
+fn purge_trash() {
+    let cutoff = Utc::now().timestamp() - trash_days() * 86400;
+    let Ok(entries) = fs::read_dir("chess") else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if !entry.path().is_dir() {
+            continue;
+        }
+        let paths = get_board_paths(&entry.file_name().to_string_lossy());
+        if !paths.db.exists() {
+            continue;
+        }
+        if let Err(e) = purge_board(&paths, cutoff) {
+            log::warn!("Could not purge the trash of {}: {}", paths.dir.display(), e);
+        }
+    }
+}
+
+fn purge_board(paths: &BoardPaths, cutoff: i64) -> rusqlite::Result<()> {
+    let mut conn = Connection::open(&paths.db)?;
+    conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+    upgrade_schema(&conn)?;
+
+    let tx = conn.transaction()?;
+    let files = tx
+        .prepare("SELECT filename FROM posts WHERE deleted_at <= ?1 AND filename IS NOT NULL")?
+        .query_map([cutoff], |r| r.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    // A purged thread's replies go with it (ON DELETE CASCADE).
+    tx.execute("DELETE FROM posts WHERE deleted_at <= ?1", [cutoff])?;
+    tx.execute("DELETE FROM replies WHERE deleted_at <= ?1", [cutoff])?;
+    tx.commit()?;
+
+    for fname in files.iter().filter(|f| !f.is_empty()) {
+        let _ = fs::remove_file(paths.trash.join(fname));
+    }
+    Ok(())
+}
+
 async fn thread_view(
     path: web::Path<(String, i64)>,
     _query: web::Query<std::collections::HashMap<String, String>>,
@@ -382,16 +692,16 @@ async fn thread_view(
     let (subject, message, filename): (String, String, String) = conn
         .query_row(
             "SELECT subject, message, COALESCE(filename,'')
-             FROM posts WHERE id = ?1",
+             FROM posts WHERE id = ?1 AND deleted_at IS NULL",
             [id],
             |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
         )
-        .map_err(ErrorInternalServerError)?;
+        .map_err(|_| ErrorNotFound("No such thread."))?;
 
     let mut stmt = conn
         .prepare(
             "SELECT id, message FROM replies
-             WHERE post_id = ?1
+             WHERE post_id = ?1 AND deleted_at IS NULL
              ORDER BY created_at ASC",
         )
         .map_err(ErrorInternalServerError)?;
@@ -401,11 +711,16 @@ async fn thread_view(
         .collect::<Result<Vec<_>, _>>()
         .map_err(ErrorInternalServerError)?;
 
+    let replies = replies
+        .into_iter()
+        .map(|(id, message)| (id, markup::render(&message)))
+        .collect();
+
     Ok(templates::ThreadTemplate {
         board,
         id,
         subject,
-        message,
+        message: markup::render(&message),
         filename,
         replies,
         
@@ -420,6 +735,14 @@ async fn main() -> std::io::Result<()> {
         panic!("Please upload your index.html to static/index.html!");
     }
 
+    actix_web::rt::spawn(async {
+        let mut tick = actix_web::rt::time::interval(Duration::from_secs(3600));
+        loop {
+            tick.tick().await;
+            let _ = web::block(purge_trash).await;
+        }
+    });
+
     println!("Server running at http://127.0.0.1:8080");
     HttpServer::new(|| {
         App::new()
@@ -437,8 +760,21 @@ async fn main() -> std::io::Result<()> {
             .route("/chess/{board}/thread/{id}", web::get().to(thread_view))
             .route("/chess/{board}/reply", web::post().to(reply_handler))
             .route("/chess/{board}/delete", web::post().to(admin_delete_handler))
+            .route("/chess/{board}/trash", web::get().to(trash_page))
+            .route("/chess/{board}/trash", web::post().to(trash_view))
+            .route("/chess/{board}/restore", web::post().to(restore_handler))
             .service(Files::new("/static", "static").prefer_utf8(true))
-            .service(Files::new("/chess", "chess").prefer_utf8(true))
+            .service(
+                Files::new("/chess", "chess")
+                    .prefer_utf8(true)
+                    // Only a board's uploads are public. Its database, which
+                    // keeps deleted posts and the mod log, and its trash are not.
+                    .path_filter(|path, _| {
+                        let parts: Vec<_> = path.components().collect();
+                        matches!(parts.as_slice(), [Component::Normal(_), Component::Normal(dir), Component::Normal(_)] if *dir == "uploads")
+                            && path.extension().is_none_or(|ext| ext != "sqlite")
+                    }),
+            )
     })
     .bind("0.0.0.0:8080")?
     .run()
@@ -0,0 +1,155 @@
+use regex::Regex;
+use std::sync::LazyLock;
+
+// Everything that can appear inside a line. Leftmost match wins, so a URL
+// containing asterisks stays one link.
+static INLINE_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"\[spoiler\](?P<spoiler>.+?)\[/spoiler\]|\*\*(?P<bold>.+?)\*\*|\*(?P<italic>[^*\s](?:[^*]*[^*\s])?)\*|(?P<url>https?://[^\s<>\x22']+)",
+    )
+    .unwrap()
+});
+
+// Punctuation that usually ends a sentence rather than a URL.
+const URL_TRAILING: &[char] = &['.', ',', ';', ':', '!', '?', ')', ']', '*'];
+
+pub fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+enum Block<'a> {
+    Line(&'a str),
+    // The lines between ``` fences. A fence left open runs to the end.
+    Code(Vec<&'a str>),
+}
+
+fn blocks(message: &str) -> Vec<Block<'_>> {
+    let mut out = Vec::new();
+    let mut code: Option<Vec<&str>> = None;
+    for line in message.lines() {
+        let fence = line.trim_start().starts_with("```");
+        match (&mut code, fence) {
+            (Some(lines), false) => lines.push(line),
+            (Some(_), true) => out.push(Block::Code(code.take().unwrap())),
+            (None, true) => code = Some(Vec::new()),
+            (None, false) => out.push(Block::Line(line)),
+        }
+    }
+    if let Some(lines) = code {
+        out.push(Block::Code(lines));
+    }
+    out
+}
+
+// One line of text. Spoilers, bold and italic are rendered recursively, so
+// every tag opened here is closed here.
+fn inline(out: &mut String, text: &str) {
+    let mut pos = 0;
+    while let Some(caps) = INLINE_RE.captures_at(text, pos) {
+        let m = caps.get(0).unwrap();
+        out.push_str(&escape_html(&text[pos..m.start()]));
+        pos = m.end();
+
+        if let Some(inner) = caps.name("spoiler") {
+            out.push_str(r#"<span class="spoiler">"#);
+            inline(out, inner.as_str());
+            out.push_str("</span>");
+        } else if let Some(inner) = caps.name("bold") {
+            out.push_str("<strong>");
+            inline(out, inner.as_str());
+            out.push_str("</strong>");
+        } else if let Some(inner) = caps.name("italic") {
+            out.push_str("<em>");
+            inline(out, inner.as_str());
+            out.push_str("</em>");
+        } else if let Some(url) = caps.name("url") {
+            let url = url.as_str().trim_end_matches(URL_TRAILING);
+            pos = m.start() + url.len();
+            if url.len() <= "https://".len() {
+                out.push_str(&escape_html(url));
+                continue;
+            }
+            let url = escape_html(url);
+            out.push_str(&format!(r#"<a href="{url}" rel="nofollow noopener" target="_blank">{url}</a>"#));
+        }
+    }
+    out.push_str(&escape_html(&text[pos..]));
+}
+
+// Turn a message into HTML. All of the message is escaped here, so the
+// result can go into a template with `| safe`.
+//
+//   >greentext          a line starting with >
+//   [spoiler]x[/spoiler], **bold**, *italic*
+//   ``` ... ```         code block, shown as typed
+//   http(s)://...       link, rel="nofollow noopener"
+pub fn render(message: &str) -> String {
+    let mut out = String::with_capacity(message.len() + 32);
+    let mut after_line = false;
+    for block in blocks(message) {
+        match block {
+            Block::Line(line) => {
+                if after_line {
+                    out.push_str("<br>\n");
+                }
+                if line.starts_with('>') {
+                    out.push_str(r#"<span class="greentext">"#);
+                    inline(&mut out, line);
+                    out.push_str("</span>");
+                } else {
+                    inline(&mut out, line);
+                }
+                after_line = true;
+            }
+            Block::Code(lines) => {
+                out.push_str(r#"<pre class="code">"#);
+                out.push_str(&escape_html(&lines.join("\n")));
+                out.push_str("</pre>");
+                after_line = false;
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats() {
+        assert_eq!(render(">be me\n**e4** *d4*"), "<span class=\"greentext\">&gt;be me</span><br>\n<strong>e4</strong> <em>d4</em>");
+        assert_eq!(render("[spoiler]Nxf7[/spoiler]"), "<span class=\"spoiler\">Nxf7</span>");
+        assert_eq!(render("```\n<b>\n```"), "<pre class=\"code\">&lt;b&gt;</pre>");
+    }
+
+    #[test]
+    fn xss_payloads_stay_text() {
+        let payloads = [
+            "<img src=x onerror=alert(1)>",
+            "[spoiler]<svg onload=alert(1)>[/spoiler]",
+            "**<script>alert(1)</script>**",
+            "```\n</pre><script>alert(1)</script>\n```",
+            "javascript:alert(1)",
+            "http://example.com/\"onmouseover=\"alert(1)",
+            "http://example.com/'onmouseover='alert(1)",
+        ];
+        for payload in payloads {
+            let out = render(payload);
+            for bad in ["<script", "<img", "<svg", "\"onmouseover", "'onmouseover", "href=\"javascript"] {
+                assert!(!out.contains(bad), "{payload} -> {out}");
+            }
+        }
+    }
+}
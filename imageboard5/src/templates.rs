@@ -25,9 +25,9 @@ pub struct ThreadTemplate {
     pub board: String,
     pub id: i64,
     pub subject: String,
-    pub message: String,      // Full OP message
+    pub message: String,      // Full OP message, formatted by markup::render
     pub filename: String,
-    pub replies: Vec<(i64, String)>, // (reply id, reply text)
+    pub replies: Vec<(i64, String)>, // (reply id, formatted reply text)
    
 }
 
@@ -36,3 +36,32 @@ pub struct ThreadTemplate {
 pub struct PostFormTemplate {
     pub board: String,
 }
+
+pub struct TrashedThread {
+    pub id: i64,
+    pub subject: String,
+    pub preview: String,
+    pub filename: String,
+    pub reply_count: i64,
+    pub deleted: String,
+    pub purged: String,       // When the purge will remove it for good
+}
+
+pub struct TrashedReply {
+    pub id: i64,
+    pub post_id: i64,
+    pub subject: String,      // Subject of the thread it was in
+    pub preview: String,
+    pub deleted: String,
+    pub purged: String,
+}
+
+#[derive(Template, WebTemplate)]
+#[template(path = "trash.html")]
+pub struct TrashTemplate {
+    pub board: String,
+    pub unlocked: bool,           // False until the admin password is given
+    pub threads: Vec<TrashedThread>,
+    pub replies: Vec<TrashedReply>,
+    pub trash_days: i64,
+}
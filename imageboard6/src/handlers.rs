@@ -1,12 +1,19 @@
 use axum::{
-    extract::{Multipart, Path},
+    extract::{multipart::Field, Multipart, Path},
     response::{Html, Redirect},
 };
 use askama::Template;
 use uuid::Uuid;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
 use crate::models;
 use crate::templates::{Board, ThreadView};
 
+const MAX_UPLOAD_SIZE: usize = 50 * 1024 * 1024; // 50MB
+// Request body limit: a max-size upload plus room for the text fields.
+pub const MAX_BODY_SIZE: usize = MAX_UPLOAD_SIZE + 1024 * 1024;
+const UPLOAD_TMP_DIR: &str = "upload_tmp";
+const SNIFF_LEN: usize = 8192;
 const THREADS_PER_PAGE: usize = 8;
 const REPLIES_TO_SHOW: usize = 3;
 
@@ -53,24 +60,62 @@ pub async fn thread_view(Path(id): Path<i64>) -> Html<String> {
     }
 }
 
-async fn save_media(field: axum::extract::multipart::Field<'_>) -> Option<String> {
-    let content_type = field.content_type().map(|m| m.to_string()).unwrap_or_default();
-    if !["image/png", "image/jpeg", "image/gif", "video/mp4"].contains(&content_type.as_str()) {
-        return None;
+fn ext_from_mime(mime: &str) -> Option<&'static str> {
+    match mime {
+        "image/png" => Some("png"),
+        "image/jpeg" => Some("jpg"),
+        "image/gif" => Some("gif"),
+        "video/mp4" => Some("mp4"),
+        _ => None,
     }
-    let ext = match content_type.as_str() {
-        "image/png" => "png",
-        "image/jpeg" => "jpg",
-        "image/gif" => "gif",
-        "video/mp4" => "mp4",
-        _ => "",
-    };
+}
+
+// Removes a half-written upload unless it was kept, including when the
+// client disconnects and the handler future is dropped mid-stream.
+struct TempUpload {
+    path: String,
+    keep: bool,
+}
+
+impl Drop for TempUpload {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+// Stream an uploaded file to a temp file, giving up as soon as it grows past
+// MAX_UPLOAD_SIZE. The type comes from the file's magic bytes, not the
+// client-sent content type. Only accepted files are moved into uploads/.
+async fn save_media(mut field: Field<'_>) -> Option<String> {
     let uuid = Uuid::new_v4().to_string();
+    fs::create_dir_all(UPLOAD_TMP_DIR).await.ok()?;
+    let mut tmp = TempUpload { path: format!("{UPLOAD_TMP_DIR}/{uuid}.part"), keep: false };
+    let mut file = fs::File::create(&tmp.path).await.ok()?;
+
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    let mut size = 0;
+    while let Some(chunk) = field.chunk().await.ok()? {
+        size += chunk.len();
+        if size > MAX_UPLOAD_SIZE {
+            return None;
+        }
+        if head.len() < SNIFF_LEN {
+            let n = (SNIFF_LEN - head.len()).min(chunk.len());
+            head.extend_from_slice(&chunk[..n]);
+        }
+        file.write_all(&chunk).await.ok()?;
+    }
+    file.flush().await.ok()?;
+    drop(file);
+
+    let ext = infer::get(&head).and_then(|kind| ext_from_mime(kind.mime_type()))?;
     let fname = format!("{}.{}", uuid, ext);
     let path = format!("uploads/{}", fname);
-
-    let data = field.bytes().await.ok()?;
-    tokio::fs::write(&path, &data).await.ok()?;
+    fs::create_dir_all("uploads").await.ok()?;
+    fs::rename(&tmp.path, &path).await.ok()?;
+    tmp.keep = true;
     Some(fname)
 }
 
@@ -1,8 +1,10 @@
 mod handlers;
+mod migrations;
 mod models;
 mod templates;
 
 use axum::{
+    extract::DefaultBodyLimit,
     routing::{get, post},
     Router,
 };
@@ -12,8 +14,24 @@ use tokio::net::TcpListener;
 
 #[tokio::main]
 async fn main() {
-    // Initialize the database
-    models::init_db();
+    // Bring the schema up to date. --reset-db wipes everything first.
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let has = |flag: &str| args.iter().any(|a| a == flag);
+    {
+        let mut db = models::DB.lock().unwrap();
+        if has("--status") {
+            migrations::print_status(&mut db);
+            return;
+        }
+        if has("--reset-db") {
+            migrations::reset(&mut db);
+        }
+        let ran = migrations::migrate(&mut db);
+        if has("--migrate") {
+            println!("{ran} migration(s) applied.");
+            return;
+        }
+    }
 
     let app = Router::new()
         .route("/", get(handlers::board))
@@ -22,7 +40,8 @@ async fn main() {
         .route("/new", post(handlers::new_thread))
         .route("/reply/{id}", post(handlers::reply))
         .nest_service("/uploads", ServeDir::new("uploads"))
-        .nest_service("/static", ServeDir::new("static"));
+        .nest_service("/static", ServeDir::new("static"))
+        .layer(DefaultBodyLimit::max(handlers::MAX_BODY_SIZE));
 
     let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
     println!("Listening on http://{}", addr);
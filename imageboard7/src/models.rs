@@ -3,7 +3,11 @@ pub const THREAD_PREVIEW_LENGTH: usize = 160;
 pub const REPLY_PREVIEW_LENGTH: usize = 80;
 pub const THREADS_PER_PAGE: usize = 8;
 pub const REPLIES_TO_SHOW: usize = 3;
-pub const RESET_DB_ON_START: bool = true; // <--- set to true to drop & recreate tables on every run
+// Replies past this many no longer bump the thread.
+pub const BUMP_LIMIT: usize = 300;
+// When a new thread takes the board past this many, the least recently
+// bumped threads are deleted.
+pub const MAX_THREADS: usize = 150;
 // ===== End Board Settings =====
 
 use mysql::*;
@@ -37,35 +41,6 @@ pub struct Post {
     pub preview: String,
 }
 
-pub fn init_db() {
-    let mut db = DB.lock().unwrap();
-    if RESET_DB_ON_START {
-        println!("RESET_DB_ON_START is true. Dropping all posts and threads tables.");
-        // Drop child (posts) before parent (threads)
-        db.query_drop("DROP TABLE IF EXISTS posts").unwrap();
-        db.query_drop("DROP TABLE IF EXISTS threads").unwrap();
-        // Optional: vacuum/optimize, not strictly needed
-    }
-    db.query_drop(
-        "CREATE TABLE IF NOT EXISTS threads (
-            id BIGINT PRIMARY KEY AUTO_INCREMENT,
-            subject TEXT NOT NULL,
-            message TEXT NOT NULL,
-            media TEXT,
-            bumped BIGINT NOT NULL
-        )"
-    ).unwrap();
-    db.query_drop(
-        "CREATE TABLE IF NOT EXISTS posts (
-            id BIGINT PRIMARY KEY AUTO_INCREMENT,
-            thread_id BIGINT NOT NULL,
-            message TEXT NOT NULL,
-            media TEXT,
-            FOREIGN KEY(thread_id) REFERENCES threads(id) ON DELETE CASCADE
-        )"
-    ).unwrap();
-}
-
 pub fn get_threads_paged(threads_per_page: usize, page: usize) -> Vec<Thread> {
     let mut db = DB.lock().unwrap();
     db.exec_map(
@@ -141,17 +116,45 @@ pub fn insert_thread(subject: &str, message: &str, media: Option<&str>) {
     ).unwrap();
 }
 
-pub fn insert_post(thread_id: i64, message: &str, media: Option<&str>) {
+// A reply bumps its thread unless it is a sage or the thread has passed
+// BUMP_LIMIT replies.
+pub fn insert_post(thread_id: i64, message: &str, media: Option<&str>, sage: bool) {
     let mut db = DB.lock().unwrap();
     db.exec_drop(
         "INSERT INTO posts (thread_id, message, media) VALUES (:tid, :message, :media)",
         params! { "tid" => thread_id, "message" => message, "media" => media },
     ).unwrap();
-    let now = chrono::Utc::now().timestamp();
-    db.exec_drop(
-        "UPDATE threads SET bumped = :bumped WHERE id = :id",
-        params! { "bumped" => now, "id" => thread_id },
-    ).unwrap();
+    let replies = db.exec_first::<u64, _, _>(
+        "SELECT COUNT(*) FROM posts WHERE thread_id = :tid",
+        params! { "tid" => thread_id }
+    ).unwrap_or(Some(0)).unwrap_or(0) as usize;
+    if !sage && replies <= BUMP_LIMIT {
+        let now = chrono::Utc::now().timestamp();
+        db.exec_drop(
+            "UPDATE threads SET bumped = :bumped WHERE id = :id",
+            params! { "bumped" => now, "id" => thread_id },
+        ).unwrap();
+    }
+}
+
+// Delete the least recently bumped threads, with their replies, until at
+// most `max_threads` are left. Returns the upload files to remove.
+pub fn prune_threads(max_threads: usize) -> Vec<String> {
+    let mut db = DB.lock().unwrap();
+    let ids: Vec<i64> = db.exec(
+        "SELECT id FROM threads ORDER BY bumped DESC LIMIT 18446744073709551615 OFFSET :keep",
+        params! { "keep" => max_threads as u64 },
+    ).unwrap_or_default();
+    let mut files = Vec::new();
+    for id in ids {
+        let media: Vec<Option<String>> = db.exec(
+            "SELECT media FROM threads WHERE id = :id UNION ALL SELECT media FROM posts WHERE thread_id = :id",
+            params! { "id" => id },
+        ).unwrap_or_default();
+        files.extend(media.into_iter().flatten());
+        db.exec_drop("DELETE FROM threads WHERE id = :id", params! { "id" => id }).unwrap();
+    }
+    files
 }
 
 pub fn get_post_count(thread_id: i64) -> usize {
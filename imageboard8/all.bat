@@ -5,7 +5,7 @@ REM Output file
 set OUTFILE=all.txt
 
 REM List all source and template files you want to include:
-set FILES=Cargo.toml .env src\main.rs src\handlers.rs src\models.rs src\templates.rs templates\board.html templates\thread.html
+set FILES=Cargo.toml .env src\main.rs src\handlers.rs src\migrations.rs src\models.rs src\templates.rs templates\board.html templates\thread.html
 
 REM Remove old all.txt
 if exist "%OUTFILE%" del "%OUTFILE%"
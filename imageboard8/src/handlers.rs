@@ -1,15 +1,21 @@
 use axum::{
-    extract::{Multipart, Path},
+    extract::{multipart::Field, Multipart, Path},
     response::{Html, Redirect, IntoResponse, Response},
 };
 use askama::Template;
 use uuid::Uuid;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
 use crate::models;
 use crate::templates::{Board, ThreadView, ErrorPage};
-use crate::models::{THREADS_PER_PAGE, REPLIES_TO_SHOW};
+use crate::models::{MAX_THREADS, THREADS_PER_PAGE, REPLIES_TO_SHOW};
 
 const MAX_MESSAGE_LEN: usize = 50_000;
 const MAX_UPLOAD_SIZE: usize = 50 * 1024 * 1024; // 50MB
+// Request body limit: a max-size upload plus room for the text fields.
+pub const MAX_BODY_SIZE: usize = MAX_UPLOAD_SIZE + 1024 * 1024;
+const UPLOAD_TMP_DIR: &str = "upload_tmp";
+const SNIFF_LEN: usize = 8192;
 
 fn ext_from_mime(mime: &str) -> Option<&'static str> {
     match mime {
@@ -66,24 +72,60 @@ pub async fn thread_view(Path(id): Path<i64>) -> Html<String> {
     }
 }
 
-async fn save_media(field: axum::extract::multipart::Field<'_>) -> Result<Option<String>, String> {
-    let content_type = field.content_type().map(|m| m.to_string()).unwrap_or_default();
-    let ext = match ext_from_mime(&content_type) {
+// Removes a half-written upload unless it was kept, including when the
+// client disconnects and the handler future is dropped mid-stream.
+struct TempUpload {
+    path: String,
+    keep: bool,
+}
+
+impl Drop for TempUpload {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+// Stream an uploaded file to a temp file, rejecting it as soon as it grows past
+// MAX_UPLOAD_SIZE. The type comes from the file's magic bytes, not the
+// client-sent content type. Only accepted files are moved into uploads/.
+async fn save_media(mut field: Field<'_>) -> Result<Option<String>, String> {
+    let uuid = Uuid::new_v4().to_string();
+    fs::create_dir_all(UPLOAD_TMP_DIR).await.map_err(|_| "Failed to save file.".to_string())?;
+    let mut tmp = TempUpload { path: format!("{UPLOAD_TMP_DIR}/{uuid}.part"), keep: false };
+    let mut file = fs::File::create(&tmp.path).await.map_err(|_| "Failed to save file.".to_string())?;
+
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    let mut size = 0;
+    while let Some(chunk) = field.chunk().await.map_err(|_| "Failed to read file data.".to_string())? {
+        size += chunk.len();
+        if size > MAX_UPLOAD_SIZE {
+            return Err("File too large. Max allowed size: 50MB.".to_string());
+        }
+        if head.len() < SNIFF_LEN {
+            let n = (SNIFF_LEN - head.len()).min(chunk.len());
+            head.extend_from_slice(&chunk[..n]);
+        }
+        file.write_all(&chunk).await.map_err(|_| "Failed to save file.".to_string())?;
+    }
+    file.flush().await.map_err(|_| "Failed to save file.".to_string())?;
+    drop(file);
+    if size == 0 {
+        return Ok(None);
+    }
+
+    let ext = match infer::get(&head).and_then(|kind| ext_from_mime(kind.mime_type())) {
         Some(e) => e,
         None => {
             return Err("Unsupported file type. Allowed: jpg, png, gif, webp, bmp, mp4.".to_string());
         }
     };
-    let uuid = Uuid::new_v4().to_string();
     let fname = format!("{}.{}", uuid, ext);
     let path = format!("uploads/{}", fname);
-
-    let data = field.bytes().await.map_err(|_| "Failed to read file data.".to_string())?;
-    if data.len() > MAX_UPLOAD_SIZE {
-        return Err("File too large. Max allowed size: 50MB.".to_string());
-    }
-    tokio::fs::create_dir_all("uploads").await.ok();
-    tokio::fs::write(&path, &data).await.map_err(|_| "Failed to save file.".to_string())?;
+    fs::create_dir_all("uploads").await.ok();
+    fs::rename(&tmp.path, &path).await.map_err(|_| "Failed to save file.".to_string())?;
+    tmp.keep = true;
     Ok(Some(fname))
 }
 
@@ -141,15 +183,20 @@ pub async fn new_thread(mut multipart: Multipart) -> Response {
         ).into_response();
     }
     models::insert_thread(&subject, &message, media.as_deref());
-    return Redirect::to("/").into_response();
+    for file in models::prune_threads(MAX_THREADS) {
+        let _ = fs::remove_file(format!("uploads/{file}")).await;
+    }
+    Redirect::to("/").into_response()
 }
 
 pub async fn reply(Path(id): Path<i64>, mut multipart: Multipart) -> Response {
+    let mut email = String::new();
     let mut message = String::new();
     let mut media: Option<String> = None;
 
     while let Some(field) = multipart.next_field().await.unwrap() {
         match field.name() {
+            Some("email") => email = field.text().await.unwrap_or_default(),
             Some("message") => message = field.text().await.unwrap_or_default(),
             Some("media") => {
                 if let Some(filename) = field.file_name() {
@@ -195,6 +242,9 @@ pub async fn reply(Path(id): Path<i64>, mut multipart: Multipart) -> Response {
             .unwrap(),
         ).into_response();
     }
-    models::insert_post(id, &message, media.as_deref());
-    return Redirect::to(&format!("/thread/{}", id)).into_response();
+    // "sage" in the email field means reply without bumping. The field is
+    // not stored.
+    let sage = email.trim().eq_ignore_ascii_case("sage");
+    models::insert_post(id, &message, media.as_deref(), sage);
+    Redirect::to(&format!("/thread/{}", id)).into_response()
 }
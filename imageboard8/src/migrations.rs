@@ -0,0 +1,99 @@
+use mysql::*;
+use mysql::prelude::*;
+
+// One schema change. Steps run in order and the version is recorded once
+// they have all succeeded. Never edit a migration that has shipped; add a
+// new one to the end of MIGRATIONS instead.
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub steps: &'static [&'static str],
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial schema",
+        // IF NOT EXISTS so boards created before migrations existed are adopted as-is.
+        steps: &[
+            "CREATE TABLE IF NOT EXISTS threads (
+                id BIGINT PRIMARY KEY AUTO_INCREMENT,
+                subject TEXT NOT NULL,
+                message TEXT NOT NULL,
+                media TEXT,
+                bumped BIGINT NOT NULL
+            )",
+            "CREATE TABLE IF NOT EXISTS posts (
+                id BIGINT PRIMARY KEY AUTO_INCREMENT,
+                thread_id BIGINT NOT NULL,
+                message TEXT NOT NULL,
+                media TEXT,
+                FOREIGN KEY(thread_id) REFERENCES threads(id) ON DELETE CASCADE
+            )",
+        ],
+    },
+];
+
+// Every table the migrations create, for --reset-db.
+const TABLES: &[&str] = &["posts", "threads", "schema_version"];
+
+fn ensure_version_table(db: &mut PooledConn) {
+    db.query_drop(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INT UNSIGNED PRIMARY KEY,
+            name VARCHAR(128) NOT NULL,
+            applied_at BIGINT NOT NULL
+        )"
+    ).unwrap();
+}
+
+pub fn applied_versions(db: &mut PooledConn) -> Vec<(u32, String, i64)> {
+    ensure_version_table(db);
+    db.query("SELECT version, name, applied_at FROM schema_version ORDER BY version").unwrap()
+}
+
+// Apply every migration newer than the database, in order. Returns how many ran.
+pub fn migrate(db: &mut PooledConn) -> usize {
+    let current = applied_versions(db).last().map_or(0, |(v, _, _)| *v);
+    let mut ran = 0;
+    for m in MIGRATIONS.iter().filter(|m| m.version > current) {
+        println!("Applying migration {} ({})", m.version, m.name);
+        for step in m.steps {
+            db.query_drop(step)
+                .unwrap_or_else(|e| panic!("Migration {} ({}) failed: {}", m.version, m.name, e));
+        }
+        db.exec_drop(
+            "INSERT INTO schema_version (version, name, applied_at) VALUES (:version, :name, :now)",
+            params! { "version" => m.version, "name" => m.name, "now" => chrono::Utc::now().timestamp() },
+        ).unwrap();
+        ran += 1;
+    }
+    ran
+}
+
+// Print applied and pending migrations.
+pub fn print_status(db: &mut PooledConn) {
+    let applied = applied_versions(db);
+    for (version, name, applied_at) in &applied {
+        let when = chrono::DateTime::from_timestamp(*applied_at, 0)
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+            .unwrap_or_default();
+        println!("  [applied] {:>3}  {}  ({})", version, name, when);
+    }
+    let current = applied.last().map_or(0, |(v, _, _)| *v);
+    let pending: Vec<_> = MIGRATIONS.iter().filter(|m| m.version > current).collect();
+    for m in &pending {
+        println!("  [pending] {:>3}  {}", m.version, m.name);
+    }
+    println!("Schema version {}, {} pending.", current, pending.len());
+}
+
+// Drop every table, data included. Only reachable through --reset-db.
+pub fn reset(db: &mut PooledConn) {
+    println!("--reset-db given. Dropping all tables.");
+    db.query_drop("SET FOREIGN_KEY_CHECKS = 0").unwrap();
+    for table in TABLES {
+        db.query_drop(format!("DROP TABLE IF EXISTS {table}")).unwrap();
+    }
+    db.query_drop("SET FOREIGN_KEY_CHECKS = 1").unwrap();
+}
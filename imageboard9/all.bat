@@ -5,9 +5,9 @@ set OUTFILE=all.txt
 REM List every single file needed by your app.
 REM Add to this list as you add more files/templates.
 set FILES=Cargo.toml .env ^
-src\main.rs src\handlers.rs src\models.rs src\templates.rs src\boards.rs ^
-templates\board.html templates\thread.html templates\error.html ^
-static\landing.html static\style.css
+src\main.rs src\admin.rs src\archive.rs src\auth.rs src\bans.rs src\handlers.rs src\ip.rs src\iplog.rs src\models.rs src\pow.rs src\reports.rs src\selfdelete.rs src\templates.rs src\boards.rs src\captcha.rs src\cli.rs src\config.rs src\db.rs src\filters.rs src\flood.rs src\markup.rs src\media.rs src\migrations.rs src\modlog.rs src\state.rs src\tripcode.rs ^
+templates\board.html templates\thread.html templates\error.html templates\landing.html templates\admin.html templates\admin_login.html templates\admin_ban.html templates\admin_filters.html templates\admin_reports.html templates\report.html templates\mod_log.html templates\archive.html templates\banned.html ^
+static\style.css static\pow.js
 
 REM Remove old all.txt
 if exist "%OUTFILE%" del "%OUTFILE%"
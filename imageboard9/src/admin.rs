@@ -0,0 +1,620 @@
+// ===== Admin Panel Settings =====
+pub const RECENT_POSTS_ON_DASHBOARD: usize = 30;
+// ===== End Admin Panel Settings =====
+
+use axum::{
+    extract::{ConnectInfo, Form, Path, Query, State},
+    http::{header::SET_COOKIE, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use askama::Template;
+use serde::Deserialize;
+use crate::auth::{self, Moderator, Role};
+use crate::bans::{self, BanTarget};
+use crate::filters::{self, FilterAction};
+use crate::ip;
+use crate::markup::PostLocation;
+use crate::handlers::error_page;
+use crate::modlog::{self, Actor, LogEntry, LogFilter, NewEntry, LOG_ACTIONS, LOG_ENTRIES_PER_PAGE};
+use crate::models::{self, ThreadFlag};
+use crate::reports;
+use crate::state::AppState;
+use crate::templates::{format_time, AdminBan, AdminDashboard, AdminFilters, AdminLogin, AdminReports, BanItem, FilterItem, LogItem, ModLog, RecentItem, ReportGroupItem};
+use std::net::SocketAddr;
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    username: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+pub struct BanForm {
+    range: String,
+    reason: String,
+    // 0 for a permanent ban.
+    hours: i64,
+    // "board" or "global"
+    scope: String,
+    delete: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct FilterForm {
+    pattern: String,
+    regex: Option<String>,
+    // A board slug, or empty for every board.
+    board: String,
+    action: String,
+    // The replacement, rejection message or ban reason.
+    #[serde(default)]
+    text: String,
+    // Ban length; 0 for a permanent ban.
+    #[serde(default)]
+    hours: i64,
+}
+
+// Sent with every moderation action: where to go afterwards.
+#[derive(Deserialize)]
+pub struct ActionForm {
+    back: Option<String>,
+}
+
+// Switches a thread setting: on if `on` is sent, off if not.
+#[derive(Deserialize)]
+pub struct ThreadFlagForm {
+    on: Option<String>,
+    back: Option<String>,
+}
+
+// Only follow `back` if it stays on this site.
+fn back_or(back: Option<String>, fallback: &str) -> String {
+    back.filter(|b| b.starts_with('/') && !b.starts_with("//") && !b.contains('\\'))
+        .unwrap_or_else(|| fallback.to_string())
+}
+
+pub async fn remove_uploads(files: Vec<String>) {
+    for file in files {
+        let _ = tokio::fs::remove_file(format!("uploads/{file}")).await;
+    }
+}
+
+// The logged-in moderator, or the error page to show instead.
+async fn require_moderator(state: &AppState, headers: &HeaderMap) -> Result<Moderator, Response> {
+    auth::current_moderator(state, headers)
+        .await
+        .ok_or_else(|| error_page("You are not logged in.", "/admin/login".to_string()))
+}
+
+// Check that `moderator` may act on post `id`. Returns where the post is.
+async fn require_post_access(state: &AppState, moderator: &Moderator, id: i64) -> Result<PostLocation, Response> {
+    let location = state.db.run(move |db| models::resolve_posts(db, &[id]).remove(&id)).await?;
+    match location {
+        None => Err(error_page("No such post.", "/admin".to_string())),
+        Some(loc) if !moderator.can_act_on(&loc.board) => {
+            Err(error_page(format!("You can't moderate /{}/.", loc.board), "/admin".to_string()))
+        }
+        Some(loc) => Ok(loc),
+    }
+}
+
+pub async fn dashboard(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let Some(moderator) = auth::current_moderator(&state, &headers).await else {
+        return Redirect::to("/admin/login").into_response();
+    };
+    let boards: Vec<_> = state.boards.all().await.into_iter().filter(|b| moderator.can_act_on(&b.slug)).collect();
+    let slugs: Vec<String> = boards.iter().map(|b| b.slug.clone()).collect();
+    let (recent, bans, reported_posts) = match state.db.run(move |db| {
+        (
+            models::get_recent_posts(db, &slugs, RECENT_POSTS_ON_DASHBOARD),
+            bans::get_active_bans(db),
+            reports::count_reported_posts(db, &slugs),
+        )
+    }).await {
+        Ok(v) => v,
+        Err(e) => return e.into_response(),
+    };
+    let recent = recent
+        .into_iter()
+        .map(|post| RecentItem { when: format_time(post.created), post })
+        .collect();
+    let bans = bans
+        .into_iter()
+        .filter(|b| b.board.as_deref().is_none_or(|board| moderator.can_act_on(board)))
+        .map(|ban| BanItem {
+            created: format_time(ban.created),
+            expires: ban.expires.map_or_else(|| "never".to_string(), format_time),
+            ban,
+        })
+        .collect();
+    Html(AdminDashboard { moderator, boards, recent, bans, reported_posts }.render().unwrap()).into_response()
+}
+
+pub async fn login_page(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if auth::current_moderator(&state, &headers).await.is_some() {
+        return Redirect::to("/admin").into_response();
+    }
+    Html(AdminLogin { error: None }.render().unwrap()).into_response()
+}
+
+pub async fn login(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Form(form): Form<LoginForm>,
+) -> Response {
+    let ip = ip::client_ip(peer.ip(), &headers, &state.config.trusted_proxies);
+    if let Err(reason) = state.flood.check_login(ip) {
+        let page = AdminLogin { error: Some(reason) };
+        return (StatusCode::TOO_MANY_REQUESTS, Html(page.render().unwrap())).into_response();
+    }
+    let moderator = match state.db.run(move |db| auth::login(db, &form.username, &form.password)).await {
+        Ok(moderator) => moderator,
+        Err(e) => return e.into_response(),
+    };
+    match moderator {
+        Some(moderator) => {
+            state.flood.login_succeeded(ip);
+            ([(SET_COOKIE, auth::session_cookie(&state, &moderator))], Redirect::to("/admin")).into_response()
+        }
+        None => {
+            let page = AdminLogin { error: Some("Wrong username or password.".to_string()) };
+            Html(page.render().unwrap()).into_response()
+        }
+    }
+}
+
+pub async fn logout() -> Response {
+    ([(SET_COOKIE, auth::clear_session_cookie())], Redirect::to("/")).into_response()
+}
+
+// Delete a thread (with its replies) or a single reply.
+pub async fn delete_post(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<i64>,
+    Form(form): Form<ActionForm>,
+) -> Response {
+    let moderator = match require_moderator(&state, &headers).await {
+        Ok(m) => m,
+        Err(page) => return page,
+    };
+    let location = match require_post_access(&state, &moderator, id).await {
+        Ok(loc) => loc,
+        Err(page) => return page,
+    };
+    let board = location.board.clone();
+    let files = match state.db.run(move |db| {
+        log_deletion(db, &moderator, &location, id);
+        models::delete_post(db, id).unwrap_or_default()
+    }).await {
+        Ok(files) => files,
+        Err(e) => return e.into_response(),
+    };
+    remove_uploads(files).await;
+    Redirect::to(&back_or(form.back, &format!("/{board}/"))).into_response()
+}
+
+// Log the deletion of post `id`, with a copy of it, before it goes.
+fn log_deletion(db: &mut mysql::PooledConn, moderator: &Moderator, location: &PostLocation, id: i64) {
+    let what = if location.thread_id == id { "thread" } else { "reply" };
+    let snapshot = modlog::snapshot_post(db, location, id);
+    modlog::record(db, Actor::Moderator(moderator), NewEntry {
+        action: "delete",
+        board: Some(&location.board),
+        target: Some(id),
+        details: format!("Deleted {what} No.{id}"),
+        snapshot,
+    });
+}
+
+// Delete a post's file but keep the post.
+pub async fn delete_media(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<i64>,
+    Form(form): Form<ActionForm>,
+) -> Response {
+    let moderator = match require_moderator(&state, &headers).await {
+        Ok(m) => m,
+        Err(page) => return page,
+    };
+    let board = match require_post_access(&state, &moderator, id).await {
+        Ok(loc) => loc.board,
+        Err(page) => return page,
+    };
+    let slug = board.clone();
+    let files = match state.db.run(move |db| {
+        let files = models::delete_media(db, id);
+        if !files.is_empty() {
+            modlog::record(db, Actor::Moderator(&moderator), NewEntry {
+                action: "delete_media",
+                board: Some(&slug),
+                target: Some(id),
+                details: format!("Deleted the file of No.{id}"),
+                snapshot: Some(files.join("\n")),
+            });
+        }
+        files
+    }).await {
+        Ok(files) => files,
+        Err(e) => return e.into_response(),
+    };
+    remove_uploads(files).await;
+    Redirect::to(&back_or(form.back, &format!("/{board}/"))).into_response()
+}
+
+pub async fn sticky(state: State<AppState>, headers: HeaderMap, id: Path<i64>, form: Form<ThreadFlagForm>) -> Response {
+    set_thread_flag(state, headers, id, form, ThreadFlag::Sticky).await
+}
+
+pub async fn lock(state: State<AppState>, headers: HeaderMap, id: Path<i64>, form: Form<ThreadFlagForm>) -> Response {
+    set_thread_flag(state, headers, id, form, ThreadFlag::Locked).await
+}
+
+pub async fn cyclical(state: State<AppState>, headers: HeaderMap, id: Path<i64>, form: Form<ThreadFlagForm>) -> Response {
+    set_thread_flag(state, headers, id, form, ThreadFlag::Cyclical).await
+}
+
+async fn set_thread_flag(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<i64>,
+    Form(form): Form<ThreadFlagForm>,
+    flag: ThreadFlag,
+) -> Response {
+    let moderator = match require_moderator(&state, &headers).await {
+        Ok(m) => m,
+        Err(page) => return page,
+    };
+    let location = match require_post_access(&state, &moderator, id).await {
+        Ok(loc) => loc,
+        Err(page) => return page,
+    };
+    if location.thread_id != id {
+        return error_page("Only a thread can be stickied, locked or made cyclical, not a reply.", "/admin".to_string());
+    }
+    let board = location.board;
+    let slug = board.clone();
+    let on = form.on.is_some();
+    if let Err(e) = state.db.run(move |db| {
+        models::set_thread_flag(db, id, flag, on);
+        let details = match (flag, on) {
+            (ThreadFlag::Sticky, true) => format!("Stickied thread No.{id}"),
+            (ThreadFlag::Sticky, false) => format!("Unstickied thread No.{id}"),
+            (ThreadFlag::Locked, true) => format!("Locked thread No.{id}"),
+            (ThreadFlag::Locked, false) => format!("Unlocked thread No.{id}"),
+            (ThreadFlag::Cyclical, true) => format!("Made thread No.{id} cyclical"),
+            (ThreadFlag::Cyclical, false) => format!("Made thread No.{id} non-cyclical"),
+        };
+        modlog::record(db, Actor::Moderator(&moderator), NewEntry {
+            action: flag.action(on),
+            board: Some(&slug),
+            target: Some(id),
+            details,
+            snapshot: None,
+        });
+    }).await {
+        return e.into_response();
+    }
+    Redirect::to(&back_or(form.back, &format!("/{board}/thread/{id}"))).into_response()
+}
+
+// The ban form for the poster of post `id`.
+pub async fn ban_page(State(state): State<AppState>, headers: HeaderMap, Path(id): Path<i64>) -> Response {
+    let moderator = match require_moderator(&state, &headers).await {
+        Ok(m) => m,
+        Err(page) => return page,
+    };
+    let location = match require_post_access(&state, &moderator, id).await {
+        Ok(loc) => loc,
+        Err(page) => return page,
+    };
+    if !moderator.can_ban() {
+        return error_page("Janitors can't ban.", "/admin".to_string());
+    }
+    let (ip, hash) = match state.db.run(move |db| (models::get_post_ip(db, id), models::get_post_ip_hash(db, id))).await {
+        Ok(v) => v,
+        Err(e) => return e.into_response(),
+    };
+    let page = AdminBan {
+        post_id: id,
+        board: location.board,
+        thread_id: location.thread_id,
+        ip,
+        has_hash: hash.is_some(),
+    };
+    Html(page.render().unwrap()).into_response()
+}
+
+pub async fn ban(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<i64>,
+    Form(form): Form<BanForm>,
+) -> Response {
+    let moderator = match require_moderator(&state, &headers).await {
+        Ok(m) => m,
+        Err(page) => return page,
+    };
+    let location = match require_post_access(&state, &moderator, id).await {
+        Ok(loc) => loc,
+        Err(page) => return page,
+    };
+    let back = format!("/admin/ban/{id}");
+    if !moderator.can_ban() {
+        return error_page("Janitors can't ban.", "/admin".to_string());
+    }
+    // An empty range bans the post's own (hashed) address.
+    let target = if form.range.trim().is_empty() {
+        match state.db.run(move |db| models::get_post_ip_hash(db, id)).await {
+            Ok(Some((hash, salt_id))) => BanTarget::Hash(hash, salt_id),
+            Ok(None) => return error_page("This post's address is no longer kept; enter one.", back),
+            Err(e) => return e.into_response(),
+        }
+    } else {
+        match ip::parse_range(&form.range) {
+            Some(range) => BanTarget::Range(range.trunc()),
+            None => return error_page(format!("'{}' is not an address or range.", form.range), back),
+        }
+    };
+    let reason = form.reason.trim().to_string();
+    if reason.is_empty() {
+        return error_page("Give a reason; the poster will see it.", back);
+    }
+    let expires = (form.hours > 0).then(|| chrono::Utc::now().timestamp() + form.hours * 3600);
+    let board = (form.scope != "global").then(|| location.board.clone());
+    let delete = form.delete.is_some();
+    let length = if form.hours > 0 { format!("{} hours", form.hours) } else { "good".to_string() };
+    let loc = location.clone();
+    let files = match state.db.run(move |db| {
+        let ban_id = bans::insert_ban(db, &target, board.as_deref(), &reason, expires, Some(moderator.id));
+        let scope = board.as_deref().map_or("all boards".to_string(), |b| format!("/{b}/"));
+        modlog::record(db, Actor::Moderator(&moderator), NewEntry {
+            action: "ban",
+            board: Some(&loc.board),
+            target: Some(id),
+            details: format!("Ban #{ban_id} for the poster of No.{id}, on {scope} for {length}: {reason}"),
+            snapshot: None,
+        });
+        if delete {
+            log_deletion(db, &moderator, &loc, id);
+            models::delete_post(db, id).unwrap_or_default()
+        } else {
+            Vec::new()
+        }
+    }).await {
+        Ok(files) => files,
+        Err(e) => return e.into_response(),
+    };
+    remove_uploads(files).await;
+    if delete && location.thread_id == id {
+        Redirect::to(&format!("/{}/", location.board)).into_response()
+    } else {
+        Redirect::to(&format!("/{}/thread/{}", location.board, location.thread_id)).into_response()
+    }
+}
+
+// The slugs of every board `moderator` can act on.
+async fn moderated_boards(state: &AppState, moderator: &Moderator) -> Vec<String> {
+    state.boards.all().await.into_iter().map(|b| b.slug).filter(|slug| moderator.can_act_on(slug)).collect()
+}
+
+// The moderation log. Janitors see only what happened on their boards.
+pub async fn log_page(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(filter): Query<LogFilter>,
+) -> Response {
+    let moderator = match require_moderator(&state, &headers).await {
+        Ok(m) => m,
+        Err(page) => return page,
+    };
+    let boards = moderated_boards(&state, &moderator).await;
+    let scope = (moderator.role == Role::Janitor).then(|| boards.clone());
+    let query = filter.clone();
+    let (entries, total, actors) = match state.db.run(move |db| {
+        let (entries, total) = modlog::get_log(db, &query, scope.as_deref());
+        (entries, total, modlog::get_actors(db))
+    }).await {
+        Ok(v) => v,
+        Err(e) => return e.into_response(),
+    };
+    Html(log_view(Some(moderator), entries, total, filter, actors, boards)).into_response()
+}
+
+pub fn log_view(
+    moderator: Option<Moderator>,
+    entries: Vec<LogEntry>,
+    total: usize,
+    filter: LogFilter,
+    actors: Vec<String>,
+    boards: Vec<String>,
+) -> String {
+    let entries = entries
+        .into_iter()
+        .map(|entry| LogItem { created: format_time(entry.created), entry })
+        .collect();
+    ModLog {
+        moderator,
+        entries,
+        filter,
+        actors,
+        actions: LOG_ACTIONS,
+        boards,
+        page_count: total.div_ceil(LOG_ENTRIES_PER_PAGE),
+    }.render().unwrap()
+}
+
+// Open reports on the moderator's boards, grouped by post.
+pub async fn reports_page(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let moderator = match require_moderator(&state, &headers).await {
+        Ok(m) => m,
+        Err(page) => return page,
+    };
+    let slugs = moderated_boards(&state, &moderator).await;
+    let groups = match state.db.run(move |db| reports::get_report_groups(db, &slugs)).await {
+        Ok(groups) => groups,
+        Err(e) => return e.into_response(),
+    };
+    let groups = groups
+        .into_iter()
+        .map(|group| ReportGroupItem { latest: format_time(group.reports[0].created), group })
+        .collect();
+    Html(AdminReports { moderator, groups }.render().unwrap()).into_response()
+}
+
+// Close the reports on a post, leaving it up.
+pub async fn dismiss_reports(State(state): State<AppState>, headers: HeaderMap, Path(id): Path<i64>) -> Response {
+    let moderator = match require_moderator(&state, &headers).await {
+        Ok(m) => m,
+        Err(page) => return page,
+    };
+    let board = match require_post_access(&state, &moderator, id).await {
+        Ok(loc) => loc.board,
+        Err(page) => return page,
+    };
+    if let Err(e) = state.db.run(move |db| {
+        reports::dismiss_reports(db, id);
+        modlog::record(db, Actor::Moderator(&moderator), NewEntry {
+            action: "dismiss_reports",
+            board: Some(&board),
+            target: Some(id),
+            details: format!("Dismissed the reports on No.{id}"),
+            snapshot: None,
+        });
+    }).await {
+        return e.into_response();
+    }
+    Redirect::to("/admin/reports").into_response()
+}
+
+// Rules on one board are managed by its moderators; rules on every board,
+// and rules that ban, by those who can ban.
+fn can_manage_filter(moderator: &Moderator, board: Option<&str>, action: &FilterAction) -> bool {
+    let scope = match board {
+        Some(board) => moderator.can_act_on(board),
+        None => moderator.can_ban(),
+    };
+    scope && (moderator.can_ban() || !matches!(action, FilterAction::Ban { .. }))
+}
+
+pub async fn filters_page(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let moderator = match require_moderator(&state, &headers).await {
+        Ok(m) => m,
+        Err(page) => return page,
+    };
+    let boards: Vec<_> = state.boards.all().await.into_iter().filter(|b| moderator.can_act_on(&b.slug)).collect();
+    let filters = match state.db.run(filters::get_filters).await {
+        Ok(filters) => filters,
+        Err(e) => return e.into_response(),
+    };
+    let filters = filters
+        .into_iter()
+        .filter(|f| f.board.as_deref().is_none_or(|board| moderator.can_act_on(board)))
+        .map(|filter| FilterItem {
+            created: format_time(filter.created),
+            can_delete: can_manage_filter(&moderator, filter.board.as_deref(), &filter.action),
+            filter,
+        })
+        .collect();
+    Html(AdminFilters { moderator, boards, filters }.render().unwrap()).into_response()
+}
+
+pub async fn add_filter(State(state): State<AppState>, headers: HeaderMap, Form(form): Form<FilterForm>) -> Response {
+    let moderator = match require_moderator(&state, &headers).await {
+        Ok(m) => m,
+        Err(page) => return page,
+    };
+    let back = "/admin/filters".to_string();
+    let is_regex = form.regex.is_some();
+    if let Err(reason) = filters::compile(&form.pattern, is_regex) {
+        return error_page(reason, back);
+    }
+    let text = form.text.trim().to_string();
+    let Some(action) = FilterAction::parse(&form.action, text.clone(), form.hours) else {
+        return error_page("Pick an action.", back);
+    };
+    if text.is_empty() && matches!(action, FilterAction::Reject(_) | FilterAction::Ban { .. }) {
+        return error_page("Give a message; the poster will see it.", back);
+    }
+    let board = Some(form.board).filter(|b| !b.is_empty());
+    if let Some(board) = &board {
+        if state.boards.get(board).await.is_none() {
+            return error_page(format!("No board '{board}'."), back);
+        }
+    }
+    if !can_manage_filter(&moderator, board.as_deref(), &action) {
+        return error_page("You can't add that rule.", back);
+    }
+    if let Err(e) = state.db.run(move |db| {
+        let filter_id = filters::insert_filter(db, &form.pattern, is_regex, board.as_deref(), &action, moderator.id);
+        modlog::record(db, Actor::Moderator(&moderator), NewEntry {
+            action: "filter_add",
+            board: board.as_deref(),
+            target: Some(filter_id),
+            details: format!("Added filter #{filter_id}: {}", filters::describe(&form.pattern, is_regex, &action)),
+            snapshot: None,
+        });
+    }).await {
+        return e.into_response();
+    }
+    Redirect::to("/admin/filters").into_response()
+}
+
+pub async fn delete_filter(State(state): State<AppState>, headers: HeaderMap, Path(id): Path<i64>) -> Response {
+    let moderator = match require_moderator(&state, &headers).await {
+        Ok(m) => m,
+        Err(page) => return page,
+    };
+    let filter = match state.db.run(move |db| filters::get_filter(db, id)).await {
+        Ok(Some(filter)) => filter,
+        Ok(None) => return error_page("No such rule.", "/admin/filters".to_string()),
+        Err(e) => return e.into_response(),
+    };
+    if !can_manage_filter(&moderator, filter.board.as_deref(), &filter.action) {
+        return error_page("You can't delete that rule.", "/admin/filters".to_string());
+    }
+    if let Err(e) = state.db.run(move |db| {
+        filters::delete_filter(db, id);
+        modlog::record(db, Actor::Moderator(&moderator), NewEntry {
+            action: "filter_delete",
+            board: filter.board.as_deref(),
+            target: Some(id),
+            details: format!(
+                "Deleted filter #{id}: {}",
+                filters::describe(&filter.pattern, filter.is_regex, &filter.action)
+            ),
+            snapshot: None,
+        });
+    }).await {
+        return e.into_response();
+    }
+    Redirect::to("/admin/filters").into_response()
+}
+
+pub async fn unban(State(state): State<AppState>, headers: HeaderMap, Path(ban_id): Path<i64>) -> Response {
+    let moderator = match require_moderator(&state, &headers).await {
+        Ok(m) => m,
+        Err(page) => return page,
+    };
+    if !moderator.can_ban() {
+        return error_page("Janitors can't lift bans.", "/admin".to_string());
+    }
+    if let Err(e) = state.db.run(move |db| {
+        let Some(ban) = bans::get_ban(db, ban_id) else {
+            return;
+        };
+        bans::delete_ban(db, ban_id);
+        modlog::record(db, Actor::Moderator(&moderator), NewEntry {
+            action: "unban",
+            board: ban.board.as_deref(),
+            target: Some(ban_id),
+            details: format!("Lifted ban #{ban_id}: {}", ban.reason),
+            snapshot: None,
+        });
+    }).await {
+        return e.into_response();
+    }
+    Redirect::to("/admin").into_response()
+}
@@ -0,0 +1,127 @@
+// ===== Archive Settings =====
+pub const ARCHIVED_THREADS_PER_PAGE: usize = 50;
+// How often archived threads past their board's retention are deleted.
+pub const PURGE_INTERVAL_SECS: u64 = 3600;
+// ===== End Archive Settings =====
+
+use crate::admin;
+use crate::boards::{BoardDef, BoardRegistry};
+use crate::db::Db;
+use crate::models::{self, REPLY_PREVIEW_LENGTH};
+use crate::templates::url_encode;
+use mysql::*;
+use mysql::prelude::*;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Threads pruned off a board (see models::prune_threads) stay in the threads
+// table with `archived` set. They are read-only, left off the board pages,
+// and listed in the board's archive until the retention purge deletes them.
+
+// One row of the archive index.
+#[derive(Debug, Clone)]
+pub struct ArchivedThread {
+    pub id: i64,
+    pub subject: String,
+    pub preview: String,
+    pub created: i64,
+    pub archived: i64,
+    pub replies: u64,
+}
+
+// The archive index's search box and page, from the query string.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ArchiveQuery {
+    pub q: String,
+    pub page: usize,
+}
+
+impl ArchiveQuery {
+    // The search and a page number as a URL query string, for page links.
+    pub fn query(&self, page: usize) -> String {
+        format!("?q={}&page={page}", url_encode(&self.q))
+    }
+}
+
+// The text as a LIKE pattern that matches it anywhere.
+fn like_pattern(text: &str) -> String {
+    let escaped = text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+    format!("%{escaped}%")
+}
+
+// Archived threads on a board, most recently archived first, with the total
+// for paging. A search matches the subject or the text of any post in the
+// thread.
+pub fn get_archived(db: &mut PooledConn, board: &str, query: &ArchiveQuery) -> (Vec<ArchivedThread>, usize) {
+    let search = query.q.trim();
+    let clause = if search.is_empty() {
+        "t.board = :board AND t.archived IS NOT NULL"
+    } else {
+        "t.board = :board AND t.archived IS NOT NULL
+         AND (t.subject LIKE :pattern OR t.message LIKE :pattern
+              OR EXISTS (SELECT 1 FROM posts p WHERE p.thread_id = t.id AND p.message LIKE :pattern))"
+    };
+    let pattern = like_pattern(search);
+    let total: usize = db
+        .exec_first(
+            format!("SELECT COUNT(*) FROM threads t WHERE {clause}"),
+            params! { "board" => board, "pattern" => &pattern },
+        )
+        .unwrap()
+        .unwrap_or(0);
+    let threads = db.exec_map(
+        format!(
+            "SELECT t.id, t.subject, t.message, t.created, t.archived,
+                    (SELECT COUNT(*) FROM posts p WHERE p.thread_id = t.id)
+             FROM threads t WHERE {clause}
+             ORDER BY t.archived DESC, t.id DESC LIMIT :limit OFFSET :offset"
+        ),
+        params! {
+            "board" => board,
+            "pattern" => &pattern,
+            "limit" => ARCHIVED_THREADS_PER_PAGE as u64,
+            "offset" => (query.page * ARCHIVED_THREADS_PER_PAGE) as u64,
+        },
+        |(id, subject, message, created, archived, replies): (i64, String, String, i64, i64, u64)| ArchivedThread {
+            id,
+            subject,
+            preview: models::preview_of(&message, REPLY_PREVIEW_LENGTH),
+            created,
+            archived,
+            replies,
+        },
+    ).unwrap();
+    (threads, total)
+}
+
+// Delete archived threads kept longer than their board's `archive_days`.
+// Returns the upload files to remove.
+pub fn purge(db: &mut PooledConn, boards: &[BoardDef]) -> Vec<String> {
+    let now = chrono::Utc::now().timestamp();
+    let mut files = Vec::new();
+    for board in boards.iter().filter(|b| b.archive_days > 0) {
+        let ids: Vec<i64> = db.exec(
+            "SELECT id FROM threads WHERE board = :board AND archived < :cutoff",
+            params! { "board" => &board.slug, "cutoff" => now - i64::from(board.archive_days) * 86400 },
+        ).unwrap();
+        for id in ids {
+            files.extend(models::delete_post(db, id).unwrap_or_default());
+        }
+    }
+    files
+}
+
+// Run `purge` in the background every PURGE_INTERVAL_SECS.
+pub async fn purge_task(db: Db, boards: Arc<BoardRegistry>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(PURGE_INTERVAL_SECS));
+    loop {
+        interval.tick().await;
+        let all = boards.all().await;
+        // A failed run is logged by Db::run and retried next time.
+        if let Ok(files) = db.run(move |db| purge(db, &all)).await {
+            admin::remove_uploads(files).await;
+        }
+    }
+}
@@ -0,0 +1,212 @@
+// ===== Moderator Settings =====
+pub const SESSION_COOKIE: &str = "mod_session";
+pub const MIN_PASSWORD_LEN: usize = 8;
+// ===== End Moderator Settings =====
+
+use crate::state::AppState;
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use axum::http::HeaderMap;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use mysql::*;
+use mysql::prelude::*;
+use sha2::Sha256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    // Everything, on every board.
+    Admin,
+    // Moderates every board.
+    GlobalMod,
+    // Cleans up the boards listed in moderator_boards only.
+    Janitor,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::GlobalMod => "globalmod",
+            Role::Janitor => "janitor",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Role> {
+        match s {
+            "admin" => Some(Role::Admin),
+            "globalmod" => Some(Role::GlobalMod),
+            "janitor" => Some(Role::Janitor),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Moderator {
+    pub id: i64,
+    pub username: String,
+    pub role: Role,
+    // Only used for janitors.
+    pub boards: Vec<String>,
+    password_hash: String,
+}
+
+impl Moderator {
+    pub fn can_act_on(&self, board: &str) -> bool {
+        match self.role {
+            Role::Admin | Role::GlobalMod => true,
+            Role::Janitor => self.boards.iter().any(|b| b == board),
+        }
+    }
+
+    // Janitors only clean up; banning is for admins and global mods.
+    pub fn can_ban(&self) -> bool {
+        self.role != Role::Janitor
+    }
+}
+
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default().hash_password(password.as_bytes(), &salt).unwrap().to_string()
+}
+
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    PasswordHash::new(hash)
+        .is_ok_and(|parsed| Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+}
+
+const MODERATOR_COLUMNS: &str = "id, username, password_hash, role";
+
+fn moderator_from_row(db: &mut PooledConn, mut row: Row) -> Moderator {
+    let id: i64 = row.take("id").unwrap();
+    let role: String = row.take("role").unwrap();
+    Moderator {
+        id,
+        username: row.take("username").unwrap(),
+        role: Role::parse(&role).unwrap_or(Role::Janitor),
+        boards: db.exec(
+            "SELECT board FROM moderator_boards WHERE moderator_id = :id ORDER BY board",
+            params! { "id" => id },
+        ).unwrap(),
+        password_hash: row.take("password_hash").unwrap(),
+    }
+}
+
+pub fn get_moderators(db: &mut PooledConn) -> Vec<Moderator> {
+    let rows: Vec<Row> = db.query(format!("SELECT {MODERATOR_COLUMNS} FROM moderators ORDER BY username")).unwrap();
+    rows.into_iter().map(|row| moderator_from_row(db, row)).collect()
+}
+
+pub fn get_moderator(db: &mut PooledConn, id: i64) -> Option<Moderator> {
+    let row: Row = db.exec_first(
+        format!("SELECT {MODERATOR_COLUMNS} FROM moderators WHERE id = :id"),
+        params! { "id" => id },
+    ).unwrap()?;
+    Some(moderator_from_row(db, row))
+}
+
+pub fn get_moderator_by_name(db: &mut PooledConn, username: &str) -> Option<Moderator> {
+    let row: Row = db.exec_first(
+        format!("SELECT {MODERATOR_COLUMNS} FROM moderators WHERE username = :username"),
+        params! { "username" => username },
+    ).unwrap()?;
+    Some(moderator_from_row(db, row))
+}
+
+pub fn insert_moderator(db: &mut PooledConn, username: &str, password: &str, role: Role, boards: &[String]) {
+    db.exec_drop(
+        "INSERT INTO moderators (username, password_hash, role, created) VALUES (:username, :hash, :role, :now)",
+        params! {
+            "username" => username,
+            "hash" => hash_password(password),
+            "role" => role.as_str(),
+            "now" => chrono::Utc::now().timestamp(),
+        },
+    ).unwrap();
+    let id = db.last_insert_id() as i64;
+    set_moderator_boards(db, id, boards);
+}
+
+// Changing the password also ends every session of that moderator, since
+// session cookies are signed over the hash.
+pub fn set_password(db: &mut PooledConn, id: i64, password: &str) {
+    db.exec_drop(
+        "UPDATE moderators SET password_hash = :hash WHERE id = :id",
+        params! { "hash" => hash_password(password), "id" => id },
+    ).unwrap();
+}
+
+pub fn set_role(db: &mut PooledConn, id: i64, role: Role) {
+    db.exec_drop(
+        "UPDATE moderators SET role = :role WHERE id = :id",
+        params! { "role" => role.as_str(), "id" => id },
+    ).unwrap();
+}
+
+pub fn set_moderator_boards(db: &mut PooledConn, id: i64, boards: &[String]) {
+    db.exec_drop("DELETE FROM moderator_boards WHERE moderator_id = :id", params! { "id" => id }).unwrap();
+    db.exec_batch(
+        "INSERT INTO moderator_boards (moderator_id, board) VALUES (:id, :board)",
+        boards.iter().map(|board| params! { "id" => id, "board" => board }),
+    ).unwrap();
+}
+
+pub fn delete_moderator(db: &mut PooledConn, id: i64) {
+    db.exec_drop("DELETE FROM moderators WHERE id = :id", params! { "id" => id }).unwrap();
+}
+
+// Check a login. Returns the moderator if the password matches.
+pub fn login(db: &mut PooledConn, username: &str, password: &str) -> Option<Moderator> {
+    get_moderator_by_name(db, username).filter(|m| verify_password(&m.password_hash, password))
+}
+
+// Session cookies are "<moderator id>.<expiry>.<signature>", signed with
+// SESSION_SECRET over the id, the expiry and the moderator's password hash.
+fn session_signature(secret: &str, id: i64, expires: i64, password_hash: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(format!("{id}.{expires}.{password_hash}").as_bytes());
+    mac
+}
+
+// The Set-Cookie value that logs `moderator` in.
+pub fn session_cookie(state: &AppState, moderator: &Moderator) -> String {
+    let ttl = state.config.session_ttl_hours * 3600;
+    let expires = chrono::Utc::now().timestamp() + ttl;
+    let sig = session_signature(&state.config.session_secret, moderator.id, expires, &moderator.password_hash)
+        .finalize()
+        .into_bytes();
+    let sig = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(sig);
+    let secure = if state.config.secure_cookies { "; Secure" } else { "" };
+    format!("{SESSION_COOKIE}={}.{expires}.{sig}; Path=/; Max-Age={ttl}; HttpOnly; SameSite=Strict{secure}", moderator.id)
+}
+
+// The Set-Cookie value that logs out.
+pub fn clear_session_cookie() -> String {
+    format!("{SESSION_COOKIE}=; Path=/; Max-Age=0; HttpOnly; SameSite=Strict")
+}
+
+pub fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(axum::http::header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|c| c.trim().strip_prefix(name)?.strip_prefix('='))
+}
+
+// The moderator whose valid session cookie came with the request, if any.
+pub async fn current_moderator(state: &AppState, headers: &HeaderMap) -> Option<Moderator> {
+    let mut parts = cookie(headers, SESSION_COOKIE)?.splitn(3, '.');
+    let id: i64 = parts.next()?.parse().ok()?;
+    let expires: i64 = parts.next()?.parse().ok()?;
+    let sig = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(parts.next()?).ok()?;
+    if expires < chrono::Utc::now().timestamp() {
+        return None;
+    }
+    let moderator = state.db.run(move |db| get_moderator(db, id)).await.ok()??;
+    session_signature(&state.config.session_secret, id, expires, &moderator.password_hash)
+        .verify_slice(&sig)
+        .ok()?;
+    Some(moderator)
+}
@@ -0,0 +1,132 @@
+use crate::{ip, iplog};
+use ipnet::IpNet;
+use mysql::*;
+use mysql::prelude::*;
+use std::fmt;
+use std::net::IpAddr;
+
+// What a ban matches.
+#[derive(Debug, Clone)]
+pub enum BanTarget {
+    // An address or CIDR range.
+    Range(IpNet),
+    // A salted address hash (with its salt id), for posts whose raw address
+    // isn't kept.
+    Hash(String, i64),
+}
+
+impl fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BanTarget::Range(net) => write!(f, "{net}"),
+            BanTarget::Hash(hash, _) => write!(f, "hashed address {}", &hash[..hash.len().min(10)]),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Ban {
+    pub id: i64,
+    pub target: BanTarget,
+    // None for a ban on every board.
+    pub board: Option<String>,
+    pub reason: String,
+    pub created: i64,
+    // None for a permanent ban.
+    pub expires: Option<i64>,
+    // Who issued it, if they still have an account.
+    pub moderator: Option<String>,
+}
+
+impl Ban {
+    pub fn covers_board(&self, board: &str) -> bool {
+        self.board.as_deref().is_none_or(|b| b == board)
+    }
+}
+
+const BAN_COLUMNS: &str = "b.id, b.ip_range, b.ip_hash, b.ip_salt, b.board, b.reason, b.created, b.expires, m.username";
+
+fn ban_from_row(mut row: Row) -> Option<Ban> {
+    let range: Option<String> = row.take("ip_range").unwrap();
+    let hash: Option<String> = row.take("ip_hash").unwrap();
+    let salt_id: Option<i64> = row.take("ip_salt").unwrap();
+    let target = match (range, hash, salt_id) {
+        (Some(range), _, _) => BanTarget::Range(ip::parse_range(&range)?),
+        (None, Some(hash), Some(salt_id)) => BanTarget::Hash(hash, salt_id),
+        _ => return None,
+    };
+    Some(Ban {
+        id: row.take("id").unwrap(),
+        target,
+        board: row.take("board").unwrap(),
+        reason: row.take("reason").unwrap(),
+        created: row.take("created").unwrap(),
+        expires: row.take("expires").unwrap(),
+        moderator: row.take("username").unwrap(),
+    })
+}
+
+// Bans that haven't expired, newest first.
+pub fn get_active_bans(db: &mut PooledConn) -> Vec<Ban> {
+    db.exec_map(
+        format!(
+            "SELECT {BAN_COLUMNS} FROM bans b LEFT JOIN moderators m ON b.moderator_id = m.id
+             WHERE b.expires IS NULL OR b.expires > :now
+             ORDER BY b.created DESC"
+        ),
+        params! { "now" => chrono::Utc::now().timestamp() },
+        ban_from_row,
+    ).unwrap().into_iter().flatten().collect()
+}
+
+pub fn get_ban(db: &mut PooledConn, id: i64) -> Option<Ban> {
+    db.exec_map(
+        format!("SELECT {BAN_COLUMNS} FROM bans b LEFT JOIN moderators m ON b.moderator_id = m.id WHERE b.id = :id"),
+        params! { "id" => id },
+        ban_from_row,
+    ).unwrap().into_iter().flatten().next()
+}
+
+// The ban stopping `ip` from posting on `board`, if any. When several
+// match, the one that lasts longest wins.
+pub fn find_ban(db: &mut PooledConn, ip: IpAddr, board: &str) -> Option<Ban> {
+    let salts = iplog::get_salts(db);
+    get_active_bans(db)
+        .into_iter()
+        .filter(|b| b.covers_board(board))
+        .filter(|b| match &b.target {
+            BanTarget::Range(net) => net.contains(&ip),
+            BanTarget::Hash(hash, salt_id) => iplog::hash_matches(&salts, ip, hash, *salt_id),
+        })
+        .max_by_key(|b| b.expires.unwrap_or(i64::MAX))
+}
+
+pub fn insert_ban(
+    db: &mut PooledConn,
+    target: &BanTarget,
+    board: Option<&str>,
+    reason: &str,
+    expires: Option<i64>,
+    // None for a ban issued by a filter rule.
+    moderator_id: Option<i64>,
+) -> i64 {
+    db.exec_drop(
+        "INSERT INTO bans (ip_range, ip_hash, ip_salt, board, reason, created, expires, moderator_id)
+         VALUES (:range, :hash, :salt_id, :board, :reason, :now, :expires, :moderator_id)",
+        params! {
+            "range" => match target { BanTarget::Range(net) => Some(net.to_string()), _ => None },
+            "hash" => match target { BanTarget::Hash(hash, _) => Some(hash.as_str()), _ => None },
+            "salt_id" => match target { BanTarget::Hash(_, salt_id) => Some(*salt_id), _ => None },
+            "board" => board,
+            "reason" => reason,
+            "now" => chrono::Utc::now().timestamp(),
+            "expires" => expires,
+            "moderator_id" => moderator_id,
+        },
+    ).unwrap();
+    db.last_insert_id() as i64
+}
+
+pub fn delete_ban(db: &mut PooledConn, id: i64) {
+    db.exec_drop("DELETE FROM bans WHERE id = :id", params! { "id" => id }).unwrap();
+}
@@ -1,31 +1,285 @@
-#[derive(Clone)]
-pub struct BoardDef {
+// ===== Board Registry Settings =====
+// How long the in-memory board list is trusted before it is re-read, so
+// changes made with `chess board ...` show up without a restart.
+pub const BOARD_CACHE_TTL_SECS: u64 = 30;
+// ===== End Board Registry Settings =====
+
+use crate::db::Db;
+use mysql::*;
+use mysql::prelude::*;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+pub struct BoardSeed {
     pub slug: &'static str,
     pub name: &'static str,
     pub desc: &'static str,
 }
 
-// Add/remove boards here!
-pub const BOARDS: &[BoardDef] = &[
-    BoardDef {
+// Seeded into the `boards` table on first run. After that, boards live in the
+// database: use `chess board add/rename/hide/delete ...` to manage them.
+pub const BOARDS: &[BoardSeed] = &[
+    BoardSeed {
         slug: "chess",
         name: "General Chess",
         desc: "Discuss chess questions, general strategy, analysis, news, and all things chess. Friendly for all levels.",
     },
-    BoardDef {
+    BoardSeed {
         slug: "puzzles",
         name: "Puzzles",
         desc: "Share, solve, and discuss chess puzzles and studies. Tactics, checkmates, and brilliant finds!",
     },
-    BoardDef {
+    BoardSeed {
         slug: "openings",
         name: "Openings",
         desc: "Debate opening theory, share repertoires, and explore move orders from the first move onward.",
     },
-    // Example of adding another board:
-    // BoardDef {
-    //     slug: "endgames",
-    //     name: "Endgames",
-    //     desc: "All about chess endgames: techniques, studies, and tricky endings.",
-    // },
-];
\ No newline at end of file
+];
+
+// Paths that can't be board slugs because the router already uses them.
+const RESERVED_SLUGS: &[&str] = &["static", "uploads", "admin", "captcha"];
+
+// Which posts on a board need a CAPTCHA (the image, or the proof-of-work
+// that can stand in for it; see CaptchaKind).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CaptchaMode {
+    Off,
+    Threads,
+    All,
+}
+
+impl CaptchaMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CaptchaMode::Off => "off",
+            CaptchaMode::Threads => "threads",
+            CaptchaMode::All => "all",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<CaptchaMode> {
+        match s {
+            "off" => Some(CaptchaMode::Off),
+            "threads" => Some(CaptchaMode::Threads),
+            "all" => Some(CaptchaMode::All),
+            _ => None,
+        }
+    }
+}
+
+// How a post passes the CAPTCHA check: by reading the image, by letting the
+// browser do a proof-of-work, or by either one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CaptchaKind {
+    Image,
+    Pow,
+    Either,
+}
+
+impl CaptchaKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CaptchaKind::Image => "image",
+            CaptchaKind::Pow => "pow",
+            CaptchaKind::Either => "either",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<CaptchaKind> {
+        match s {
+            "image" => Some(CaptchaKind::Image),
+            "pow" => Some(CaptchaKind::Pow),
+            "either" => Some(CaptchaKind::Either),
+            _ => None,
+        }
+    }
+
+    pub fn image(self) -> bool {
+        self != CaptchaKind::Pow
+    }
+
+    pub fn pow(self) -> bool {
+        self != CaptchaKind::Image
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BoardDef {
+    pub slug: String,
+    pub name: String,
+    pub desc: String,
+    pub position: i32,
+    // Left off the board list but still reachable by URL.
+    pub hidden: bool,
+    // Readable, but new threads and replies are refused.
+    pub locked: bool,
+    // Flood control, in seconds (0 turns a limit off): the wait between any
+    // two posts, the wait between two new threads, and how long a message
+    // can't be posted again by the same client.
+    pub post_interval: i32,
+    pub thread_interval: i32,
+    pub duplicate_window: i32,
+    pub captcha: CaptchaMode,
+    pub captcha_kind: CaptchaKind,
+    // Base proof-of-work difficulty in bits; busy periods add more.
+    pub pow_bits: i32,
+    // Replies past this many no longer bump a thread (0 for no limit).
+    pub bump_limit: i32,
+    // When a new thread takes the board past this many, the least recently
+    // bumped threads are pruned (0 for no cap). Stickies don't count.
+    pub max_threads: i32,
+    // A cyclical thread keeps only this many replies: each new one past it
+    // deletes the oldest (0 for no cap).
+    pub cyclical_replies: i32,
+    // Pruned threads stay readable in the board's archive instead of being
+    // deleted, for `archive_days` days (0 keeps them forever).
+    pub archive: bool,
+    pub archive_days: i32,
+}
+
+impl BoardDef {
+    pub fn captcha_for_threads(&self) -> bool {
+        self.captcha != CaptchaMode::Off
+    }
+
+    pub fn captcha_for_replies(&self) -> bool {
+        self.captcha == CaptchaMode::All
+    }
+}
+
+const BOARD_COLUMNS: &str =
+    "slug, name, description, position, hidden, locked, post_interval, thread_interval, duplicate_window, captcha, captcha_kind, pow_bits, bump_limit, max_threads, cyclical_replies, archive, archive_days";
+
+fn board_from_row(mut row: Row) -> BoardDef {
+    BoardDef {
+        slug: row.take("slug").unwrap(),
+        name: row.take("name").unwrap(),
+        desc: row.take("description").unwrap(),
+        position: row.take("position").unwrap(),
+        hidden: row.take("hidden").unwrap(),
+        locked: row.take("locked").unwrap(),
+        post_interval: row.take("post_interval").unwrap(),
+        thread_interval: row.take("thread_interval").unwrap(),
+        duplicate_window: row.take("duplicate_window").unwrap(),
+        captcha: CaptchaMode::parse(&row.take::<String, _>("captcha").unwrap()).unwrap_or(CaptchaMode::Off),
+        captcha_kind: CaptchaKind::parse(&row.take::<String, _>("captcha_kind").unwrap()).unwrap_or(CaptchaKind::Image),
+        pow_bits: row.take("pow_bits").unwrap(),
+        bump_limit: row.take("bump_limit").unwrap(),
+        max_threads: row.take("max_threads").unwrap(),
+        cyclical_replies: row.take("cyclical_replies").unwrap(),
+        archive: row.take("archive").unwrap(),
+        archive_days: row.take("archive_days").unwrap(),
+    }
+}
+
+pub fn valid_slug(slug: &str) -> bool {
+    !slug.is_empty()
+        && slug.len() <= 32
+        && slug.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit())
+        && !RESERVED_SLUGS.contains(&slug)
+}
+
+// Run by the migration that creates the boards table.
+pub fn seed_boards(db: &mut PooledConn) {
+    for (i, b) in BOARDS.iter().enumerate() {
+        insert_board(db, b.slug, b.name, b.desc, i as i32);
+    }
+}
+
+pub fn get_boards(db: &mut PooledConn) -> Result<Vec<BoardDef>, mysql::Error> {
+    db.exec_map(
+        format!("SELECT {BOARD_COLUMNS} FROM boards ORDER BY position, slug"),
+        (),
+        board_from_row,
+    )
+}
+
+pub fn insert_board(db: &mut PooledConn, slug: &str, name: &str, desc: &str, position: i32) {
+    db.exec_drop(
+        "INSERT INTO boards (slug, name, description, position) VALUES (:slug, :name, :desc, :position)",
+        params! { "slug" => slug, "name" => name, "desc" => desc, "position" => position },
+    ).unwrap();
+}
+
+pub fn next_position(db: &mut PooledConn) -> i32 {
+    db.query_first::<Option<i32>, _>("SELECT MAX(position) FROM boards")
+        .unwrap()
+        .flatten()
+        .map_or(0, |p| p + 1)
+}
+
+// Update one column of a board.
+pub fn set_board_field<V: Into<Value>>(db: &mut PooledConn, slug: &str, column: &str, value: V) {
+    let column = match column {
+        "name" | "description" | "position" | "hidden" | "locked" | "post_interval" | "thread_interval"
+        | "duplicate_window" | "captcha" | "captcha_kind" | "pow_bits" | "bump_limit" | "max_threads"
+        | "cyclical_replies" | "archive" | "archive_days" => column,
+        _ => panic!("not a board column: {column}"),
+    };
+    db.exec_drop(
+        format!("UPDATE boards SET {column} = :value WHERE slug = :slug"),
+        params! { "value" => value.into(), "slug" => slug },
+    ).unwrap();
+}
+
+// Delete a board and all of its threads and posts. Returns the upload files
+// (originals and thumbnails) that belonged to them, for the caller to remove.
+pub fn delete_board(db: &mut PooledConn, slug: &str) -> Vec<String> {
+    let files: Vec<(Option<String>, Option<String>)> = db.exec(
+        "SELECT media, thumb FROM threads WHERE board = :slug
+         UNION ALL
+         SELECT p.media, p.thumb FROM posts p JOIN threads t ON p.thread_id = t.id WHERE t.board = :slug",
+        params! { "slug" => slug },
+    ).unwrap();
+    db.exec_drop("DELETE FROM threads WHERE board = :slug", params! { "slug" => slug }).unwrap();
+    db.exec_drop("DELETE FROM post_refs WHERE from_board = :slug", params! { "slug" => slug }).unwrap();
+    db.exec_drop("DELETE FROM moderator_boards WHERE board = :slug", params! { "slug" => slug }).unwrap();
+    db.exec_drop("DELETE FROM filters WHERE board = :slug", params! { "slug" => slug }).unwrap();
+    db.exec_drop("DELETE FROM reports WHERE board = :slug", params! { "slug" => slug }).unwrap();
+    db.exec_drop("DELETE FROM boards WHERE slug = :slug", params! { "slug" => slug }).unwrap();
+    files.into_iter().flat_map(|(m, t)| m.into_iter().chain(t)).collect()
+}
+
+struct CachedBoards {
+    boards: Vec<BoardDef>,
+    loaded_at: Option<Instant>,
+}
+
+// In-memory copy of the boards table, re-read once it is older than
+// BOARD_CACHE_TTL_SECS.
+pub struct BoardRegistry {
+    db: Db,
+    cache: RwLock<CachedBoards>,
+}
+
+impl BoardRegistry {
+    pub fn new(db: Db) -> BoardRegistry {
+        BoardRegistry {
+            db,
+            cache: RwLock::new(CachedBoards { boards: Vec::new(), loaded_at: None }),
+        }
+    }
+
+    // All boards in display order, hidden ones included. If the database
+    // can't be reached, the last list read is used until it can.
+    pub async fn all(&self) -> Vec<BoardDef> {
+        {
+            let cache = self.cache.read().unwrap();
+            let fresh = cache
+                .loaded_at
+                .is_some_and(|t| t.elapsed() < Duration::from_secs(BOARD_CACHE_TTL_SECS));
+            if fresh {
+                return cache.boards.clone();
+            }
+        }
+        let Ok(Ok(boards)) = self.db.run(get_boards).await else {
+            return self.cache.read().unwrap().boards.clone();
+        };
+        *self.cache.write().unwrap() = CachedBoards { boards: boards.clone(), loaded_at: Some(Instant::now()) };
+        boards
+    }
+
+    pub async fn get(&self, slug: &str) -> Option<BoardDef> {
+        self.all().await.into_iter().find(|b| b.slug == slug)
+    }
+}
@@ -0,0 +1,217 @@
+// ===== CAPTCHA Settings =====
+// How long a challenge can be answered after the page showing it loads.
+pub const CAPTCHA_TTL_SECS: u64 = 600;
+// Characters per challenge.
+pub const CAPTCHA_LENGTH: usize = 6;
+// Unanswered challenges kept at most; the oldest are dropped past this.
+pub const MAX_PENDING_CAPTCHAS: usize = 50_000;
+// ===== End CAPTCHA Settings =====
+
+use image::{ImageFormat, Rgb, RgbImage};
+use rand::Rng;
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Challenges are made and checked here, with no outside service. A page with
+// a post form gets a fresh random token; /captcha/{token} draws that token's
+// text as a PNG. The answer lives only in memory, and a token is removed the
+// first time it is checked, right or wrong, so each one can be tried once.
+
+// Letters and digits that can't be mistaken for one another (no 0/O, 1/I/L,
+// 2/Z, 5/S, 8/B).
+const ALPHABET: &[u8] = b"ACDEFHJKMNPRTUVWXY34679";
+
+// 5x7 glyphs for ALPHABET, one row per byte, high bit on the left.
+const GLYPHS: &[[u8; 7]] = &[
+    [0x0E, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11], // A
+    [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E], // C
+    [0x1E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x1E], // D
+    [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F], // E
+    [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10], // F
+    [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11], // H
+    [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C], // J
+    [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11], // K
+    [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11], // M
+    [0x11, 0x19, 0x15, 0x13, 0x11, 0x11, 0x11], // N
+    [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10], // P
+    [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11], // R
+    [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04], // T
+    [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E], // U
+    [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04], // V
+    [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A], // W
+    [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11], // X
+    [0x11, 0x11, 0x0A, 0x04, 0x04, 0x04, 0x04], // Y
+    [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E], // 3
+    [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02], // 4
+    [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E], // 6
+    [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08], // 7
+    [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C], // 9
+];
+
+// Glyph pixels are drawn as SCALE x SCALE blocks before the image is warped.
+const SCALE: u32 = 4;
+const CELL_WIDTH: u32 = 6 * SCALE;
+const MARGIN: u32 = 12;
+const WIDTH: u32 = 2 * MARGIN + CAPTCHA_LENGTH as u32 * CELL_WIDTH;
+const HEIGHT: u32 = 60;
+
+struct Challenge {
+    answer: String,
+    expires: Instant,
+}
+
+#[derive(Default)]
+pub struct CaptchaStore {
+    pending: Mutex<HashMap<String, Challenge>>,
+}
+
+impl CaptchaStore {
+    // A new challenge; the token goes into the form and the image URL.
+    pub fn issue(&self) -> String {
+        let mut rng = rand::thread_rng();
+        let token: String = (0..32).map(|_| format!("{:x}", rng.gen_range(0..16))).collect();
+        let answer: String = (0..CAPTCHA_LENGTH)
+            .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+            .collect();
+        let now = Instant::now();
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, c| c.expires > now);
+        if pending.len() >= MAX_PENDING_CAPTCHAS {
+            // Every page view makes one, so a crawler can fill the table;
+            // drop the tenth that expires soonest.
+            let mut expiries: Vec<Instant> = pending.values().map(|c| c.expires).collect();
+            expiries.sort_unstable();
+            let cutoff = expiries[MAX_PENDING_CAPTCHAS / 10];
+            pending.retain(|_, c| c.expires > cutoff);
+        }
+        pending.insert(token.clone(), Challenge { answer, expires: now + Duration::from_secs(CAPTCHA_TTL_SECS) });
+        token
+    }
+
+    // The PNG for a token, or None once it has expired or been used.
+    pub fn image(&self, token: &str) -> Option<Vec<u8>> {
+        let answer = {
+            let pending = self.pending.lock().unwrap();
+            let challenge = pending.get(token).filter(|c| c.expires > Instant::now())?;
+            challenge.answer.clone()
+        };
+        Some(draw(&answer))
+    }
+
+    // Check an answer, using the token up either way. Case and spaces don't
+    // matter.
+    pub fn verify(&self, token: &str, answer: &str) -> bool {
+        let Some(challenge) = self.pending.lock().unwrap().remove(token) else {
+            return false;
+        };
+        let answer: String = answer.chars().filter(|c| !c.is_whitespace()).collect();
+        challenge.expires > Instant::now() && answer.eq_ignore_ascii_case(&challenge.answer)
+    }
+}
+
+fn glyph(c: u8) -> &'static [u8; 7] {
+    &GLYPHS[ALPHABET.iter().position(|&a| a == c).unwrap()]
+}
+
+// Draw the text with each character jittered and the whole image bent by a
+// random wave, then cross it with a few lines and sprinkle noise.
+fn draw(text: &str) -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let background = Rgb([238, 242, 250]);
+    let ink = Rgb([rng.gen_range(20..80), rng.gen_range(20..80), rng.gen_range(60..120)]);
+
+    let mut flat = RgbImage::from_pixel(WIDTH, HEIGHT, background);
+    for (i, c) in text.bytes().enumerate() {
+        let x0 = MARGIN + i as u32 * CELL_WIDTH + rng.gen_range(0..4);
+        let y0 = (HEIGHT - 7 * SCALE) / 2 + rng.gen_range(0..8) - 4;
+        // Lean each character a little: shift rows sideways by a set amount.
+        let lean: f32 = rng.gen_range(-0.35..0.35);
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for col in 0..5 {
+                if bits & (0x10 >> col) == 0 {
+                    continue;
+                }
+                for dy in 0..SCALE {
+                    let y = y0 + row as u32 * SCALE + dy;
+                    let shift = (lean * (y as f32 - HEIGHT as f32 / 2.0)) as i32;
+                    for dx in 0..SCALE {
+                        let x = (x0 + col * SCALE + dx) as i32 + shift;
+                        if (0..WIDTH as i32).contains(&x) && y < HEIGHT {
+                            flat.put_pixel(x as u32, y, ink);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    let amplitude: f32 = rng.gen_range(2.5..4.5);
+    let period: f32 = rng.gen_range(30.0..60.0);
+    let phase: f32 = rng.gen_range(0.0..std::f32::consts::TAU);
+    let mut image = RgbImage::from_pixel(WIDTH, HEIGHT, background);
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let sy = y as f32 + amplitude * ((x as f32 / period) * std::f32::consts::TAU + phase).sin();
+            let sx = x as f32 + amplitude / 2.0 * ((y as f32 / period) * std::f32::consts::TAU + phase).cos();
+            if sx >= 0.0 && sy >= 0.0 && (sx as u32) < WIDTH && (sy as u32) < HEIGHT {
+                image.put_pixel(x, y, *flat.get_pixel(sx as u32, sy as u32));
+            }
+        }
+    }
+
+    for _ in 0..4 {
+        let (mut x, mut y) = (0.0f32, rng.gen_range(0.0..HEIGHT as f32));
+        let slope: f32 = rng.gen_range(-0.4..0.4);
+        while x < WIDTH as f32 {
+            if (0.0..HEIGHT as f32).contains(&y) {
+                image.put_pixel(x as u32, y as u32, ink);
+            }
+            x += 1.0;
+            y += slope;
+        }
+    }
+    for _ in 0..(WIDTH * HEIGHT / 12) {
+        let (x, y) = (rng.gen_range(0..WIDTH), rng.gen_range(0..HEIGHT));
+        let shade = rng.gen_range(120..230);
+        image.put_pixel(x, y, Rgb([shade, shade, shade]));
+    }
+
+    let mut png = Vec::new();
+    image.write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+    png
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answer(store: &CaptchaStore, token: &str) -> String {
+        store.pending.lock().unwrap()[token].answer.clone()
+    }
+
+    #[test]
+    fn token_verifies_once() {
+        let store = CaptchaStore::default();
+        let token = store.issue();
+        let right = answer(&store, &token);
+        assert!(store.image(&token).is_some());
+        // Case and spacing don't matter.
+        let typed = format!(" {} ", right.to_lowercase());
+        assert!(store.verify(&token, &typed));
+        assert!(!store.verify(&token, &right));
+        assert!(store.image(&token).is_none());
+    }
+
+    #[test]
+    fn wrong_answer_fails_and_uses_the_token() {
+        let store = CaptchaStore::default();
+        let token = store.issue();
+        let right = answer(&store, &token);
+        let wrong: String = right.chars().rev().chain("X".chars()).collect();
+        assert!(!store.verify(&token, &wrong));
+        assert!(!store.verify(&token, &right));
+        assert!(!store.verify("no such token", &right));
+    }
+}
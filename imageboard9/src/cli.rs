@@ -0,0 +1,372 @@
+use crate::auth::{self, Role, MIN_PASSWORD_LEN};
+use crate::boards::{self, CaptchaKind, CaptchaMode};
+use crate::db::Db;
+use crate::migrations;
+use crate::modlog::{self, Actor, NewEntry};
+use crate::pow::POW_MAX_TOTAL_BITS;
+use mysql::{PooledConn, Value};
+use std::io::Write;
+
+const USAGE: &str = "Usage: chess [--reset-db] [--migrate | --status]
+       chess board <command> ...
+       chess mod <command> ...
+
+  (no flags)   apply pending migrations, then start the server
+  --migrate    apply pending migrations and exit
+  --status     list applied and pending migrations and exit
+  --reset-db   DROP EVERY TABLE before migrating (deletes all posts!)
+
+Board commands (a running server picks changes up within 30 seconds):
+  board list
+  board add <slug> <name> [description]
+  board rename <slug> <name>
+  board describe <slug> <description>
+  board move <slug> <position>
+  board hide <slug>      leave off the board list (still reachable by URL)
+  board show <slug>
+  board lock <slug>      refuse new threads and replies
+  board unlock <slug>
+  board limits <slug> <post> <thread> <duplicate>
+                         flood control, in seconds (0 = off): between posts,
+                         between new threads, and before a message can repeat
+  board captcha <slug> <off|threads|all> [image|pow|either]
+                         ask for a CAPTCHA on new threads, or on every post:
+                         an image to read, a proof-of-work done by the
+                         browser, or a choice of the two
+  board pow <slug> <bits>
+                         base proof-of-work difficulty (16 takes a second or
+                         so; each extra bit doubles it)
+  board size <slug> <bump limit> <max threads> [cyclical replies]
+                         replies that bump a thread, threads kept before
+                         the least recently bumped are pruned, and replies
+                         a cyclical thread keeps (0 = no limit)
+  board archive <slug> <on|off> [days]
+                         keep pruned threads read-only in the board's
+                         archive instead of deleting them, for the given
+                         number of days (0 = forever)
+  board delete <slug>    delete the board with all its threads and files
+
+Moderator commands (roles: admin, globalmod, janitor; janitors only act on
+the boards given). add and passwd ask for the password on stdin.
+  mod list
+  mod add <username> <role> [board ...]
+  mod passwd <username>
+  mod role <username> <role> [board ...]
+  mod delete <username>";
+
+fn usage_error(msg: &str) -> ! {
+    eprintln!("{msg}\n\n{USAGE}");
+    std::process::exit(2);
+}
+
+// Handle command-line flags and bring the schema up to date.
+// Returns false if the process should exit instead of serving.
+pub fn run(db: &Db) -> bool {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut conn = db.conn().unwrap_or_else(|e| {
+        eprintln!("Can't reach the database: {e}");
+        std::process::exit(1);
+    });
+
+    if args.first().map(String::as_str) == Some("board") {
+        migrations::migrate(&mut conn);
+        board_command(&mut conn, &args[1..]);
+        return false;
+    }
+    if args.first().map(String::as_str) == Some("mod") {
+        migrations::migrate(&mut conn);
+        mod_command(&mut conn, &args[1..]);
+        return false;
+    }
+
+    let has = |flag: &str| args.iter().any(|a| a == flag);
+    if let Some(unknown) = args.iter().find(|a| !["--migrate", "--status", "--reset-db"].contains(&a.as_str())) {
+        usage_error(&format!("Unknown argument '{unknown}'"));
+    }
+
+    if has("--status") {
+        migrations::print_status(&mut conn);
+        return false;
+    }
+    if has("--reset-db") {
+        migrations::reset(&mut conn);
+    }
+    let ran = migrations::migrate(&mut conn);
+    if has("--migrate") {
+        println!("{ran} migration(s) applied.");
+        return false;
+    }
+    true
+}
+
+fn board_command(conn: &mut PooledConn, args: &[String]) {
+    let arg = |i: usize| -> &str {
+        args.get(i).map(String::as_str).unwrap_or_else(|| usage_error("Missing argument"))
+    };
+    let slug = || {
+        let slug = arg(1);
+        if !boards::valid_slug(slug) {
+            usage_error(&format!(
+                "Invalid slug '{slug}': use 1-32 lowercase letters or digits, not static/uploads/admin/captcha"
+            ));
+        }
+        slug
+    };
+
+    match arg(0) {
+        "list" => {
+            for b in boards::get_boards(conn).unwrap() {
+                let mut flags = Vec::new();
+                if b.hidden {
+                    flags.push("hidden");
+                }
+                if b.locked {
+                    flags.push("locked");
+                }
+                println!(
+                    "{:>3}  /{}/  {}  {}  (limits {}s/{}s/{}s, captcha {} {}, pow {} bits, bump limit {}, max {} threads, cyclical {} replies, archive {})",
+                    b.position,
+                    b.slug,
+                    b.name,
+                    flags.join(" "),
+                    b.post_interval,
+                    b.thread_interval,
+                    b.duplicate_window,
+                    b.captcha.as_str(),
+                    b.captcha_kind.as_str(),
+                    b.pow_bits,
+                    b.bump_limit,
+                    b.max_threads,
+                    b.cyclical_replies,
+                    match (b.archive, b.archive_days) {
+                        (false, _) => "off".to_string(),
+                        (true, 0) => "forever".to_string(),
+                        (true, days) => format!("{days} days"),
+                    }
+                );
+            }
+        }
+        "add" => {
+            let slug = slug();
+            if boards::get_boards(conn).unwrap().iter().any(|b| b.slug == slug) {
+                eprintln!("Board '{slug}' already exists.");
+                std::process::exit(1);
+            }
+            let position = boards::next_position(conn);
+            boards::insert_board(conn, slug, arg(2), args.get(3).map_or("", String::as_str), position);
+            println!("Board '{slug}' created.");
+        }
+        "rename" => update(conn, slug(), "name", arg(2).into()),
+        "describe" => update(conn, slug(), "description", arg(2).into()),
+        "move" => {
+            let position: i32 = arg(2).parse().unwrap_or_else(|_| usage_error("Position must be a number"));
+            update(conn, slug(), "position", position.into())
+        }
+        "hide" => update(conn, slug(), "hidden", true.into()),
+        "show" => update(conn, slug(), "hidden", false.into()),
+        "lock" => update(conn, slug(), "locked", true.into()),
+        "unlock" => update(conn, slug(), "locked", false.into()),
+        "limits" => {
+            let slug = slug();
+            let secs = |i: usize| -> i32 {
+                arg(i)
+                    .parse()
+                    .ok()
+                    .filter(|n| *n >= 0)
+                    .unwrap_or_else(|| usage_error("Limits must be whole numbers of seconds"))
+            };
+            let (post, thread, duplicate) = (secs(2), secs(3), secs(4));
+            require_board(conn, slug);
+            boards::set_board_field(conn, slug, "post_interval", post);
+            boards::set_board_field(conn, slug, "thread_interval", thread);
+            boards::set_board_field(conn, slug, "duplicate_window", duplicate);
+            println!("Board '{slug}' updated.");
+        }
+        "captcha" => {
+            let slug = slug();
+            let mode = CaptchaMode::parse(arg(2)).unwrap_or_else(|| usage_error("CAPTCHA mode must be off, threads or all"));
+            let kind = args.get(3).map(|k| {
+                CaptchaKind::parse(k).unwrap_or_else(|| usage_error("CAPTCHA kind must be image, pow or either"))
+            });
+            require_board(conn, slug);
+            boards::set_board_field(conn, slug, "captcha", mode.as_str());
+            if let Some(kind) = kind {
+                boards::set_board_field(conn, slug, "captcha_kind", kind.as_str());
+            }
+            println!("Board '{slug}' updated.");
+        }
+        "pow" => {
+            let slug = slug();
+            let bits: i32 = arg(2)
+                .parse()
+                .ok()
+                .filter(|b| (1..=POW_MAX_TOTAL_BITS as i32).contains(b))
+                .unwrap_or_else(|| usage_error(&format!("Difficulty must be 1 to {POW_MAX_TOTAL_BITS} bits")));
+            update(conn, slug, "pow_bits", bits.into())
+        }
+        "size" => {
+            let slug = slug();
+            let count = |i: usize| -> i32 {
+                arg(i)
+                    .parse()
+                    .ok()
+                    .filter(|n| *n >= 0)
+                    .unwrap_or_else(|| usage_error("Bump limit, max threads and cyclical replies must be whole numbers"))
+            };
+            let (bump_limit, max_threads) = (count(2), count(3));
+            let cyclical_replies = (args.len() > 4).then(|| count(4));
+            require_board(conn, slug);
+            boards::set_board_field(conn, slug, "bump_limit", bump_limit);
+            boards::set_board_field(conn, slug, "max_threads", max_threads);
+            if let Some(cyclical_replies) = cyclical_replies {
+                boards::set_board_field(conn, slug, "cyclical_replies", cyclical_replies);
+            }
+            println!("Board '{slug}' updated.");
+        }
+        "archive" => {
+            let slug = slug();
+            let on = match arg(2) {
+                "on" => true,
+                "off" => false,
+                _ => usage_error("Archive must be on or off"),
+            };
+            let days = args.get(3).map(|d| -> i32 {
+                d.parse()
+                    .ok()
+                    .filter(|n| *n >= 0)
+                    .unwrap_or_else(|| usage_error("Archive days must be a whole number"))
+            });
+            require_board(conn, slug);
+            boards::set_board_field(conn, slug, "archive", on);
+            if let Some(days) = days {
+                boards::set_board_field(conn, slug, "archive_days", days);
+            }
+            println!("Board '{slug}' updated.");
+        }
+        "delete" => {
+            let slug = slug();
+            require_board(conn, slug);
+            let files = boards::delete_board(conn, slug);
+            for file in &files {
+                let _ = std::fs::remove_file(format!("uploads/{file}"));
+            }
+            println!("Board '{slug}' deleted ({} files removed).", files.len());
+        }
+        other => usage_error(&format!("Unknown board command '{other}'")),
+    }
+
+    // Every command that failed has exited by now.
+    let action = match arg(0) {
+        "list" => return,
+        "add" => "board_add",
+        "delete" => "board_delete",
+        _ => "board_edit",
+    };
+    modlog::record(conn, Actor::Console, NewEntry {
+        action,
+        board: Some(arg(1)),
+        target: None,
+        details: format!("Ran `chess board {}`", args.join(" ")),
+        snapshot: None,
+    });
+}
+
+fn require_board(conn: &mut PooledConn, slug: &str) {
+    if !boards::get_boards(conn).unwrap().iter().any(|b| b.slug == slug) {
+        eprintln!("No board '{slug}'.");
+        std::process::exit(1);
+    }
+}
+
+fn update(conn: &mut PooledConn, slug: &str, column: &str, value: Value) {
+    require_board(conn, slug);
+    boards::set_board_field(conn, slug, column, value);
+    println!("Board '{slug}' updated.");
+}
+
+fn mod_command(conn: &mut PooledConn, args: &[String]) {
+    let arg = |i: usize| -> &str {
+        args.get(i).map(String::as_str).unwrap_or_else(|| usage_error("Missing argument"))
+    };
+    let role = || Role::parse(arg(2)).unwrap_or_else(|| usage_error(&format!("Unknown role '{}'", arg(2))));
+
+    match arg(0) {
+        "list" => {
+            for m in auth::get_moderators(conn) {
+                println!("{:<20} {:<10} {}", m.username, m.role.as_str(), m.boards.join(" "));
+            }
+        }
+        "add" => {
+            let username = arg(1);
+            if username.is_empty() || username.len() > 64 {
+                usage_error("Username must be 1-64 characters");
+            }
+            let role = role();
+            let board_list = board_args(conn, role, &args[3..]);
+            if auth::get_moderator_by_name(conn, username).is_some() {
+                eprintln!("Moderator '{username}' already exists.");
+                std::process::exit(1);
+            }
+            let password = read_password();
+            auth::insert_moderator(conn, username, &password, role, &board_list);
+            println!("Moderator '{username}' created.");
+        }
+        "passwd" => {
+            let m = require_moderator(conn, arg(1));
+            let password = read_password();
+            auth::set_password(conn, m.id, &password);
+            println!("Password for '{}' changed. Their sessions have been logged out.", m.username);
+        }
+        "role" => {
+            let m = require_moderator(conn, arg(1));
+            let role = role();
+            let board_list = board_args(conn, role, &args[3..]);
+            auth::set_role(conn, m.id, role);
+            auth::set_moderator_boards(conn, m.id, &board_list);
+            println!("Moderator '{}' updated.", m.username);
+        }
+        "delete" => {
+            let m = require_moderator(conn, arg(1));
+            auth::delete_moderator(conn, m.id);
+            println!("Moderator '{}' deleted.", m.username);
+        }
+        other => usage_error(&format!("Unknown mod command '{other}'")),
+    }
+}
+
+// The boards a janitor gets. Other roles act on every board and take none.
+fn board_args(conn: &mut PooledConn, role: Role, args: &[String]) -> Vec<String> {
+    if role != Role::Janitor {
+        if !args.is_empty() {
+            usage_error("Only janitors are given boards; admins and global mods act on all of them");
+        }
+        return Vec::new();
+    }
+    if args.is_empty() {
+        usage_error("A janitor needs at least one board");
+    }
+    for slug in args {
+        require_board(conn, slug);
+    }
+    args.to_vec()
+}
+
+fn require_moderator(conn: &mut PooledConn, username: &str) -> auth::Moderator {
+    auth::get_moderator_by_name(conn, username).unwrap_or_else(|| {
+        eprintln!("No moderator '{username}'.");
+        std::process::exit(1);
+    })
+}
+
+fn read_password() -> String {
+    print!("Password: ");
+    std::io::stdout().flush().unwrap();
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password).unwrap();
+    let password = password.trim_end_matches(['\r', '\n']).to_string();
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        eprintln!("Password must be at least {MIN_PASSWORD_LEN} characters.");
+        std::process::exit(1);
+    }
+    password
+}
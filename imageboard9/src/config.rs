@@ -0,0 +1,92 @@
+use crate::ip;
+use ipnet::IpNet;
+use std::env;
+use std::str::FromStr;
+
+pub fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    env::var(key).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpStorage {
+    // Keep the address (until the retention purge) as well as its hash.
+    Raw,
+    // Only ever keep the hash.
+    Hashed,
+}
+
+impl FromStr for IpStorage {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<IpStorage, ()> {
+        match s {
+            "raw" => Ok(IpStorage::Raw),
+            "hashed" => Ok(IpStorage::Hashed),
+            _ => Err(()),
+        }
+    }
+}
+
+// Runtime settings read from the environment (or .env) at startup.
+pub struct Config {
+    // TRIPCODE_SECRET: key for ## secure tripcodes. Keep it private and
+    // never change it, or every secure tripcode changes with it.
+    pub tripcode_secret: String,
+    // SESSION_SECRET: key that signs moderator session cookies.
+    pub session_secret: String,
+    // SESSION_TTL_HOURS: how long a moderator stays logged in (default 12).
+    pub session_ttl_hours: i64,
+    // SECURE_COOKIES: set to true when the site is served over HTTPS.
+    pub secure_cookies: bool,
+    // TRUSTED_PROXIES: comma-separated addresses or ranges of reverse proxies
+    // whose X-Forwarded-For header is believed, e.g. "127.0.0.1,10.0.0.0/8".
+    // Empty (the default) means the TCP peer is the client.
+    pub trusted_proxies: Vec<IpNet>,
+    // IP_STORAGE: "raw" (default) or "hashed". Either way a salted hash of
+    // the address is stored, which is what bans match against.
+    pub ip_storage: IpStorage,
+    // IP_RETENTION_DAYS: raw addresses, and the salts behind the hashes, are
+    // purged after this many days (default 30). 0 keeps them forever.
+    pub ip_retention_days: i64,
+    // IP_SALT_ROTATE_DAYS: a new hashing salt is started this often (default 7).
+    pub ip_salt_rotate_days: i64,
+    // PUBLIC_MOD_LOG: set to true to show the moderation log at /mod-log,
+    // without moderator names or deleted content.
+    pub public_mod_log: bool,
+    // SELF_DELETE_MINUTES: how long after posting a poster can delete their
+    // own post with its deletion password (default 60). 0 turns it off.
+    pub self_delete_minutes: i64,
+}
+
+impl Config {
+    pub fn from_env() -> Config {
+        let tripcode_secret = env::var("TRIPCODE_SECRET").unwrap_or_else(|_| {
+            eprintln!("WARNING: TRIPCODE_SECRET is not set. Secure tripcodes will change on every restart.");
+            uuid::Uuid::new_v4().to_string()
+        });
+        let session_secret = env::var("SESSION_SECRET").unwrap_or_else(|_| {
+            eprintln!("WARNING: SESSION_SECRET is not set. Moderators will be logged out on every restart.");
+            uuid::Uuid::new_v4().to_string()
+        });
+        Config {
+            tripcode_secret,
+            session_secret,
+            session_ttl_hours: env_or("SESSION_TTL_HOURS", 12),
+            secure_cookies: env_or("SECURE_COOKIES", false),
+            trusted_proxies: trusted_proxies(),
+            ip_storage: env_or("IP_STORAGE", IpStorage::Raw),
+            ip_retention_days: env_or("IP_RETENTION_DAYS", 30),
+            ip_salt_rotate_days: env_or("IP_SALT_ROTATE_DAYS", 7).max(1),
+            public_mod_log: env_or("PUBLIC_MOD_LOG", false),
+            self_delete_minutes: env_or("SELF_DELETE_MINUTES", 60).max(0),
+        }
+    }
+}
+
+fn trusted_proxies() -> Vec<IpNet> {
+    let list = env::var("TRUSTED_PROXIES").unwrap_or_default();
+    list.split(',')
+        .filter(|s| !s.trim().is_empty())
+        .map(|s| ip::parse_range(s).unwrap_or_else(|| panic!("TRUSTED_PROXIES: '{}' is not an address or range", s.trim())))
+        .collect()
+}
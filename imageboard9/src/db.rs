@@ -0,0 +1,121 @@
+// ===== Pool Settings (override in .env) =====
+// DB_POOL_MIN              connections kept open even when idle
+// DB_POOL_MAX              most connections the pool will ever open
+// DB_ACQUIRE_TIMEOUT_SECS  how long a request waits for a free connection
+// DB_CONNECT_TIMEOUT_SECS  TCP connect timeout for new connections
+// DB_QUERY_TIMEOUT_SECS    read/write timeout on a connection's socket
+const DEFAULT_POOL_MIN: usize = 2;
+const DEFAULT_POOL_MAX: usize = 16;
+const DEFAULT_ACQUIRE_TIMEOUT_SECS: u64 = 5;
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_QUERY_TIMEOUT_SECS: u64 = 30;
+// ===== End Pool Settings =====
+
+use crate::config::env_or;
+use crate::templates::ErrorPage;
+use askama::Template;
+use axum::http::StatusCode;
+use axum::response::{Html, IntoResponse, Response};
+use mysql::{DriverError, Opts, OptsBuilder, Pool, PoolConstraints, PoolOpts, PooledConn};
+use std::env;
+use std::time::Duration;
+
+// Why a database call didn't happen or didn't finish.
+#[derive(Debug)]
+pub enum DbError {
+    // Every connection stayed busy for DB_ACQUIRE_TIMEOUT_SECS.
+    Busy,
+    // No connection could be opened, or the query itself failed.
+    Failed,
+}
+
+impl std::fmt::Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DbError::Busy => write!(f, "timed out waiting for a database connection"),
+            DbError::Failed => write!(f, "database error"),
+        }
+    }
+}
+
+// The error page: 503 when the pool is busy, so clients can retry, and 500
+// otherwise.
+impl IntoResponse for DbError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            DbError::Busy => (StatusCode::SERVICE_UNAVAILABLE, "The site is busy. Please try again in a moment."),
+            DbError::Failed => (StatusCode::INTERNAL_SERVER_ERROR, "Something went wrong. Please try again."),
+        };
+        let page = ErrorPage { message: message.to_string(), back_url: "/".to_string() };
+        (status, Html(page.render().unwrap())).into_response()
+    }
+}
+
+// So that `?` works in helpers that return the page to show instead.
+impl From<DbError> for Response {
+    fn from(e: DbError) -> Response {
+        e.into_response()
+    }
+}
+
+// A MySQL connection pool. The mysql crate is blocking, so queries go through
+// `run`, which checks out a connection on tokio's blocking thread pool instead
+// of tying up an async worker.
+#[derive(Clone)]
+pub struct Db {
+    pool: Pool,
+    acquire_timeout: Duration,
+}
+
+impl Db {
+    pub fn connect() -> Db {
+        let db_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set in .env or environment");
+        let opts = Opts::from_url(&db_url).expect("DATABASE_URL is not a valid MySQL URL");
+
+        let min = env_or("DB_POOL_MIN", DEFAULT_POOL_MIN);
+        let max = env_or("DB_POOL_MAX", DEFAULT_POOL_MAX).max(1);
+        let constraints = PoolConstraints::new(min.min(max), max).unwrap();
+        let connect_timeout = Duration::from_secs(env_or("DB_CONNECT_TIMEOUT_SECS", DEFAULT_CONNECT_TIMEOUT_SECS));
+        let query_timeout = Duration::from_secs(env_or("DB_QUERY_TIMEOUT_SECS", DEFAULT_QUERY_TIMEOUT_SECS));
+
+        let opts = OptsBuilder::from_opts(opts)
+            .pool_opts(PoolOpts::default().with_constraints(constraints))
+            .tcp_connect_timeout(Some(connect_timeout))
+            .read_timeout(Some(query_timeout))
+            .write_timeout(Some(query_timeout));
+        let pool = Pool::new(opts).expect("Failed to connect to MariaDB/MySQL");
+
+        Db {
+            pool,
+            acquire_timeout: Duration::from_secs(env_or("DB_ACQUIRE_TIMEOUT_SECS", DEFAULT_ACQUIRE_TIMEOUT_SECS)),
+        }
+    }
+
+    // Blocking checkout, waiting at most DB_ACQUIRE_TIMEOUT_SECS.
+    pub fn conn(&self) -> Result<PooledConn, DbError> {
+        self.pool.try_get_conn(self.acquire_timeout).map_err(|e| {
+            eprintln!("Failed to get DB connection: {e}");
+            match e {
+                mysql::Error::DriverError(DriverError::Timeout) => DbError::Busy,
+                _ => DbError::Failed,
+            }
+        })
+    }
+
+    // Run `f` with a pooled connection on the blocking thread pool. A query
+    // that panics comes back as DbError::Failed rather than taking the
+    // request down with it.
+    pub async fn run<T, F>(&self, f: F) -> Result<T, DbError>
+    where
+        F: FnOnce(&mut PooledConn) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let db = self.clone();
+        tokio::task::spawn_blocking(move || db.conn().map(|mut conn| f(&mut conn)))
+            .await
+            .unwrap_or_else(|e| {
+                eprintln!("Database task failed: {e}");
+                Err(DbError::Failed)
+            })
+    }
+}
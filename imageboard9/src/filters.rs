@@ -0,0 +1,321 @@
+// ===== Filter Settings =====
+// Compiled size limit for a regex rule, so one rule can't make posting slow.
+pub const MAX_FILTER_REGEX_SIZE: usize = 256 * 1024;
+pub const MAX_FILTER_PATTERN_LEN: usize = 500;
+// ===== End Filter Settings =====
+
+use mysql::*;
+use mysql::prelude::*;
+use regex::{Regex, RegexBuilder};
+
+// Word filters and spam rules, run over a post's subject and message before
+// it is saved. A rule matches literal text (ignoring case) or a regex, on one
+// board or all of them, and says what happens to a post it matches.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterAction {
+    // Swap the matched text for this and let the post through.
+    Replace(String),
+    // Refuse the post, showing this message.
+    Reject(String),
+    // Pretend to accept the post but throw it away.
+    Drop,
+    // Refuse the post and ban the poster for this many hours (0 for good),
+    // giving `reason`.
+    Ban { reason: String, hours: i64 },
+}
+
+impl FilterAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FilterAction::Replace(_) => "replace",
+            FilterAction::Reject(_) => "reject",
+            FilterAction::Drop => "drop",
+            FilterAction::Ban { .. } => "ban",
+        }
+    }
+
+    // Build an action from the admin form or a table row: the action name,
+    // its text (replacement, message or ban reason) and a ban length.
+    pub fn parse(name: &str, text: String, hours: i64) -> Option<FilterAction> {
+        match name {
+            "replace" => Some(FilterAction::Replace(text)),
+            "reject" => Some(FilterAction::Reject(text)),
+            "drop" => Some(FilterAction::Drop),
+            "ban" => Some(FilterAction::Ban { reason: text, hours: hours.max(0) }),
+            _ => None,
+        }
+    }
+
+    pub fn text(&self) -> Option<&str> {
+        match self {
+            FilterAction::Replace(text) | FilterAction::Reject(text) => Some(text),
+            FilterAction::Ban { reason, .. } => Some(reason),
+            FilterAction::Drop => None,
+        }
+    }
+
+    // Which action wins when a post matches several rules.
+    fn severity(&self) -> u8 {
+        match self {
+            FilterAction::Replace(_) => 0,
+            FilterAction::Reject(_) => 1,
+            FilterAction::Drop => 2,
+            FilterAction::Ban { .. } => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Filter {
+    pub id: i64,
+    pub pattern: String,
+    pub is_regex: bool,
+    // None for a rule on every board.
+    pub board: Option<String>,
+    pub action: FilterAction,
+    pub hits: i64,
+    pub created: i64,
+    // Who added it, if they still have an account.
+    pub moderator: Option<String>,
+}
+
+// The regex a rule matches with; literal rules are escaped and match
+// without regard to case.
+pub fn compile(pattern: &str, is_regex: bool) -> Result<Regex, String> {
+    if pattern.is_empty() || pattern.len() > MAX_FILTER_PATTERN_LEN {
+        return Err(format!("A pattern must be 1 to {MAX_FILTER_PATTERN_LEN} characters."));
+    }
+    let source = if is_regex { pattern.to_string() } else { regex::escape(pattern) };
+    RegexBuilder::new(&source)
+        .case_insensitive(!is_regex)
+        .size_limit(MAX_FILTER_REGEX_SIZE)
+        .build()
+        .map_err(|e| format!("Bad regex: {e}"))
+}
+
+// What happens to a post after the filters have run.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Allow,
+    Reject(String),
+    Drop,
+    // Ban the poster: on the rule's board, or everywhere for a rule on
+    // every board.
+    Ban { reason: String, hours: i64, board: Option<String> },
+}
+
+const FILTER_COLUMNS: &str =
+    "f.id, f.pattern, f.is_regex, f.board, f.action, f.action_text, f.ban_hours, f.hits, f.created, m.username";
+
+fn filter_from_row(mut row: Row) -> Option<Filter> {
+    let name: String = row.take("action").unwrap();
+    let text: Option<String> = row.take("action_text").unwrap();
+    let hours: i64 = row.take("ban_hours").unwrap();
+    Some(Filter {
+        id: row.take("id").unwrap(),
+        pattern: row.take("pattern").unwrap(),
+        is_regex: row.take("is_regex").unwrap(),
+        board: row.take("board").unwrap(),
+        action: FilterAction::parse(&name, text.unwrap_or_default(), hours)?,
+        hits: row.take("hits").unwrap(),
+        created: row.take("created").unwrap(),
+        moderator: row.take("username").unwrap(),
+    })
+}
+
+// Every rule, oldest first.
+pub fn get_filters(db: &mut PooledConn) -> Vec<Filter> {
+    db.query_map(
+        format!("SELECT {FILTER_COLUMNS} FROM filters f LEFT JOIN moderators m ON f.moderator_id = m.id ORDER BY f.id"),
+        filter_from_row,
+    ).unwrap().into_iter().flatten().collect()
+}
+
+pub fn get_filter(db: &mut PooledConn, id: i64) -> Option<Filter> {
+    db.exec_map(
+        format!("SELECT {FILTER_COLUMNS} FROM filters f LEFT JOIN moderators m ON f.moderator_id = m.id WHERE f.id = :id"),
+        params! { "id" => id },
+        filter_from_row,
+    ).unwrap().into_iter().flatten().next()
+}
+
+pub fn insert_filter(
+    db: &mut PooledConn,
+    pattern: &str,
+    is_regex: bool,
+    board: Option<&str>,
+    action: &FilterAction,
+    moderator_id: i64,
+) -> i64 {
+    let hours = match action {
+        FilterAction::Ban { hours, .. } => *hours,
+        _ => 0,
+    };
+    db.exec_drop(
+        "INSERT INTO filters (pattern, is_regex, board, action, action_text, ban_hours, created, moderator_id)
+         VALUES (:pattern, :is_regex, :board, :action, :text, :hours, :now, :moderator_id)",
+        params! {
+            "pattern" => pattern,
+            "is_regex" => is_regex,
+            "board" => board,
+            "action" => action.name(),
+            "text" => action.text(),
+            "hours" => hours,
+            "now" => chrono::Utc::now().timestamp(),
+            "moderator_id" => moderator_id,
+        },
+    ).unwrap();
+    db.last_insert_id() as i64
+}
+
+// A one-line summary of a rule, for the moderation log.
+pub fn describe(pattern: &str, is_regex: bool, action: &FilterAction) -> String {
+    let kind = if is_regex { "regex" } else { "text" };
+    let action = match action {
+        FilterAction::Replace(with) => format!("replace with \"{with}\""),
+        FilterAction::Reject(message) => format!("reject (\"{message}\")"),
+        FilterAction::Drop => "drop".to_string(),
+        FilterAction::Ban { reason, hours: 0 } => format!("ban for good (\"{reason}\")"),
+        FilterAction::Ban { reason, hours } => format!("ban for {hours} hours (\"{reason}\")"),
+    };
+    format!("{kind} \"{pattern}\" → {action}")
+}
+
+pub fn delete_filter(db: &mut PooledConn, id: i64) {
+    db.exec_drop("DELETE FROM filters WHERE id = :id", params! { "id" => id }).unwrap();
+}
+
+// Run the rules for `board` over `texts` (subject, message), applying
+// replacements in place. Every rule that matches gets a hit; the most
+// severe action among them decides the post's fate.
+pub fn check(db: &mut PooledConn, board: &str, texts: &mut [&mut String]) -> Verdict {
+    let (verdict, hit) = apply(&get_filters(db), board, texts);
+    if !hit.is_empty() {
+        db.exec_batch(
+            "UPDATE filters SET hits = hits + 1 WHERE id = :id",
+            hit.iter().map(|id| params! { "id" => id }),
+        ).unwrap();
+    }
+    verdict
+}
+
+// `check` without the database: the verdict of `filters` on `texts`, and the
+// ids of the rules that matched.
+fn apply(filters: &[Filter], board: &str, texts: &mut [&mut String]) -> (Verdict, Vec<i64>) {
+    let mut verdict = &FilterAction::Replace(String::new());
+    let mut verdict_board = None;
+    let mut hit = Vec::new();
+    for filter in filters {
+        if filter.board.as_deref().is_some_and(|b| b != board) {
+            continue;
+        }
+        // Rules are checked when they are added, so this only skips one that
+        // a newer regex version no longer accepts.
+        let Ok(re) = compile(&filter.pattern, filter.is_regex) else {
+            continue;
+        };
+        if !texts.iter().any(|t| re.is_match(t)) {
+            continue;
+        }
+        hit.push(filter.id);
+        if let FilterAction::Replace(with) = &filter.action {
+            for text in texts.iter_mut() {
+                **text = re.replace_all(text, regex::NoExpand(with)).into_owned();
+            }
+        }
+        if filter.action.severity() > verdict.severity() {
+            verdict = &filter.action;
+            verdict_board = filter.board.clone();
+        }
+    }
+    let verdict = match verdict.clone() {
+        FilterAction::Replace(_) => Verdict::Allow,
+        FilterAction::Reject(message) => Verdict::Reject(message),
+        FilterAction::Drop => Verdict::Drop,
+        FilterAction::Ban { reason, hours } => Verdict::Ban { reason, hours, board: verdict_board },
+    };
+    (verdict, hit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(id: i64, pattern: &str, is_regex: bool, board: Option<&str>, action: FilterAction) -> Filter {
+        Filter {
+            id,
+            pattern: pattern.to_string(),
+            is_regex,
+            board: board.map(str::to_string),
+            action,
+            hits: 0,
+            created: 0,
+            moderator: None,
+        }
+    }
+
+    fn run(filters: &[Filter], board: &str, subject: &str, message: &str) -> (Verdict, Vec<i64>, String, String) {
+        let (mut subject, mut message) = (subject.to_string(), message.to_string());
+        let (verdict, hit) = apply(filters, board, &mut [&mut subject, &mut message]);
+        (verdict, hit, subject, message)
+    }
+
+    #[test]
+    fn literal_rules_ignore_case_and_regex_syntax() {
+        let filters = [rule(1, "a.b", false, None, FilterAction::Reject("no".into()))];
+        assert_eq!(run(&filters, "chess", "", "xA.By").0, Verdict::Reject("no".into()));
+        assert_eq!(run(&filters, "chess", "", "axb").0, Verdict::Allow);
+    }
+
+    #[test]
+    fn regex_rules_are_regexes_and_keep_case() {
+        let filters = [rule(1, r"^buy \d+$", true, None, FilterAction::Drop)];
+        assert_eq!(run(&filters, "chess", "", "buy 100").0, Verdict::Drop);
+        assert_eq!(run(&filters, "chess", "", "BUY 100").0, Verdict::Allow);
+        assert_eq!(run(&filters, "chess", "", "buy now").0, Verdict::Allow);
+    }
+
+    #[test]
+    fn replacements_apply_to_every_text_literally() {
+        let filters = [
+            rule(1, "blunder", false, None, FilterAction::Replace("$1 brilliancy".into())),
+            rule(2, r"(\d+)\?\?", true, None, FilterAction::Replace("!!".into())),
+        ];
+        let (verdict, hit, subject, message) = run(&filters, "chess", "Blunder check", "12?? was a BLUNDER");
+        assert_eq!(verdict, Verdict::Allow);
+        assert_eq!(hit, vec![1, 2]);
+        assert_eq!(subject, "$1 brilliancy check");
+        assert_eq!(message, "!! was a $1 brilliancy");
+    }
+
+    #[test]
+    fn most_severe_action_wins() {
+        let ban = FilterAction::Ban { reason: "spam".into(), hours: 24 };
+        let filters = [
+            rule(1, "spam", false, None, FilterAction::Replace("ham".into())),
+            rule(2, "ham", false, None, FilterAction::Reject("no ham".into())),
+            rule(3, "link", false, Some("chess"), ban),
+            rule(4, "link", false, None, FilterAction::Drop),
+        ];
+        // Replace < Reject, and the replacement is what the next rule sees.
+        let (verdict, hit, _, message) = run(&filters, "chess", "", "spam");
+        assert_eq!(verdict, Verdict::Reject("no ham".into()));
+        assert_eq!(hit, vec![1, 2]);
+        assert_eq!(message, "ham");
+        // Reject < Drop < Ban, whatever order the rules come in.
+        let (verdict, hit, _, _) = run(&filters, "chess", "ham", "link");
+        assert_eq!(verdict, Verdict::Ban { reason: "spam".into(), hours: 24, board: Some("chess".into()) });
+        assert_eq!(hit, vec![2, 3, 4]);
+        // The ban rule is for /chess/ only, so elsewhere Drop wins.
+        let (verdict, hit, _, _) = run(&filters, "other", "ham", "link");
+        assert_eq!(verdict, Verdict::Drop);
+        assert_eq!(hit, vec![2, 4]);
+    }
+
+    #[test]
+    fn no_match_allows_and_hits_nothing() {
+        let filters = [rule(1, "spam", false, None, FilterAction::Drop)];
+        assert_eq!(run(&filters, "chess", "Opening", "1. e4"), (Verdict::Allow, vec![], "Opening".into(), "1. e4".into()));
+    }
+}
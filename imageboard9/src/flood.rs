@@ -0,0 +1,298 @@
+// ===== Flood Control Settings =====
+// How often clients whose limits have run out are forgotten.
+pub const PRUNE_INTERVAL_SECS: u64 = 300;
+// Moderator login attempts allowed per client address in LOGIN_WINDOW_SECS.
+pub const LOGIN_ATTEMPTS: usize = 5;
+pub const LOGIN_WINDOW_SECS: u64 = 900;
+// Self-deletion attempts allowed per client address in SELF_DELETE_WINDOW_SECS.
+pub const SELF_DELETE_ATTEMPTS: usize = 10;
+pub const SELF_DELETE_WINDOW_SECS: u64 = 600;
+// ===== End Flood Control Settings =====
+
+use crate::boards::{BoardDef, BoardRegistry};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Flood control is kept in memory, per client address and board. Each board
+// sets its own limits (see `chess board limits`); a limit of 0 turns it off.
+// A restart forgets everything, which only ever lets one extra post through.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PostKind {
+    Thread,
+    Reply,
+}
+
+#[derive(Default)]
+struct Recent {
+    last_post: Option<Instant>,
+    last_thread: Option<Instant>,
+    // Fingerprints of recent messages, with when they were posted.
+    messages: Vec<(u64, Instant)>,
+}
+
+#[derive(Default)]
+pub struct FloodControl {
+    recent: Mutex<HashMap<(IpAddr, String), Recent>>,
+    // When each client address tried to log in, within LOGIN_WINDOW_SECS.
+    logins: Mutex<HashMap<IpAddr, Vec<Instant>>>,
+    // When each client address tried to delete its own post, within
+    // SELF_DELETE_WINDOW_SECS.
+    deletions: Mutex<HashMap<IpAddr, Vec<Instant>>>,
+}
+
+// Messages that differ only in case or spacing count as the same.
+fn fingerprint(message: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for word in message.split_whitespace() {
+        word.to_lowercase().hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+// Record an attempt by `ip`, unless it has already made `max` of them in the
+// last `window`.
+fn attempt(attempts: &Mutex<HashMap<IpAddr, Vec<Instant>>>, ip: IpAddr, max: usize, window: Duration) -> Result<(), String> {
+    let now = Instant::now();
+    let mut attempts = attempts.lock().unwrap();
+    let times = attempts.entry(ip).or_default();
+    times.retain(|t| now.duration_since(*t) < window);
+    if times.len() >= max {
+        let left = (times[0] + window).saturating_duration_since(now);
+        return Err(format!("Too many attempts. Wait {} seconds.", left.as_secs() + 1));
+    }
+    times.push(now);
+    Ok(())
+}
+
+// Forget attempts older than `window`, and the clients left with none.
+fn prune_attempts(attempts: &Mutex<HashMap<IpAddr, Vec<Instant>>>, window: Duration) {
+    let now = Instant::now();
+    attempts.lock().unwrap().retain(|_, times| {
+        times.retain(|t| now.duration_since(*t) < window);
+        !times.is_empty()
+    });
+}
+
+fn secs(n: i32) -> Duration {
+    Duration::from_secs(n.max(0) as u64)
+}
+
+// Time left before `last + wait` has passed, if any.
+fn remaining(last: Option<Instant>, wait: Duration, now: Instant) -> Option<Duration> {
+    let left = (last? + wait).checked_duration_since(now)?;
+    (!left.is_zero()).then_some(left)
+}
+
+impl FloodControl {
+    // Whether `ip` has waited long enough to post on `board`. Checked before
+    // the form is read, so a flood is turned away without saving its uploads.
+    pub fn check_interval(&self, ip: IpAddr, board: &BoardDef, kind: PostKind) -> Result<(), String> {
+        let recent = self.recent.lock().unwrap();
+        match recent.get(&(ip, board.slug.clone())) {
+            Some(r) => interval_error(r, board, kind, Instant::now()),
+            None => Ok(()),
+        }
+    }
+
+    // The full check, including repeated messages. If the post is allowed it
+    // is recorded at once, so parallel requests can't all slip through.
+    pub fn check_and_record(&self, ip: IpAddr, board: &BoardDef, kind: PostKind, message: &str) -> Result<(), String> {
+        let now = Instant::now();
+        let duplicate_window = secs(board.duplicate_window);
+        let print = fingerprint(message);
+        let mut recent = self.recent.lock().unwrap();
+        let r = recent.entry((ip, board.slug.clone())).or_default();
+        interval_error(r, board, kind, now)?;
+        r.messages.retain(|(_, at)| now.duration_since(*at) < duplicate_window);
+        if r.messages.iter().any(|(p, _)| *p == print) {
+            return Err("You already posted that message. Say something new.".to_string());
+        }
+        r.last_post = Some(now);
+        if kind == PostKind::Thread {
+            r.last_thread = Some(now);
+        }
+        if !duplicate_window.is_zero() {
+            r.messages.push((print, now));
+        }
+        Ok(())
+    }
+
+    // Whether `ip` may try to log in, counting the attempt if so. Checked
+    // before the password, so guessing it is limited to LOGIN_ATTEMPTS tries
+    // per LOGIN_WINDOW_SECS.
+    pub fn check_login(&self, ip: IpAddr) -> Result<(), String> {
+        attempt(&self.logins, ip, LOGIN_ATTEMPTS, Duration::from_secs(LOGIN_WINDOW_SECS))
+    }
+
+    // A successful login starts `ip` over.
+    pub fn login_succeeded(&self, ip: IpAddr) {
+        self.logins.lock().unwrap().remove(&ip);
+    }
+
+    // Whether `ip` may try to delete its own post, counting the attempt if
+    // so. Every attempt counts, as each one costs a password hash.
+    pub fn check_self_delete(&self, ip: IpAddr) -> Result<(), String> {
+        attempt(&self.deletions, ip, SELF_DELETE_ATTEMPTS, Duration::from_secs(SELF_DELETE_WINDOW_SECS))
+    }
+
+    // Forget clients whose limits have all run out. `longest` is the longest
+    // limit on any board.
+    fn prune(&self, longest: Duration) {
+        let now = Instant::now();
+        self.recent.lock().unwrap().retain(|_, r| {
+            r.last_post.is_some_and(|t| now.duration_since(t) < longest)
+                || r.messages.iter().any(|(_, t)| now.duration_since(*t) < longest)
+        });
+        prune_attempts(&self.logins, Duration::from_secs(LOGIN_WINDOW_SECS));
+        prune_attempts(&self.deletions, Duration::from_secs(SELF_DELETE_WINDOW_SECS));
+    }
+}
+
+fn interval_error(r: &Recent, board: &BoardDef, kind: PostKind, now: Instant) -> Result<(), String> {
+    if kind == PostKind::Thread {
+        if let Some(left) = remaining(r.last_thread, secs(board.thread_interval), now) {
+            return Err(format!("You can start another thread in {} seconds.", left.as_secs() + 1));
+        }
+    }
+    if let Some(left) = remaining(r.last_post, secs(board.post_interval), now) {
+        return Err(format!("You are posting too fast. Wait {} seconds.", left.as_secs() + 1));
+    }
+    Ok(())
+}
+
+// Run `prune` in the background every PRUNE_INTERVAL_SECS.
+pub async fn prune_task(flood: Arc<FloodControl>, boards: Arc<BoardRegistry>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(PRUNE_INTERVAL_SECS));
+    loop {
+        interval.tick().await;
+        let longest = boards
+            .all()
+            .await
+            .iter()
+            .flat_map(|b| [b.post_interval, b.thread_interval, b.duplicate_window])
+            .max()
+            .unwrap_or(0);
+        flood.prune(secs(longest));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boards::{CaptchaKind, CaptchaMode};
+
+    fn board(slug: &str, post_interval: i32, thread_interval: i32, duplicate_window: i32) -> BoardDef {
+        BoardDef {
+            slug: slug.to_string(),
+            name: slug.to_string(),
+            desc: String::new(),
+            position: 0,
+            hidden: false,
+            locked: false,
+            post_interval,
+            thread_interval,
+            duplicate_window,
+            captcha: CaptchaMode::Off,
+            captcha_kind: CaptchaKind::Image,
+            pow_bits: 16,
+            bump_limit: 0,
+            max_threads: 0,
+            cyclical_replies: 0,
+            archive: true,
+            archive_days: 0,
+        }
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn post_interval_is_per_address_and_board() {
+        let flood = FloodControl::default();
+        let (chess, go) = (board("chess", 30, 0, 0), board("go", 30, 0, 0));
+        let a = ip("203.0.113.1");
+        assert!(flood.check_and_record(a, &chess, PostKind::Reply, "1. e4").is_ok());
+        assert!(flood.check_interval(a, &chess, PostKind::Reply).is_err());
+        assert!(flood.check_and_record(a, &chess, PostKind::Reply, "1. d4").is_err());
+        // Another address, or another board, has its own limits.
+        assert!(flood.check_and_record(ip("203.0.113.2"), &chess, PostKind::Reply, "1. e4").is_ok());
+        assert!(flood.check_and_record(a, &go, PostKind::Reply, "1. e4").is_ok());
+    }
+
+    #[test]
+    fn intervals_end_exactly_when_they_run_out() {
+        let chess = board("chess", 10, 60, 0);
+        let start = Instant::now();
+        let r = Recent { last_post: Some(start), last_thread: Some(start), messages: Vec::new() };
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        assert!(interval_error(&r, &chess, PostKind::Reply, at(9)).is_err());
+        assert!(interval_error(&r, &chess, PostKind::Reply, at(10)).is_ok());
+        assert!(interval_error(&r, &chess, PostKind::Thread, at(59)).is_err());
+        assert!(interval_error(&r, &chess, PostKind::Thread, at(60)).is_ok());
+    }
+
+    #[test]
+    fn thread_interval_only_limits_threads() {
+        let flood = FloodControl::default();
+        let chess = board("chess", 0, 600, 0);
+        let a = ip("203.0.113.1");
+        assert!(flood.check_and_record(a, &chess, PostKind::Thread, "Opening").is_ok());
+        assert!(flood.check_interval(a, &chess, PostKind::Thread).is_err());
+        assert!(flood.check_and_record(a, &chess, PostKind::Reply, "Reply").is_ok());
+    }
+
+    #[test]
+    fn zero_limits_are_off() {
+        let flood = FloodControl::default();
+        let chess = board("chess", 0, 0, 0);
+        let a = ip("203.0.113.1");
+        for _ in 0..3 {
+            assert!(flood.check_and_record(a, &chess, PostKind::Thread, "same").is_ok());
+        }
+    }
+
+    #[test]
+    fn repeated_messages_are_refused_in_the_window() {
+        let flood = FloodControl::default();
+        let (chess, go) = (board("chess", 0, 0, 600), board("go", 0, 0, 600));
+        let a = ip("203.0.113.1");
+        assert!(flood.check_and_record(a, &chess, PostKind::Reply, "Nf3 d5 c4").is_ok());
+        // Case and spacing don't make it new.
+        assert!(flood.check_and_record(a, &chess, PostKind::Reply, "  nf3\nD5   c4 ").is_err());
+        assert!(flood.check_and_record(a, &chess, PostKind::Reply, "Nf3 d5 g3").is_ok());
+        assert!(flood.check_and_record(ip("203.0.113.2"), &chess, PostKind::Reply, "Nf3 d5 c4").is_ok());
+        assert!(flood.check_and_record(a, &go, PostKind::Reply, "Nf3 d5 c4").is_ok());
+    }
+
+    #[test]
+    fn login_attempts_run_out_per_address() {
+        let flood = FloodControl::default();
+        let (a, b) = (ip("203.0.113.1"), ip("203.0.113.2"));
+        for _ in 0..LOGIN_ATTEMPTS {
+            assert!(flood.check_login(a).is_ok());
+        }
+        assert!(flood.check_login(a).is_err());
+        assert!(flood.check_login(b).is_ok());
+        // Self-deletion keeps a separate count.
+        assert!(flood.check_self_delete(a).is_ok());
+        flood.login_succeeded(a);
+        assert!(flood.check_login(a).is_ok());
+    }
+
+    #[test]
+    fn attempts_outside_the_window_are_forgotten() {
+        let attempts = Mutex::default();
+        let a = ip("203.0.113.1");
+        assert!(attempt(&attempts, a, 1, Duration::from_secs(60)).is_ok());
+        assert!(attempt(&attempts, a, 1, Duration::from_secs(60)).is_err());
+        // With no window every earlier attempt has already run out.
+        assert!(attempt(&attempts, a, 1, Duration::ZERO).is_ok());
+        assert!(attempt(&attempts, a, 1, Duration::ZERO).is_ok());
+    }
+}
@@ -1,60 +1,285 @@
-use crate::boards::{BOARDS, BoardDef};
 use axum::{
-    extract::{Multipart, Path},
+    extract::{multipart::{Field, MultipartError}, ConnectInfo, Form, Multipart, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
     response::{Html, Redirect, IntoResponse, Response},
 };
 use askama::Template;
 use uuid::Uuid;
-use crate::models;
-use crate::templates::{Board, ThreadView, ErrorPage};
-use crate::models::{THREADS_PER_PAGE, REPLIES_TO_SHOW};
+use crate::admin;
+use crate::archive::{self, ArchiveQuery, ARCHIVED_THREADS_PER_PAGE};
+use crate::auth;
+use crate::bans::{self, BanTarget};
+use crate::filters::{self, Verdict};
+use crate::boards::BoardDef;
+use crate::db::DbError;
+use crate::flood::PostKind;
+use crate::ip;
+use crate::iplog;
+use crate::markup::{self, PostLocation};
+use crate::media::{self, OP_THUMB_SIZE, REPLY_THUMB_SIZE};
+use crate::modlog::{self, Actor, LogFilter, NewEntry};
+use crate::models::{self, Media, Poster, StoredIp};
+use crate::pow::POW_MAX_TOTAL_BITS;
+use crate::tripcode::{self, MAX_NAME_LEN};
+use crate::templates::{format_time, ArchiveIndex, Banned, Board, PostChallenge, ReportPage, BoardSummary, ErrorPage, Landing, RecentItem, ThreadView};
+use crate::models::{RECENT_POSTS_ON_LANDING, REPLIES_TO_SHOW, REPLY_PREVIEW_LENGTH, THREADS_PER_PAGE, THREAD_PREVIEW_LENGTH};
+use crate::reports::{self, MAX_REPORT_COMMENT_LEN, REPORT_CATEGORIES};
+use crate::selfdelete::{self, MAX_PASSWORD_LEN};
+use crate::state::AppState;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use tokio::fs;
+use tokio::io::AsyncWriteExt;
 
 const MAX_MESSAGE_LEN: usize = 50_000;
+const MAX_EMAIL_LEN: usize = 128;
 const MAX_UPLOAD_SIZE: usize = 50 * 1024 * 1024; // 50MB
+// Request body limit: a max-size upload plus room for the text fields.
+pub const MAX_BODY_SIZE: usize = MAX_UPLOAD_SIZE + 1024 * 1024;
+const UPLOAD_TMP_DIR: &str = "upload_tmp";
+const SNIFF_LEN: usize = 8192;
 
-// Serve the static landing page
-pub async fn landing_page() -> Html<String> {
-    let html = fs::read_to_string("static/landing.html").await.unwrap_or_else(|_| {
-        "<h2>Landing page not found</h2>".to_string()
-    });
-    Html(html)
+pub fn error_page(message: impl Into<String>, back_url: String) -> Response {
+    Html(ErrorPage { message: message.into(), back_url }.render().unwrap()).into_response()
 }
 
-fn get_board(slug: &str) -> Option<&'static BoardDef> {
-    BOARDS.iter().find(|b| b.slug == slug)
+// The challenge for a post form, if the board wants one for it: an image
+// CAPTCHA, a proof-of-work, or both to choose from.
+fn issue_challenge(state: &AppState, board: &BoardDef, required: bool) -> PostChallenge {
+    if !required {
+        return PostChallenge::default();
+    }
+    let kind = board.captcha_kind;
+    PostChallenge {
+        captcha: kind.image().then(|| state.captcha.issue()),
+        pow: kind.pow().then(|| {
+            state.pow.issue(&state.config.session_secret, &board.slug, board.pow_bits.clamp(1, POW_MAX_TOTAL_BITS as i32) as u32)
+        }),
+    }
+}
+
+// Same, but logged-in moderators never get one.
+async fn challenge_for(state: &AppState, headers: &HeaderMap, board: &BoardDef, required: bool) -> PostChallenge {
+    if auth::current_moderator(state, headers).await.is_some() {
+        return PostChallenge::default();
+    }
+    issue_challenge(state, board, required)
+}
+
+// What a post form sent back for its challenge.
+#[derive(Default)]
+struct ChallengeAnswer {
+    captcha_token: String,
+    captcha: String,
+    pow_challenge: String,
+    pow_nonce: String,
+}
+
+// Check the answer sent with a post, when the board asks for one. Either
+// kind passes where the board offers both.
+async fn check_challenge(
+    state: &AppState,
+    headers: &HeaderMap,
+    board: &BoardDef,
+    required: bool,
+    answer: &ChallengeAnswer,
+) -> bool {
+    let kind = board.captcha_kind;
+    let secret = &state.config.session_secret;
+    !required
+        || (kind.image() && state.captcha.verify(&answer.captcha_token, &answer.captcha))
+        || (kind.pow() && state.pow.verify(secret, &board.slug, &answer.pow_challenge, &answer.pow_nonce))
+        || auth::current_moderator(state, headers).await.is_some()
+}
+
+pub async fn captcha_image(State(state): State<AppState>, Path(token): Path<String>) -> Response {
+    match state.captcha.image(&token) {
+        Some(png) => (
+            [(header::CONTENT_TYPE, "image/png"), (header::CACHE_CONTROL, "no-store")],
+            png,
+        ).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+// Ban the poster when a filter rule says to. The ban is on the address hash,
+// like a moderator's ban on a post whose address isn't kept.
+fn filter_ban(db: &mut mysql::PooledConn, verdict: &Verdict, ip: &StoredIp) {
+    if let Verdict::Ban { reason, hours, board } = verdict {
+        let expires = (*hours > 0).then(|| chrono::Utc::now().timestamp() + hours * 3600);
+        let target = BanTarget::Hash(ip.hash.clone(), ip.salt_id);
+        let ban_id = bans::insert_ban(db, &target, board.as_deref(), reason, expires, None);
+        let scope = board.as_deref().map_or("all boards".to_string(), |b| format!("/{b}/"));
+        let length = if *hours > 0 { format!("{hours} hours") } else { "good".to_string() };
+        modlog::record(db, Actor::Filter, NewEntry {
+            action: "ban",
+            board: board.as_deref(),
+            target: Some(ban_id),
+            details: format!("Ban #{ban_id} on {scope} for {length}: {reason}"),
+            snapshot: None,
+        });
+    }
+}
+
+// After the filters have run: count an accepted post, or clean up after a
+// refused one and say what to show instead. A dropped post looks accepted.
+async fn refused(
+    state: &AppState,
+    verdict: Verdict,
+    unused_media: Option<Media>,
+    ip: IpAddr,
+    board: &str,
+    back_url: String,
+) -> Option<Response> {
+    discard_media(unused_media).await;
+    match verdict {
+        Verdict::Allow => {
+            state.pow.record_post(board);
+            None
+        }
+        Verdict::Drop => None,
+        Verdict::Reject(message) => Some(error_page(message, back_url)),
+        Verdict::Ban { .. } => Some(check_ban(state, ip, board).await.err().unwrap_or_else(|| {
+            error_page("Your post was refused.", back_url)
+        })),
+    }
+}
+
+// Turned away by flood control: the error page, with 429 Too Many Requests.
+fn rate_limited(message: String, back_url: String) -> Response {
+    (StatusCode::TOO_MANY_REQUESTS, error_page(message, back_url)).into_response()
+}
+
+// Stop a banned client before anything is saved. The page says why and
+// for how long.
+async fn check_ban(state: &AppState, ip: IpAddr, board: &str) -> Result<(), Response> {
+    let board = board.to_string();
+    let Some(ban) = state.db.run(move |db| bans::find_ban(db, ip, &board)).await? else {
+        return Ok(());
+    };
+    let page = Banned {
+        ip: ip.to_string(),
+        created: format_time(ban.created),
+        expires: ban.expires.map_or_else(|| "never".to_string(), format_time),
+        ban,
+    };
+    Err((StatusCode::FORBIDDEN, Html(page.render().unwrap())).into_response())
+}
+
+// Build the poster from the name and email fields, or say what's wrong with
+// them. The deletion password is only checked here; it is hashed later.
+fn make_poster(state: &AppState, name: &str, email: &str, password: &str) -> Result<Poster, String> {
+    let (name, tripcode) = tripcode::parse_name(name, &state.config.tripcode_secret);
+    if name.chars().count() > MAX_NAME_LEN {
+        return Err(format!("Name is too long! (Max {MAX_NAME_LEN} characters)"));
+    }
+    let email = email.trim();
+    if email.chars().count() > MAX_EMAIL_LEN {
+        return Err(format!("Email is too long! (Max {MAX_EMAIL_LEN} characters)"));
+    }
+    if password.trim().chars().count() > MAX_PASSWORD_LEN {
+        return Err(format!("Password is too long! (Max {MAX_PASSWORD_LEN} characters)"));
+    }
+    Ok(Poster {
+        name,
+        tripcode,
+        email: (!email.is_empty()).then(|| email.to_string()),
+        password_hash: None,
+    })
+}
+
+// Landing page: every listed board with its stats, plus the newest posts site-wide
+pub async fn landing_page(State(state): State<AppState>) -> Response {
+    let boards: Vec<_> = state.boards.all().await.into_iter().filter(|b| !b.hidden).collect();
+    let slugs: Vec<String> = boards.iter().map(|b| b.slug.clone()).collect();
+    let (mut stats, recent) = match state.db.run(move |db| {
+        (models::get_board_stats(db), models::get_recent_posts(db, &slugs, RECENT_POSTS_ON_LANDING))
+    }).await {
+        Ok(v) => v,
+        Err(e) => return e.into_response(),
+    };
+
+    let boards = boards
+        .into_iter()
+        .map(|board| {
+            let stats = stats.remove(&board.slug).unwrap_or_default();
+            let last_post = stats.last_post.map_or_else(|| "never".to_string(), format_time);
+            BoardSummary { board, stats, last_post }
+        })
+        .collect();
+    let recent = recent
+        .into_iter()
+        .map(|post| RecentItem { when: format_time(post.created), post })
+        .collect();
+    Html(Landing { boards, recent }.render().unwrap()).into_response()
+}
+
+// A formatted message cut down for the board page.
+fn preview_html(message: &str, board: &str, locations: &HashMap<i64, PostLocation>, len: usize) -> String {
+    let (mut html, cut) = markup::truncate_html(&markup::render(message, board, locations), len);
+    if cut {
+        html.push('…');
+    }
+    html
 }
 
-pub async fn board_page(Path(board_slug): Path<String>) -> Response {
-    board_page_with_page(Path((board_slug, 0))).await
+pub async fn board_page(state: State<AppState>, headers: HeaderMap, Path(board_slug): Path<String>) -> Response {
+    board_page_with_page(state, headers, Path((board_slug, 0))).await
 }
 
-pub async fn board_page_with_page(Path((board_slug, page)): Path<(String, usize)>) -> Response {
-    let board = match get_board(&board_slug) {
+pub async fn board_page_with_page(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((board_slug, page)): Path<(String, usize)>,
+) -> Response {
+    let board = match state.boards.get(&board_slug).await {
         Some(b) => b,
         None => {
             return Html(format!("<h2>Board '{board_slug}' not found</h2>")).into_response();
         }
     };
-    let threads = models::get_threads_paged(&board.slug, THREADS_PER_PAGE, page);
-    let total_threads = models::get_total_thread_count(&board.slug);
-    let page_count = if total_threads == 0 {
-        1
-    } else {
-        (total_threads + THREADS_PER_PAGE - 1) / THREADS_PER_PAGE
-    };
+    let slug = board.slug.clone();
+    let (mut threads, total_threads, thread_reply_counts, mut last_replies, locations) = match state.db.run(move |db| {
+        let threads = models::get_threads_paged(db, &slug, THREADS_PER_PAGE, page);
+        let total_threads = models::get_total_thread_count(db, &slug);
 
-    let mut thread_reply_counts = std::collections::HashMap::new();
-    let mut last_replies = std::collections::HashMap::new();
+        let mut thread_reply_counts = HashMap::new();
+        let mut last_replies = HashMap::new();
 
-    for thread in &threads {
-        let count = models::get_post_count(thread.id);
-        thread_reply_counts.insert(thread.id, count);
+        for thread in &threads {
+            let count = models::get_post_count(db, thread.id);
+            thread_reply_counts.insert(thread.id, count);
 
-        let replies = models::get_last_n_replies(thread.id, REPLIES_TO_SHOW);
-        last_replies.insert(thread.id, replies);
+            let replies = models::get_last_n_replies(db, thread.id, REPLIES_TO_SHOW);
+            last_replies.insert(thread.id, replies);
+        }
+        let quoted: Vec<i64> = threads
+            .iter()
+            .map(|t| &t.message)
+            .chain(last_replies.values().flatten().map(|p: &models::Post| &p.message))
+            .flat_map(|m| markup::quoted_posts(m, &slug))
+            .map(|(_, id)| id)
+            .collect();
+        let locations = models::resolve_posts(db, &quoted);
+        (threads, total_threads, thread_reply_counts, last_replies, locations)
+    }).await {
+        Ok(v) => v,
+        Err(e) => return e.into_response(),
+    };
+    for thread in &mut threads {
+        thread.body_html = preview_html(&thread.message, &board.slug, &locations, THREAD_PREVIEW_LENGTH);
     }
+    for post in last_replies.values_mut().flatten() {
+        post.body_html = preview_html(&post.message, &board.slug, &locations, REPLY_PREVIEW_LENGTH);
+    }
+    let page_count = if total_threads == 0 {
+        1
+    } else {
+        total_threads.div_ceil(THREADS_PER_PAGE)
+    };
 
+    let challenge = challenge_for(&state, &headers, &board, board.captcha_for_threads()).await;
     let tmpl = Board {
         board: board.clone(),
         threads,
@@ -62,110 +287,326 @@ pub async fn board_page_with_page(Path((board_slug, page)): Path<(String, usize)
         last_replies,
         page,
         page_count,
+        challenge,
     };
     Html(tmpl.render().unwrap()).into_response()
 }
 
-// Save an uploaded file, check type and size
-async fn save_media(field: axum::extract::multipart::Field<'_>) -> Result<Option<String>, String> {
-    let content_type = field.content_type().map(|m| m.to_string()).unwrap_or_default();
-    let ext = match content_type.as_str() {
-        "image/jpeg" => "jpg",
-        "image/png" => "png",
-        "image/gif" => "gif",
-        "image/webp" => "webp",
-        "image/bmp" => "bmp",
-        "video/mp4" => "mp4",
-        _ => return Err("Unsupported file type. Allowed: jpg, png, gif, webp, bmp, mp4.".to_string()),
-    };
+fn ext_from_mime(mime: &str) -> Option<&'static str> {
+    match mime {
+        "image/jpeg" => Some("jpg"),
+        "image/png" => Some("png"),
+        "image/gif" => Some("gif"),
+        "image/webp" => Some("webp"),
+        "image/bmp" => Some("bmp"),
+        "video/mp4" => Some("mp4"),
+        _ => None,
+    }
+}
+
+// Removes a half-written upload unless it was kept, including when the
+// client disconnects and the handler future is dropped mid-stream.
+struct TempUpload {
+    path: String,
+    keep: bool,
+}
+
+impl Drop for TempUpload {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+// Remove an upload whose post was refused after the file was saved.
+async fn discard_media(media: Option<Media>) {
+    if let Some(media) = media {
+        for file in std::iter::once(media.file).chain(media.thumb) {
+            let _ = fs::remove_file(format!("uploads/{file}")).await;
+        }
+    }
+}
+
+// Why a post form couldn't be read: the body went over MAX_BODY_SIZE, or
+// was cut off or malformed.
+fn form_error(err: &MultipartError) -> &'static str {
+    if err.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        "File too large. Max allowed size: 50MB."
+    } else {
+        "Failed to read the form. Please try again."
+    }
+}
+
+// The error page for a post form that couldn't be read, with the status
+// axum gives the error (413 Payload Too Large for an oversized body).
+fn unreadable_form(err: MultipartError, back_url: String) -> Response {
+    (err.status(), error_page(format!("Upload error: {}", form_error(&err)), back_url)).into_response()
+}
+
+// Stream an uploaded file to a temp file, rejecting it as soon as it grows past
+// MAX_UPLOAD_SIZE. The type comes from the file's magic bytes, not the
+// client-sent content type. Only accepted files are moved into uploads/,
+// and images get a thumbnail.
+async fn save_media(mut field: Field<'_>, thumb_size: u32) -> Result<Option<Media>, String> {
     let uuid = Uuid::new_v4().to_string();
+    fs::create_dir_all(UPLOAD_TMP_DIR).await.map_err(|_| "Failed to save file.".to_string())?;
+    let mut tmp = TempUpload { path: format!("{UPLOAD_TMP_DIR}/{uuid}.part"), keep: false };
+    let mut file = fs::File::create(&tmp.path).await.map_err(|_| "Failed to save file.".to_string())?;
+
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    let mut size = 0;
+    while let Some(chunk) = field.chunk().await.map_err(|e| form_error(&e).to_string())? {
+        size += chunk.len();
+        if size > MAX_UPLOAD_SIZE {
+            return Err("File too large. Max allowed size: 50MB.".to_string());
+        }
+        if head.len() < SNIFF_LEN {
+            let n = (SNIFF_LEN - head.len()).min(chunk.len());
+            head.extend_from_slice(&chunk[..n]);
+        }
+        file.write_all(&chunk).await.map_err(|_| "Failed to save file.".to_string())?;
+    }
+    file.flush().await.map_err(|_| "Failed to save file.".to_string())?;
+    drop(file);
+    if size == 0 {
+        return Ok(None);
+    }
+
+    let ext = infer::get(&head)
+        .and_then(|kind| ext_from_mime(kind.mime_type()))
+        .ok_or_else(|| "Unsupported file type. Allowed: jpg, png, gif, webp, bmp, mp4.".to_string())?;
     let fname = format!("{}.{}", uuid, ext);
     let path = format!("uploads/{}", fname);
+    fs::create_dir_all("uploads").await.map_err(|_| "Failed to save file.".to_string())?;
+    fs::rename(&tmp.path, &path).await.map_err(|_| "Failed to save file.".to_string())?;
+    tmp.keep = true;
 
-    let data = field.bytes().await.map_err(|_| "Failed to read file data.".to_string())?;
-    if data.len() > MAX_UPLOAD_SIZE {
-        return Err("File too large. Max allowed size: 50MB.".to_string());
-    }
-    tokio::fs::create_dir_all("uploads").await.ok();
-    tokio::fs::write(&path, &data).await.map_err(|_| "Failed to save file.".to_string())?;
-    Ok(Some(fname))
+    let thumb = if ext == "mp4" {
+        None
+    } else {
+        tokio::task::spawn_blocking(move || media::make_thumbnail(&path, &uuid, thumb_size))
+            .await
+            .unwrap_or(None)
+    };
+    Ok(Some(Media {
+        file: fname,
+        thumb_width: thumb.as_ref().map_or(0, |t| t.width),
+        thumb_height: thumb.as_ref().map_or(0, |t| t.height),
+        thumb: thumb.map(|t| t.file),
+    }))
 }
 
-pub async fn new_thread(Path(board_slug): Path<String>, mut multipart: Multipart) -> Response {
-    let board = match get_board(&board_slug) {
+pub async fn new_thread(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(board_slug): Path<String>,
+    mut multipart: Multipart,
+) -> Response {
+    let board = match state.boards.get(&board_slug).await {
         Some(b) => b,
         None => {
             return Html(format!("<h2>Board '{board_slug}' not found</h2>")).into_response();
         }
     };
+    if board.locked {
+        return error_page("This board is locked. New threads and replies are disabled.", format!("/{}/", board.slug));
+    }
+    let ip = ip::client_ip(peer.ip(), &headers, &state.config.trusted_proxies);
+    if let Err(page) = check_ban(&state, ip, &board.slug).await {
+        return page;
+    }
+    if let Err(reason) = state.flood.check_interval(ip, &board, PostKind::Thread) {
+        return rate_limited(reason, format!("/{}/", board.slug));
+    }
 
+    let mut name = String::new();
+    let mut email = String::new();
+    let mut password = String::new();
     let mut subject = String::new();
     let mut message = String::new();
-    let mut media: Option<String> = None;
-
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        match field.name() {
-            Some("subject") => subject = field.text().await.unwrap_or_default(),
-            Some("message") => message = field.text().await.unwrap_or_default(),
-            Some("media") => {
-                if let Some(filename) = field.file_name() {
-                    if !filename.is_empty() {
-                        match save_media(field).await {
-                            Ok(opt) => media = opt,
-                            Err(reason) => {
-                                return Html(
-                                    ErrorPage {
-                                        message: format!("Upload error: {}", reason),
-                                        back_url: format!("/{}/", board.slug),
-                                    }
-                                    .render()
-                                    .unwrap(),
-                                ).into_response();
-                            }
-                        }
+    let mut challenge = ChallengeAnswer::default();
+    let mut media: Option<Media> = None;
+
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => {
+                discard_media(media).await;
+                return unreadable_form(e, format!("/{}/", board.slug));
+            }
+        };
+        let key = field.name().unwrap_or_default().to_string();
+        if key == "media" {
+            if field.file_name().is_some_and(|f| !f.is_empty()) {
+                match save_media(field, OP_THUMB_SIZE).await {
+                    Ok(opt) => media = opt,
+                    Err(reason) => {
+                        discard_media(media).await;
+                        return error_page(format!("Upload error: {}", reason), format!("/{}/", board.slug));
                     }
                 }
             }
+            continue;
+        }
+        let value = match field.text().await {
+            Ok(value) => value,
+            Err(e) => {
+                discard_media(media).await;
+                return unreadable_form(e, format!("/{}/", board.slug));
+            }
+        };
+        match key.as_str() {
+            "name" => name = value,
+            "email" => email = value,
+            "password" => password = value,
+            "subject" => subject = value,
+            "message" => message = value,
+            "captcha_token" => challenge.captcha_token = value,
+            "captcha" => challenge.captcha = value,
+            "pow_challenge" => challenge.pow_challenge = value,
+            "pow_nonce" => challenge.pow_nonce = value,
             _ => {}
         }
     }
     if subject.trim().is_empty() || message.trim().is_empty() {
-        return Html(
-            ErrorPage {
-                message: "Subject and message are required.".to_string(),
-                back_url: format!("/{}/", board.slug),
-            }
-            .render()
-            .unwrap(),
-        ).into_response();
+        discard_media(media).await;
+        return error_page("Subject and message are required.", format!("/{}/", board.slug));
     }
     if message.len() > MAX_MESSAGE_LEN {
-        return Html(
-            ErrorPage {
-                message: "Message is too long! (Max 50,000 bytes)".to_string(),
-                back_url: format!("/{}/", board.slug),
+        discard_media(media).await;
+        return error_page("Message is too long! (Max 50,000 bytes)", format!("/{}/", board.slug));
+    }
+    let mut poster = match make_poster(&state, &name, &email, &password) {
+        Ok(p) => p,
+        Err(reason) => {
+            discard_media(media).await;
+            return error_page(reason, format!("/{}/", board.slug));
+        }
+    };
+    if !check_challenge(&state, &headers, &board, board.captcha_for_threads(), &challenge).await {
+        discard_media(media).await;
+        return error_page("The anti-spam check failed or has expired. Please try again.", format!("/{}/", board.slug));
+    }
+    if let Err(reason) = state.flood.check_and_record(ip, &board, PostKind::Thread, &message) {
+        discard_media(media).await;
+        return rate_limited(reason, format!("/{}/", board.slug));
+    }
+    let slug = board.slug.clone();
+    let config = state.config.clone();
+    let def = board.clone();
+    let password = selfdelete::choose_password(&headers, &password);
+    let hashed = password.clone();
+    // A copy of the upload, to remove if no connection was free and so
+    // nothing was saved.
+    let saved = media.clone();
+    let (verdict, unused_media, pruned) = match state.db.run(move |db| {
+        let verdict = filters::check(db, &slug, &mut [&mut subject, &mut message]);
+        let ip = iplog::stored_ip(db, &config, ip);
+        if verdict != Verdict::Allow {
+            filter_ban(db, &verdict, &ip);
+            return (verdict, media, Vec::new());
+        }
+        poster.password_hash = Some(auth::hash_password(&hashed));
+        models::insert_thread(db, &slug, &poster, &subject, &message, media.as_ref(), &ip);
+        let pruned = models::prune_threads(db, &def);
+        (verdict, None, pruned)
+    }).await {
+        Ok(v) => v,
+        Err(e) => {
+            if matches!(e, DbError::Busy) {
+                discard_media(saved).await;
             }
-            .render()
-            .unwrap(),
-        ).into_response();
+            return e.into_response();
+        }
+    };
+    admin::remove_uploads(pruned).await;
+    if let Some(page) = refused(&state, verdict, unused_media, ip, &board.slug, format!("/{}/", board.slug)).await {
+        return page;
     }
-    models::insert_thread(&board.slug, &subject, &message, media.as_deref());
-    Redirect::to(&format!("/{}/", board.slug)).into_response()
+    let cookie = selfdelete::password_cookie(&state, &password);
+    ([(header::SET_COOKIE, cookie)], Redirect::to(&format!("/{}/", board.slug))).into_response()
+}
+
+pub async fn thread_view(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((board_slug, id)): Path<(String, i64)>,
+) -> Response {
+    show_thread(&state, &headers, board_slug, id, false).await
+}
+
+// An archived thread, read-only.
+pub async fn archived_thread(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((board_slug, id)): Path<(String, i64)>,
+) -> Response {
+    show_thread(&state, &headers, board_slug, id, true).await
 }
 
-pub async fn thread_view(Path((board_slug, id)): Path<(String, i64)>) -> Response {
-    let board = match get_board(&board_slug) {
+// A thread page. A thread asked for at the wrong one of its two addresses
+// (live or archived) is sent to the other.
+async fn show_thread(state: &AppState, headers: &HeaderMap, board_slug: String, id: i64, archived: bool) -> Response {
+    let board = match state.boards.get(&board_slug).await {
         Some(b) => b,
         None => {
             return Html(format!("<h2>Board '{board_slug}' not found</h2>")).into_response();
         }
     };
 
-    if let Some((thread, posts)) = models::get_thread(id) {
+    let found = match state.db.run(move |db| {
+        let (thread, posts) = models::get_thread(db, id)?;
+        let quoted: Vec<i64> = std::iter::once(&thread.message)
+            .chain(posts.iter().map(|p| &p.message))
+            .flat_map(|m| markup::quoted_posts(m, &thread.board))
+            .map(|(_, id)| id)
+            .collect();
+        let locations = models::resolve_posts(db, &quoted);
+        Some((thread, posts, locations))
+    }).await {
+        Ok(found) => found,
+        Err(e) => return e.into_response(),
+    };
+
+    if let Some((mut thread, mut posts, locations)) = found {
         if thread.board != board.slug {
             return Html("<h2>Thread not found in this board.</h2>".to_string()).into_response();
         }
-        let tmpl = ThreadView { thread, posts };
+        match (thread.archived.is_some(), archived) {
+            (true, false) => return Redirect::to(&format!("/{}/archive/{}", board.slug, id)).into_response(),
+            (false, true) => return Redirect::to(&format!("/{}/thread/{}", board.slug, id)).into_response(),
+            _ => {}
+        }
+        thread.body_html = markup::render(&thread.message, &thread.board, &locations);
+        for post in &mut posts {
+            post.body_html = markup::render(&post.message, &thread.board, &locations);
+        }
+        let moderator = auth::current_moderator(state, headers).await;
+        // No reply form, so no challenge, on a locked or archived thread.
+        let challenge = if moderator.is_some() || thread.locked || archived {
+            PostChallenge::default()
+        } else {
+            issue_challenge(state, &board, board.captcha_for_replies())
+        };
+        let moderator = moderator.filter(|m| m.can_act_on(&board.slug));
+        let window = state.config.self_delete_minutes;
+        let self_delete_after = if window > 0 && !archived {
+            chrono::Utc::now().timestamp() - window * 60
+        } else {
+            i64::MAX
+        };
+        let tmpl = ThreadView {
+            thread,
+            posts,
+            self_delete_after,
+            cyclical_replies: board.cyclical_replies,
+            can_ban: moderator.as_ref().is_some_and(|m| m.can_ban()),
+            moderator: moderator.is_some(),
+            challenge,
+        };
         Html(tmpl.render().unwrap()).into_response()
     } else {
         Html("<h2>Thread not found</h2>".to_string()).into_response()
@@ -173,64 +614,300 @@ pub async fn thread_view(Path((board_slug, id)): Path<(String, i64)>) -> Respons
 }
 
 pub async fn reply(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Path((board_slug, id)): Path<(String, i64)>,
-    mut multipart: Multipart
+    mut multipart: Multipart,
 ) -> Response {
-    let board = match get_board(&board_slug) {
+    let board = match state.boards.get(&board_slug).await {
         Some(b) => b,
         None => {
             return Html(format!("<h2>Board '{board_slug}' not found</h2>")).into_response();
         }
     };
+    if board.locked {
+        return error_page("This board is locked. New threads and replies are disabled.", format!("/{}/thread/{}", board.slug, id));
+    }
+    let ip = ip::client_ip(peer.ip(), &headers, &state.config.trusted_proxies);
+    if let Err(page) = check_ban(&state, ip, &board.slug).await {
+        return page;
+    }
+    if let Err(reason) = state.flood.check_interval(ip, &board, PostKind::Reply) {
+        return rate_limited(reason, format!("/{}/thread/{}", board.slug, id));
+    }
+    // Checked before the upload is saved, so a bad thread id leaves no file behind.
+    match state.db.run(move |db| models::get_thread_status(db, id)).await {
+        Ok(Some((slug, locked, archived))) if slug == board.slug => {
+            if archived {
+                return error_page("This thread is archived. No new replies can be posted.", format!("/{}/archive/{}", board.slug, id));
+            }
+            if locked {
+                return error_page("This thread is locked. No new replies can be posted.", format!("/{}/thread/{}", board.slug, id));
+            }
+        }
+        Ok(_) => return error_page("Thread not found in this board.", format!("/{}/", board.slug)),
+        Err(e) => return e.into_response(),
+    }
 
+    let mut name = String::new();
+    let mut email = String::new();
+    let mut password = String::new();
     let mut message = String::new();
-    let mut media: Option<String> = None;
-
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        match field.name() {
-            Some("message") => message = field.text().await.unwrap_or_default(),
-            Some("media") => {
-                if let Some(filename) = field.file_name() {
-                    if !filename.is_empty() {
-                        match save_media(field).await {
-                            Ok(opt) => media = opt,
-                            Err(reason) => {
-                                return Html(
-                                    ErrorPage {
-                                        message: format!("Upload error: {}", reason),
-                                        back_url: format!("/{}/thread/{}", board.slug, id),
-                                    }
-                                    .render()
-                                    .unwrap(),
-                                ).into_response();
-                            }
-                        }
+    let mut challenge = ChallengeAnswer::default();
+    let mut media: Option<Media> = None;
+
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => {
+                discard_media(media).await;
+                return unreadable_form(e, format!("/{}/thread/{}", board.slug, id));
+            }
+        };
+        let key = field.name().unwrap_or_default().to_string();
+        if key == "media" {
+            if field.file_name().is_some_and(|f| !f.is_empty()) {
+                match save_media(field, REPLY_THUMB_SIZE).await {
+                    Ok(opt) => media = opt,
+                    Err(reason) => {
+                        discard_media(media).await;
+                        return error_page(format!("Upload error: {}", reason), format!("/{}/thread/{}", board.slug, id));
                     }
                 }
             }
+            continue;
+        }
+        let value = match field.text().await {
+            Ok(value) => value,
+            Err(e) => {
+                discard_media(media).await;
+                return unreadable_form(e, format!("/{}/thread/{}", board.slug, id));
+            }
+        };
+        match key.as_str() {
+            "name" => name = value,
+            "email" => email = value,
+            "password" => password = value,
+            "message" => message = value,
+            "captcha_token" => challenge.captcha_token = value,
+            "captcha" => challenge.captcha = value,
+            "pow_challenge" => challenge.pow_challenge = value,
+            "pow_nonce" => challenge.pow_nonce = value,
             _ => {}
         }
     }
     if message.trim().is_empty() {
-        return Html(
-            ErrorPage {
-                message: "Message is required.".to_string(),
-                back_url: format!("/{}/thread/{}", board.slug, id),
-            }
-            .render()
-            .unwrap(),
-        ).into_response();
+        discard_media(media).await;
+        return error_page("Message is required.", format!("/{}/thread/{}", board.slug, id));
     }
     if message.len() > MAX_MESSAGE_LEN {
-        return Html(
-            ErrorPage {
-                message: "Message is too long! (Max 50,000 bytes)".to_string(),
-                back_url: format!("/{}/thread/{}", board.slug, id),
+        discard_media(media).await;
+        return error_page("Message is too long! (Max 50,000 bytes)", format!("/{}/thread/{}", board.slug, id));
+    }
+    let mut poster = match make_poster(&state, &name, &email, &password) {
+        Ok(p) => p,
+        Err(reason) => {
+            discard_media(media).await;
+            return error_page(reason, format!("/{}/thread/{}", board.slug, id));
+        }
+    };
+    if !check_challenge(&state, &headers, &board, board.captcha_for_replies(), &challenge).await {
+        discard_media(media).await;
+        return error_page(
+            "The anti-spam check failed or has expired. Please try again.",
+            format!("/{}/thread/{}", board.slug, id),
+        );
+    }
+    if let Err(reason) = state.flood.check_and_record(ip, &board, PostKind::Reply, &message) {
+        discard_media(media).await;
+        return rate_limited(reason, format!("/{}/thread/{}", board.slug, id));
+    }
+    let slug = board.slug.clone();
+    let config = state.config.clone();
+    let def = board.clone();
+    let password = selfdelete::choose_password(&headers, &password);
+    let hashed = password.clone();
+    // A copy of the upload, to remove if no connection was free and so
+    // nothing was saved.
+    let saved = media.clone();
+    let (verdict, unused_media, cycled) = match state.db.run(move |db| {
+        let verdict = filters::check(db, &slug, &mut [&mut message]);
+        let ip = iplog::stored_ip(db, &config, ip);
+        if verdict != Verdict::Allow {
+            filter_ban(db, &verdict, &ip);
+            return (verdict, media, Vec::new());
+        }
+        poster.password_hash = Some(auth::hash_password(&hashed));
+        models::insert_post(db, &def, id, &poster, &message, media.as_ref(), &ip);
+        let cycled = models::cycle_thread(db, &def, id);
+        (verdict, None, cycled)
+    }).await {
+        Ok(v) => v,
+        Err(e) => {
+            if matches!(e, DbError::Busy) {
+                discard_media(saved).await;
+            }
+            return e.into_response();
+        }
+    };
+    admin::remove_uploads(cycled).await;
+    let back = format!("/{}/thread/{}", board.slug, id);
+    if let Some(page) = refused(&state, verdict, unused_media, ip, &board.slug, back.clone()).await {
+        return page;
+    }
+    let cookie = selfdelete::password_cookie(&state, &password);
+    ([(header::SET_COOKIE, cookie)], Redirect::to(&back)).into_response()
+}
+
+// A board's archive: the threads pruned off it, newest first, with a search.
+pub async fn archive_page(
+    State(state): State<AppState>,
+    Path(board_slug): Path<String>,
+    Query(query): Query<ArchiveQuery>,
+) -> Response {
+    let board = match state.boards.get(&board_slug).await {
+        Some(b) => b,
+        None => {
+            // The error page shows its message as HTML, so the slug from the
+            // path is escaped.
+            let message = format!("Board '{}' not found.", markup::escape_html(&board_slug));
+            return (StatusCode::NOT_FOUND, error_page(message, "/".to_string())).into_response();
+        }
+    };
+    let slug = board.slug.clone();
+    let search = query.clone();
+    let (threads, total) = match state.db.run(move |db| archive::get_archived(db, &slug, &search)).await {
+        Ok(v) => v,
+        Err(e) => return e.into_response(),
+    };
+    let tmpl = ArchiveIndex {
+        board,
+        threads,
+        page_count: total.div_ceil(ARCHIVED_THREADS_PER_PAGE).max(1),
+        query,
+    };
+    Html(tmpl.render().unwrap()).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct SelfDeleteForm {
+    #[serde(default)]
+    password: String,
+    file_only: Option<String>,
+}
+
+// A poster deleting their own post, or only its file, with its deletion
+// password.
+pub async fn self_delete(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path((board_slug, id)): Path<(String, i64)>,
+    Form(form): Form<SelfDeleteForm>,
+) -> Response {
+    let back = format!("/{board_slug}/");
+    let window = state.config.self_delete_minutes;
+    if window <= 0 {
+        return error_page("Deleting your own posts is turned off.", back);
+    }
+    let ip = ip::client_ip(peer.ip(), &headers, &state.config.trusted_proxies);
+    if let Err(reason) = state.flood.check_self_delete(ip) {
+        return rate_limited(reason, back);
+    }
+    let Some(password) = selfdelete::given_password(&headers, &form.password) else {
+        return error_page("Wrong password.", back);
+    };
+    let file_only = form.file_only.is_some();
+    let deleted = match state.db.run(move |db| {
+        selfdelete::delete_own(db, &board_slug, id, &password, file_only, window)
+    }).await {
+        Ok(deleted) => deleted,
+        Err(e) => return e.into_response(),
+    };
+    match deleted {
+        Ok((location, files)) => {
+            admin::remove_uploads(files).await;
+            if location.thread_id == id && !file_only {
+                Redirect::to(&format!("/{}/", location.board)).into_response()
+            } else {
+                Redirect::to(&format!("/{}/thread/{}#p{}", location.board, location.thread_id, id)).into_response()
             }
-            .render()
-            .unwrap(),
-        ).into_response();
+        }
+        Err(reason) => error_page(reason, back),
     }
-    models::insert_post(id, &message, media.as_deref());
-    Redirect::to(&format!("/{}/thread/{}", board.slug, id)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct ReportForm {
+    category: String,
+    #[serde(default)]
+    comment: String,
+}
+
+// Post `id`, if it is on board `board_slug`, or the error page to show instead.
+async fn post_on_board(state: &AppState, board_slug: String, id: i64) -> Result<models::RecentPost, Response> {
+    let post = state.db.run(move |db| models::get_posts_by_id(db, &[id]).pop()).await?;
+    post.filter(|p| p.board == board_slug)
+        .ok_or_else(|| error_page("No such post.", format!("/{board_slug}/")))
+}
+
+pub async fn report_page(State(state): State<AppState>, Path((board_slug, id)): Path<(String, i64)>) -> Response {
+    match post_on_board(&state, board_slug, id).await {
+        Ok(post) => Html(ReportPage { post, categories: REPORT_CATEGORIES, done: None }.render().unwrap()).into_response(),
+        Err(page) => page,
+    }
+}
+
+pub async fn report(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path((board_slug, id)): Path<(String, i64)>,
+    Form(form): Form<ReportForm>,
+) -> Response {
+    let post = match post_on_board(&state, board_slug.clone(), id).await {
+        Ok(post) => post,
+        Err(page) => return page,
+    };
+    let back = format!("/{}/report/{}", board_slug, id);
+    if !reports::valid_category(&form.category) {
+        return error_page("Pick what the report is about.", back);
+    }
+    let comment = form.comment.trim().to_string();
+    if comment.len() > MAX_REPORT_COMMENT_LEN {
+        return error_page("The comment is too long! (Max 1,000 bytes)", back);
+    }
+    let ip = ip::client_ip(peer.ip(), &headers, &state.config.trusted_proxies);
+    let config = state.config.clone();
+    let filed = match state.db.run(move |db| {
+        reports::insert_report(db, &config, id, &board_slug, &form.category, &comment, ip)
+    }).await {
+        Ok(filed) => filed,
+        Err(e) => return e.into_response(),
+    };
+    let done = if filed {
+        "Thanks. The moderators will take a look."
+    } else {
+        "You have already reported this post."
+    };
+    Html(ReportPage { post, categories: REPORT_CATEGORIES, done: Some(done.to_string()) }.render().unwrap()).into_response()
+}
+
+// The public moderation log, if PUBLIC_MOD_LOG is on: what was done on the
+// listed boards, without who did it or what was deleted.
+pub async fn mod_log(State(state): State<AppState>, Query(mut filter): Query<LogFilter>) -> Response {
+    if !state.config.public_mod_log {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    filter.actor.clear();
+    let boards: Vec<String> = state.boards.all().await.into_iter().filter(|b| !b.hidden).map(|b| b.slug).collect();
+    let scope = boards.clone();
+    let query = filter.clone();
+    let (entries, total) = match state.db.run(move |db| modlog::get_log(db, &query, Some(&scope))).await {
+        Ok(v) => v,
+        Err(e) => return e.into_response(),
+    };
+    Html(admin::log_view(None, entries, total, filter, Vec::new(), boards)).into_response()
 }
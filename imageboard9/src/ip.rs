@@ -0,0 +1,80 @@
+use axum::http::HeaderMap;
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+// Parse an address or range: "203.0.113.7", "203.0.113.0/24", "2001:db8::/32".
+// A bare address becomes a /32 (or /128) range.
+pub fn parse_range(s: &str) -> Option<IpNet> {
+    let s = s.trim();
+    s.parse::<IpNet>().ok().or_else(|| s.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+// Who sent the request. The TCP peer is trusted only if it is one of
+// TRUSTED_PROXIES; then X-Forwarded-For is read right to left, skipping
+// further trusted hops, and the first untrusted address is the client.
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted: &[IpNet]) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+    let mut ip = peer.to_canonical();
+    if !is_trusted(&ip) {
+        return ip;
+    }
+    let forwarded = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .collect::<Vec<_>>();
+    for hop in forwarded.iter().rev() {
+        match hop.trim().parse::<IpAddr>() {
+            Ok(hop) => {
+                ip = hop.to_canonical();
+                if !is_trusted(&ip) {
+                    break;
+                }
+            }
+            // Garbage from the client side: stop at the last good hop.
+            Err(_) => break,
+        }
+    }
+    ip
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_ip_walks_past_trusted_proxies() {
+        let trusted = [parse_range("10.0.0.0/8").unwrap(), parse_range("::1").unwrap()];
+        // (peer, X-Forwarded-For headers, expected client)
+        let cases: &[(&str, &[&str], &str)] = &[
+            // No proxy header: the peer itself.
+            ("203.0.113.7", &[], "203.0.113.7"),
+            ("10.0.0.1", &[], "10.0.0.1"),
+            // An untrusted peer can't pick its address by sending the header.
+            ("203.0.113.7", &["198.51.100.1"], "203.0.113.7"),
+            // Through one trusted proxy.
+            ("10.0.0.1", &["198.51.100.1"], "198.51.100.1"),
+            // Addresses the client put in front of the real one are ignored.
+            ("10.0.0.1", &["1.2.3.4, 198.51.100.1"], "198.51.100.1"),
+            // A chain of trusted hops, in one header or several.
+            ("10.0.0.1", &["1.2.3.4, 198.51.100.1, 10.0.0.3, 10.0.0.2"], "198.51.100.1"),
+            ("10.0.0.1", &["1.2.3.4, 198.51.100.1", "10.0.0.2"], "198.51.100.1"),
+            // Every hop trusted: the one furthest away.
+            ("10.0.0.1", &["10.0.0.3, 10.0.0.2"], "10.0.0.3"),
+            // Garbage stops the walk at the last good hop.
+            ("10.0.0.1", &["198.51.100.1, junk, 10.0.0.2"], "10.0.0.2"),
+            // IPv4-mapped IPv6 is the same client as plain IPv4.
+            ("::ffff:203.0.113.7", &[], "203.0.113.7"),
+            ("::1", &["::ffff:198.51.100.1"], "198.51.100.1"),
+        ];
+        for (peer, forwarded, expected) in cases {
+            let mut headers = HeaderMap::new();
+            for value in *forwarded {
+                headers.append("x-forwarded-for", value.parse().unwrap());
+            }
+            let got = client_ip(peer.parse().unwrap(), &headers, &trusted);
+            assert_eq!(got, expected.parse::<IpAddr>().unwrap(), "peer {peer}, X-Forwarded-For {forwarded:?}");
+        }
+    }
+}
@@ -0,0 +1,177 @@
+// ===== IP Retention Settings =====
+// How often the retention purge runs.
+pub const PURGE_INTERVAL_SECS: u64 = 3600;
+// ===== End IP Retention Settings =====
+
+use crate::config::{Config, IpStorage};
+use crate::db::Db;
+use crate::models::StoredIp;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use mysql::*;
+use mysql::prelude::*;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Posters' addresses are kept as HMAC-SHA256(salt, address). Salts live in
+// the ip_salts table; a new one starts every IP_SALT_ROTATE_DAYS, so the same
+// address hashes differently from one period to the next. Once a salt is
+// older than IP_RETENTION_DAYS (and no active ban needs it) it is deleted,
+// and the hashes made with it can never be matched to an address again.
+
+pub fn hash_ip(salt: &[u8], ip: IpAddr) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(salt).unwrap();
+    mac.update(ip.to_string().as_bytes());
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+}
+
+// Whether `hash`, made with salt `salt_id`, is the hash of `ip`. Never true
+// once the salt has been purged.
+pub fn hash_matches(salts: &HashMap<i64, Vec<u8>>, ip: IpAddr, hash: &str, salt_id: i64) -> bool {
+    salts.get(&salt_id).is_some_and(|salt| hash_ip(salt, ip) == hash)
+}
+
+// The salt new posts are hashed with, starting a new one when the current
+// one is older than the rotation period.
+fn current_salt(db: &mut PooledConn, rotate_days: i64) -> (i64, Vec<u8>) {
+    let now = chrono::Utc::now().timestamp();
+    let latest: Option<(i64, Vec<u8>, i64)> =
+        db.query_first("SELECT id, salt, created FROM ip_salts ORDER BY id DESC LIMIT 1").unwrap();
+    match latest {
+        Some((id, salt, created)) if now - created < rotate_days * 86400 => (id, salt),
+        _ => {
+            let salt = rand::random::<[u8; 32]>().to_vec();
+            db.exec_drop(
+                "INSERT INTO ip_salts (salt, created) VALUES (:salt, :now)",
+                params! { "salt" => &salt, "now" => now },
+            ).unwrap();
+            (db.last_insert_id() as i64, salt)
+        }
+    }
+}
+
+// Every salt still kept, by id.
+pub fn get_salts(db: &mut PooledConn) -> HashMap<i64, Vec<u8>> {
+    db.query("SELECT id, salt FROM ip_salts").unwrap().into_iter().collect()
+}
+
+// What to store for a new post from `ip`. The hash is always kept, so bans
+// keep working after the raw address is purged; the raw address only when
+// IP_STORAGE=raw.
+pub fn stored_ip(db: &mut PooledConn, config: &Config, ip: IpAddr) -> StoredIp {
+    let (salt_id, salt) = current_salt(db, config.ip_salt_rotate_days);
+    StoredIp {
+        raw: (config.ip_storage == IpStorage::Raw).then(|| ip.to_string()),
+        hash: hash_ip(&salt, ip),
+        salt_id,
+    }
+}
+
+// Run by the migration that adds hashing: hash the addresses already stored.
+pub fn backfill_hashes(db: &mut PooledConn) {
+    // ip_salts is empty here, so this starts the first salt.
+    let (salt_id, salt) = current_salt(db, 1);
+    for table in ["threads", "posts"] {
+        let rows: Vec<(i64, String)> =
+            db.query(format!("SELECT id, ip FROM {table} WHERE ip IS NOT NULL")).unwrap();
+        db.exec_batch(
+            format!("UPDATE {table} SET ip_hash = :hash, ip_salt = :salt_id WHERE id = :id"),
+            rows.iter().filter_map(|(id, ip)| {
+                let hash = hash_ip(&salt, ip.parse().ok()?);
+                Some(params! { "hash" => hash, "salt_id" => salt_id, "id" => id })
+            }),
+        ).unwrap();
+    }
+}
+
+// Drop raw addresses older than the retention window (all of them when
+// IP_STORAGE=hashed), then salts that are too old and no longer needed by an
+// active ban, along with the hashes made from them (on posts and reports).
+pub fn purge(db: &mut PooledConn, storage: IpStorage, retention_days: i64) {
+    let now = chrono::Utc::now().timestamp();
+    let cutoff = now - retention_days * 86400;
+    let raw_cutoff = match storage {
+        IpStorage::Hashed => Some(now + 1),
+        IpStorage::Raw if retention_days > 0 => Some(cutoff),
+        IpStorage::Raw => None,
+    };
+    if let Some(raw_cutoff) = raw_cutoff {
+        for table in ["threads", "posts"] {
+            db.exec_drop(
+                format!("UPDATE {table} SET ip = NULL WHERE ip IS NOT NULL AND created < :cutoff"),
+                params! { "cutoff" => raw_cutoff },
+            ).unwrap();
+        }
+    }
+    if retention_days <= 0 {
+        return;
+    }
+    let expired: Vec<i64> = db.exec(
+        "SELECT id FROM ip_salts
+         WHERE created < :cutoff
+           AND id <> (SELECT MAX(id) FROM ip_salts)
+           AND id NOT IN (SELECT ip_salt FROM bans
+                          WHERE ip_salt IS NOT NULL AND (expires IS NULL OR expires > :now))",
+        params! { "cutoff" => cutoff, "now" => now },
+    ).unwrap();
+    for salt_id in expired {
+        for table in ["threads", "posts", "reports"] {
+            db.exec_drop(
+                format!("UPDATE {table} SET ip_hash = NULL, ip_salt = NULL WHERE ip_salt = :id"),
+                params! { "id" => salt_id },
+            ).unwrap();
+        }
+        db.exec_drop("DELETE FROM ip_salts WHERE id = :id", params! { "id" => salt_id }).unwrap();
+    }
+}
+
+// Run `purge` in the background every PURGE_INTERVAL_SECS. Nothing to do
+// when raw addresses are kept forever (IP_STORAGE=raw, IP_RETENTION_DAYS=0).
+pub async fn purge_task(db: Db, config: Arc<Config>) {
+    if config.ip_storage == IpStorage::Raw && config.ip_retention_days <= 0 {
+        return;
+    }
+    let mut interval = tokio::time::interval(Duration::from_secs(PURGE_INTERVAL_SECS));
+    loop {
+        interval.tick().await;
+        let (storage, retention_days) = (config.ip_storage, config.ip_retention_days);
+        // A failed run is logged by Db::run and retried next time.
+        let _ = db.run(move |db| purge(db, storage, retention_days)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IP: &str = "203.0.113.7";
+
+    #[test]
+    fn same_address_and_salt_give_the_same_hash() {
+        let ip: IpAddr = IP.parse().unwrap();
+        assert_eq!(hash_ip(b"salt one", ip), hash_ip(b"salt one", ip));
+        assert_ne!(hash_ip(b"salt one", ip), hash_ip(b"salt one", "203.0.113.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn rotated_salt_gives_a_different_hash() {
+        let ip: IpAddr = IP.parse().unwrap();
+        assert_ne!(hash_ip(b"salt one", ip), hash_ip(b"salt two", ip));
+    }
+
+    #[test]
+    fn hash_matches_only_with_its_own_salt() {
+        let ip: IpAddr = IP.parse().unwrap();
+        let salts = HashMap::from([(1, b"salt one".to_vec()), (2, b"salt two".to_vec())]);
+        let hash = hash_ip(b"salt one", ip);
+        assert!(hash_matches(&salts, ip, &hash, 1));
+        assert!(!hash_matches(&salts, ip, &hash, 2));
+        assert!(!hash_matches(&salts, "203.0.113.8".parse().unwrap(), &hash, 1));
+        // Salt 1 purged: the hash can't be matched to anyone any more.
+        let salts = HashMap::from([(2, b"salt two".to_vec())]);
+        assert!(!hash_matches(&salts, ip, &hash, 1));
+    }
+}
@@ -1,33 +1,102 @@
+mod admin;
+mod archive;
+mod auth;
+mod bans;
 mod handlers;
+mod ip;
+mod iplog;
 mod models;
 mod templates;
 mod boards;
+mod captcha;
+mod cli;
+mod config;
+mod db;
+mod filters;
+mod flood;
+mod markup;
+mod media;
+mod migrations;
+mod modlog;
+mod pow;
+mod reports;
+mod selfdelete;
+mod state;
+mod tripcode;
 
 use axum::{
+    extract::DefaultBodyLimit,
     routing::{get, post},
     Router,
 };
 use std::net::SocketAddr;
 use tower_http::services::ServeDir;
 use tokio::net::TcpListener;
+use dotenvy::dotenv;
+use boards::BoardRegistry;
+use captcha::CaptchaStore;
+use config::Config;
+use db::Db;
+use pow::PowGuard;
+use flood::FloodControl;
+use std::sync::Arc;
+use state::AppState;
 
 #[tokio::main]
 async fn main() {
-    models::init_db();
+    dotenv().ok();
+    let db = Db::connect();
+    if !cli::run(&db) {
+        return;
+    }
+    let state = AppState {
+        boards: Arc::new(BoardRegistry::new(db.clone())),
+        config: Arc::new(Config::from_env()),
+        flood: Arc::new(FloodControl::default()),
+        captcha: Arc::new(CaptchaStore::default()),
+        pow: Arc::new(PowGuard::default()),
+        db,
+    };
+    tokio::spawn(iplog::purge_task(state.db.clone(), state.config.clone()));
+    tokio::spawn(flood::prune_task(state.flood.clone(), state.boards.clone()));
+    tokio::spawn(archive::purge_task(state.db.clone(), state.boards.clone()));
 
     let app = Router::new()
         .nest_service("/static", ServeDir::new("static"))
         .route("/", get(handlers::landing_page))
+        .route("/captcha/{token}", get(handlers::captcha_image))
+        .route("/mod-log", get(handlers::mod_log))
         .route("/{board}/", get(handlers::board_page))
         .route("/{board}/page/{page}", get(handlers::board_page_with_page))
         .route("/{board}/new", post(handlers::new_thread))
         .route("/{board}/thread/{id}", get(handlers::thread_view))
         .route("/{board}/reply/{id}", post(handlers::reply))
-        .nest_service("/uploads", ServeDir::new("uploads"));
+        .route("/{board}/archive", get(handlers::archive_page))
+        .route("/{board}/archive/{id}", get(handlers::archived_thread))
+        .route("/{board}/report/{id}", get(handlers::report_page).post(handlers::report))
+        .route("/{board}/delete/{id}", post(handlers::self_delete))
+        .route("/admin", get(admin::dashboard))
+        .route("/admin/login", get(admin::login_page).post(admin::login))
+        .route("/admin/logout", post(admin::logout))
+        .route("/admin/delete/{id}", post(admin::delete_post))
+        .route("/admin/delete-media/{id}", post(admin::delete_media))
+        .route("/admin/sticky/{id}", post(admin::sticky))
+        .route("/admin/lock/{id}", post(admin::lock))
+        .route("/admin/cyclical/{id}", post(admin::cyclical))
+        .route("/admin/ban/{id}", get(admin::ban_page).post(admin::ban))
+        .route("/admin/unban/{id}", post(admin::unban))
+        .route("/admin/reports", get(admin::reports_page))
+        .route("/admin/reports/dismiss/{id}", post(admin::dismiss_reports))
+        .route("/admin/filters", get(admin::filters_page).post(admin::add_filter))
+        .route("/admin/filters/delete/{id}", post(admin::delete_filter))
+        .route("/admin/log", get(admin::log_page))
+        .nest_service("/uploads", ServeDir::new("uploads"))
+        .layer(DefaultBodyLimit::max(handlers::MAX_BODY_SIZE))
+        .with_state(state);
 
     let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
     println!("Listening on http://{}", addr);
 
     let listener = TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
 }
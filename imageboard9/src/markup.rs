@@ -0,0 +1,427 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use unicode_segmentation::UnicodeSegmentation;
+
+// >>>/board/123, >>>/board/ and >>123
+const QUOTE_PATTERN: &str = r">>>/(?P<qboard>[a-z0-9]{1,32})/(?P<qpost>\d{1,18})?|>>(?P<qlocal>\d{1,18})";
+
+static QUOTE_RE: Lazy<Regex> = Lazy::new(|| Regex::new(QUOTE_PATTERN).unwrap());
+
+// Everything that can appear inside a line. Leftmost match wins, so a URL
+// containing asterisks stays one link.
+static INLINE_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(&format!(
+        r"\[spoiler\](?P<spoiler>.+?)\[/spoiler\]|\*\*(?P<bold>.+?)\*\*|\*(?P<italic>[^*\s](?:[^*]*[^*\s])?)\*|(?P<url>https?://[^\s<>\x22']+)|{QUOTE_PATTERN}"
+    ))
+    .unwrap()
+});
+
+// Punctuation that usually ends a sentence rather than a URL.
+const URL_TRAILING: &[char] = &['.', ',', ';', ':', '!', '?', ')', ']', '*'];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Quote {
+    // >>123, a post on the same board
+    Local(i64),
+    // >>>/board/123, or >>>/board/ for the board itself
+    CrossBoard(String, Option<i64>),
+}
+
+// Where a post lives, for building links to it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PostLocation {
+    pub board: String,
+    pub thread_id: i64,
+}
+
+fn parse_quote(caps: &regex::Captures) -> Quote {
+    match caps.name("qlocal") {
+        Some(id) => Quote::Local(id.as_str().parse().unwrap()),
+        None => Quote::CrossBoard(caps["qboard"].to_string(), caps.name("qpost").map(|id| id.as_str().parse().unwrap())),
+    }
+}
+
+// Quotes outside of code blocks.
+pub fn find_quotes(message: &str) -> Vec<Quote> {
+    blocks(message)
+        .into_iter()
+        .filter_map(|b| match b {
+            Block::Line(line) => Some(line),
+            Block::Code(_) => None,
+        })
+        .flat_map(|line| QUOTE_RE.captures_iter(line).map(|c| parse_quote(&c)).collect::<Vec<_>>())
+        .collect()
+}
+
+// The post ids a message refers to, with the board each is expected on.
+pub fn quoted_posts(message: &str, board: &str) -> Vec<(String, i64)> {
+    find_quotes(message)
+        .into_iter()
+        .filter_map(|q| match q {
+            Quote::Local(id) => Some((board.to_string(), id)),
+            Quote::CrossBoard(b, Some(id)) => Some((b, id)),
+            Quote::CrossBoard(_, None) => None,
+        })
+        .collect()
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+enum Block<'a> {
+    Line(&'a str),
+    // The lines between ``` fences. A fence left open runs to the end.
+    Code(Vec<&'a str>),
+}
+
+fn blocks(message: &str) -> Vec<Block<'_>> {
+    let mut out = Vec::new();
+    let mut code: Option<Vec<&str>> = None;
+    for line in message.lines() {
+        let fence = line.trim_start().starts_with("```");
+        match (&mut code, fence) {
+            (Some(lines), false) => lines.push(line),
+            (Some(_), true) => out.push(Block::Code(code.take().unwrap())),
+            (None, true) => code = Some(Vec::new()),
+            (None, false) => out.push(Block::Line(line)),
+        }
+    }
+    if let Some(lines) = code {
+        out.push(Block::Code(lines));
+    }
+    out
+}
+
+fn quote_link(out: &mut String, text: &str, target: Option<(&str, i64, i64)>) {
+    match target {
+        Some((board, thread_id, id)) => {
+            out.push_str(&format!(
+                r#"<a class="quotelink" href="/{board}/thread/{thread_id}#p{id}">{}</a>"#,
+                escape_html(text)
+            ));
+        }
+        None => {
+            out.push_str(&format!(r#"<span class="quotelink deadlink">{}</span>"#, escape_html(text)));
+        }
+    }
+}
+
+struct Renderer<'a> {
+    board: &'a str,
+    locations: &'a HashMap<i64, PostLocation>,
+}
+
+impl Renderer<'_> {
+    // One line of text. Spoilers, bold and italic are rendered recursively,
+    // so every tag opened here is closed here.
+    fn inline(&self, out: &mut String, text: &str) {
+        let mut pos = 0;
+        while let Some(caps) = INLINE_RE.captures_at(text, pos) {
+            let m = caps.get(0).unwrap();
+            out.push_str(&escape_html(&text[pos..m.start()]));
+            pos = m.end();
+
+            if let Some(inner) = caps.name("spoiler") {
+                out.push_str(r#"<span class="spoiler">"#);
+                self.inline(out, inner.as_str());
+                out.push_str("</span>");
+            } else if let Some(inner) = caps.name("bold") {
+                out.push_str("<strong>");
+                self.inline(out, inner.as_str());
+                out.push_str("</strong>");
+            } else if let Some(inner) = caps.name("italic") {
+                out.push_str("<em>");
+                self.inline(out, inner.as_str());
+                out.push_str("</em>");
+            } else if let Some(url) = caps.name("url") {
+                let url = url.as_str().trim_end_matches(URL_TRAILING);
+                pos = m.start() + url.len();
+                if url.len() <= "https://".len() {
+                    out.push_str(&escape_html(url));
+                    continue;
+                }
+                let url = escape_html(url);
+                out.push_str(&format!(r#"<a href="{url}" rel="nofollow noopener" target="_blank">{url}</a>"#));
+            } else {
+                self.quote(out, &caps);
+            }
+        }
+        out.push_str(&escape_html(&text[pos..]));
+    }
+
+    fn quote(&self, out: &mut String, caps: &regex::Captures) {
+        let text = caps.get(0).unwrap().as_str();
+        let (target_board, id) = match parse_quote(caps) {
+            Quote::CrossBoard(b, None) => {
+                out.push_str(&format!(r#"<a class="quotelink" href="/{b}/">{}</a>"#, escape_html(text)));
+                return;
+            }
+            Quote::CrossBoard(b, Some(id)) => (b, id),
+            Quote::Local(id) => (self.board.to_string(), id),
+        };
+        let target = self
+            .locations
+            .get(&id)
+            .filter(|loc| loc.board == target_board)
+            .map(|loc| (loc.board.as_str(), loc.thread_id, id));
+        quote_link(out, text, target);
+    }
+}
+
+// Turn a post's message into HTML. All of the message is escaped here, so the
+// result can go into a template with `| safe`.
+//
+//   >greentext          a line starting with > (but not a >>quote)
+//   [spoiler]x[/spoiler], **bold**, *italic*
+//   ``` ... ```         code block, shown as typed
+//   http(s)://...       link, rel="nofollow noopener"
+//   >>123, >>>/board/123, >>>/board/
+//
+// `locations` holds every quoted post that exists; anything missing from it
+// (or on a different board than the quote says) is a dead link.
+pub fn render(message: &str, board: &str, locations: &HashMap<i64, PostLocation>) -> String {
+    let renderer = Renderer { board, locations };
+    let mut out = String::with_capacity(message.len() + 32);
+    let mut after_line = false;
+    for block in blocks(message) {
+        match block {
+            Block::Line(line) => {
+                if after_line {
+                    out.push_str("<br>\n");
+                }
+                let green = line.starts_with('>') && QUOTE_RE.find(line).is_none_or(|m| m.start() != 0);
+                if green {
+                    out.push_str(r#"<span class="greentext">"#);
+                    renderer.inline(&mut out, line);
+                    out.push_str("</span>");
+                } else {
+                    renderer.inline(&mut out, line);
+                }
+                after_line = true;
+            }
+            Block::Code(lines) => {
+                out.push_str(r#"<pre class="code">"#);
+                out.push_str(&escape_html(&lines.join("\n")));
+                out.push_str("</pre>");
+                after_line = false;
+            }
+        }
+    }
+    out
+}
+
+// The message as plain text, for previews outside a post: markup is
+// removed, code keeps its content and spoilers stay hidden.
+pub fn strip(message: &str) -> String {
+    let mut out = String::with_capacity(message.len());
+    for block in blocks(message) {
+        match block {
+            Block::Line(line) => strip_inline(&mut out, line),
+            Block::Code(lines) => out.push_str(&lines.join("\n")),
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn strip_inline(out: &mut String, text: &str) {
+    let mut pos = 0;
+    for caps in INLINE_RE.captures_iter(text) {
+        let m = caps.get(0).unwrap();
+        out.push_str(&text[pos..m.start()]);
+        pos = m.end();
+        if caps.name("spoiler").is_some() {
+            out.push_str("(spoiler)");
+        } else if let Some(inner) = caps.name("bold").or_else(|| caps.name("italic")) {
+            strip_inline(out, inner.as_str());
+        } else {
+            out.push_str(m.as_str());
+        }
+    }
+    out.push_str(&text[pos..]);
+}
+
+// Cut HTML from `render` down to `max_chars` visible characters (grapheme
+// clusters, so an accent or emoji sequence stays whole). Cuts only between
+// tags, never inside one, and closes whatever was still open. Returns the
+// HTML and whether anything was cut.
+pub fn truncate_html(html: &str, max_chars: usize) -> (String, bool) {
+    let mut out = String::with_capacity(html.len().min(max_chars * 4));
+    let mut open: Vec<&str> = Vec::new();
+    let mut visible = 0;
+    let mut rest = html;
+    while !rest.is_empty() {
+        if rest.starts_with('<') {
+            let end = rest.find('>').map_or(rest.len(), |i| i + 1);
+            let tag = &rest[..end];
+            if let Some(name) = tag.strip_prefix("</") {
+                let name = name.trim_end_matches('>');
+                if let Some(i) = open.iter().rposition(|&t| t == name) {
+                    open.truncate(i);
+                }
+            } else {
+                let name = tag[1..].split(|c: char| c.is_whitespace() || c == '>').next().unwrap_or("");
+                if name != "br" {
+                    open.push(name);
+                }
+            }
+            out.push_str(tag);
+            rest = &rest[end..];
+            continue;
+        }
+        if visible == max_chars {
+            for name in open.iter().rev() {
+                out.push_str(&format!("</{name}>"));
+            }
+            return (out, true);
+        }
+        // An entity like &amp; counts as the one character it shows.
+        let len = if rest.starts_with('&') {
+            rest.find(';').map_or(1, |i| i + 1)
+        } else {
+            let text = &rest[..rest.find(['<', '&']).unwrap_or(rest.len())];
+            text.graphemes(true).next().unwrap().len()
+        };
+        out.push_str(&rest[..len]);
+        rest = &rest[len..];
+        visible += 1;
+    }
+    (out, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn html(message: &str) -> String {
+        render(message, "chess", &HashMap::new())
+    }
+
+    // Every tag in `html` is one `render` writes, and is closed in order.
+    fn assert_balanced(html: &str) {
+        let mut open = Vec::new();
+        for tag in html.split('<').skip(1).map(|t| t.split('>').next().unwrap()) {
+            if let Some(name) = tag.strip_prefix('/') {
+                assert_eq!(open.pop(), Some(name.to_string()), "unbalanced: {html}");
+            } else {
+                let name = tag.split(' ').next().unwrap();
+                assert!(["a", "span", "strong", "em", "pre", "br"].contains(&name), "unexpected tag <{tag}> in {html}");
+                if name != "br" {
+                    open.push(name.to_string());
+                }
+            }
+        }
+        assert!(open.is_empty(), "unclosed tags in {html}");
+    }
+
+    #[test]
+    fn escapes_html() {
+        let out = html(r#"<script>alert("x")</script> & 'y'"#);
+        assert_eq!(out, "&lt;script&gt;alert(&quot;x&quot;)&lt;/script&gt; &amp; &#39;y&#39;");
+    }
+
+    #[test]
+    fn xss_payloads_stay_text() {
+        let payloads = [
+            "<img src=x onerror=alert(1)>",
+            "[spoiler]<svg onload=alert(1)>[/spoiler]",
+            "**<iframe src=javascript:alert(1)>**",
+            "*<b onmouseover=alert(1)>*",
+            "><script>alert(1)</script>",
+            "```\n</pre><script>alert(1)</script>\n```",
+            "javascript:alert(1)",
+            "http://example.com/\"onmouseover=\"alert(1)",
+            "http://example.com/'onmouseover='alert(1)",
+            "http://example.com/<script>alert(1)</script>",
+            "[spoiler]**[/spoiler]<script>**",
+            ">>1\"><script>alert(1)</script>",
+        ];
+        for payload in payloads {
+            let out = html(payload);
+            assert_balanced(&out);
+            assert!(!out.contains("<script"), "{payload} -> {out}");
+            assert!(!out.contains("<img"), "{payload} -> {out}");
+            assert!(!out.contains("<svg"), "{payload} -> {out}");
+            assert!(!out.contains("<iframe"), "{payload} -> {out}");
+            assert!(!out.contains("<b "), "{payload} -> {out}");
+            assert!(!out.contains("\"onmouseover"), "{payload} -> {out}");
+            assert!(!out.contains("'onmouseover"), "{payload} -> {out}");
+            assert!(!out.contains("href=\"javascript"), "{payload} -> {out}");
+        }
+    }
+
+    #[test]
+    fn greentext() {
+        assert_eq!(html(">be me\nok"), "<span class=\"greentext\">&gt;be me</span><br>\nok");
+        assert!(!html(">>12 no").contains("greentext"));
+    }
+
+    #[test]
+    fn spoiler_bold_italic() {
+        assert_eq!(html("[spoiler]Nxf7[/spoiler]"), "<span class=\"spoiler\">Nxf7</span>");
+        assert_eq!(html("**e4** and *d4*"), "<strong>e4</strong> and <em>d4</em>");
+        assert_eq!(html("[spoiler]**mate**[/spoiler]"), "<span class=\"spoiler\"><strong>mate</strong></span>");
+        assert_eq!(html("a * b * c"), "a * b * c");
+        assert_eq!(html("[spoiler]unclosed"), "[spoiler]unclosed");
+    }
+
+    #[test]
+    fn code_blocks_are_verbatim() {
+        assert_eq!(
+            html("see\n```\n1. e4 **e5**\n>>5 <b>\n```\nafter"),
+            "see<pre class=\"code\">1. e4 **e5**\n&gt;&gt;5 &lt;b&gt;</pre>after"
+        );
+        assert!(find_quotes("```\n>>5\n```").is_empty());
+    }
+
+    #[test]
+    fn autolinks() {
+        assert_eq!(
+            html("see https://lichess.org/abc?x=1&y=2."),
+            "see <a href=\"https://lichess.org/abc?x=1&amp;y=2\" rel=\"nofollow noopener\" target=\"_blank\">https://lichess.org/abc?x=1&amp;y=2</a>."
+        );
+        assert!(html("*http://a.com/*").starts_with("<em><a href=\"http://a.com/\""));
+    }
+
+    #[test]
+    fn quotes() {
+        let mut locations = HashMap::new();
+        locations.insert(5, PostLocation { board: "chess".into(), thread_id: 3 });
+        let out = render(">>5 >>6 >>>/puzzles/5 >>>/openings/", "chess", &locations);
+        assert_eq!(
+            out,
+            "<a class=\"quotelink\" href=\"/chess/thread/3#p5\">&gt;&gt;5</a> \
+             <span class=\"quotelink deadlink\">&gt;&gt;6</span> \
+             <span class=\"quotelink deadlink\">&gt;&gt;&gt;/puzzles/5</span> \
+             <a class=\"quotelink\" href=\"/openings/\">&gt;&gt;&gt;/openings/</a>"
+        );
+    }
+
+    #[test]
+    fn truncation_never_splits_tags() {
+        let out = html("**bold start** then [spoiler]a secret &amp; more[/spoiler]\n>green https://example.com/x");
+        for max in 0..80 {
+            let (cut, truncated) = truncate_html(&out, max);
+            assert_balanced(&cut);
+            assert!(!cut.ends_with('&'));
+            assert_eq!(truncated, cut != out, "max {max}");
+        }
+        assert_eq!(truncate_html("<strong>abc</strong>d", 2), ("<strong>ab</strong>".to_string(), true));
+        assert_eq!(truncate_html("&lt;&gt;x", 2), ("&lt;&gt;".to_string(), true));
+        assert_eq!(truncate_html("👨‍👩‍👧é国x", 3), ("👨‍👩‍👧é国".to_string(), true));
+    }
+}
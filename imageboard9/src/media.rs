@@ -0,0 +1,39 @@
+// ===== Thumbnail Settings =====
+pub const OP_THUMB_SIZE: u32 = 250;
+pub const REPLY_THUMB_SIZE: u32 = 180;
+pub const THUMB_DIR: &str = "uploads/thumbs";
+// ===== End Thumbnail Settings =====
+
+use image::{DynamicImage, ImageFormat, ImageReader};
+
+pub struct Thumbnail {
+    pub file: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+// Decode an uploaded image and write a thumbnail that fits in a max x max box.
+// Images with transparency are kept as PNG, everything else becomes JPEG.
+// Returns None if the file can't be decoded (the post still goes through).
+pub fn make_thumbnail(src: &str, stem: &str, max: u32) -> Option<Thumbnail> {
+    let img = ImageReader::open(src).ok()?.with_guessed_format().ok()?.decode().ok()?;
+    let thumb = if img.width() > max || img.height() > max {
+        img.thumbnail(max, max)
+    } else {
+        img
+    };
+
+    std::fs::create_dir_all(THUMB_DIR).ok()?;
+    let (file, thumb, format) = if thumb.color().has_alpha() {
+        (format!("{stem}.png"), thumb, ImageFormat::Png)
+    } else {
+        (format!("{stem}.jpg"), DynamicImage::ImageRgb8(thumb.to_rgb8()), ImageFormat::Jpeg)
+    };
+    thumb.save_with_format(format!("{THUMB_DIR}/{file}"), format).ok()?;
+
+    Some(Thumbnail {
+        file: format!("thumbs/{file}"),
+        width: thumb.width(),
+        height: thumb.height(),
+    })
+}
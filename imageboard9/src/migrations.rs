@@ -0,0 +1,396 @@
+use crate::boards;
+use crate::iplog;
+use crate::models;
+use mysql::*;
+use mysql::prelude::*;
+
+// One schema change. SQL steps run in order, then `code` if present (for
+// data that has to come from Rust, like the seeded board list), and the
+// version is recorded once everything has succeeded. Never edit a migration
+// that has shipped; add a new one to the end of MIGRATIONS instead.
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub steps: &'static [&'static str],
+    pub code: Option<fn(&mut PooledConn)>,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial schema",
+        // IF NOT EXISTS so boards created before migrations existed are adopted as-is.
+        steps: &[
+            "CREATE TABLE IF NOT EXISTS threads (
+                id BIGINT PRIMARY KEY AUTO_INCREMENT,
+                board VARCHAR(32) NOT NULL,
+                subject TEXT NOT NULL,
+                message TEXT NOT NULL,
+                media TEXT,
+                bumped BIGINT NOT NULL
+            )",
+            "CREATE TABLE IF NOT EXISTS posts (
+                id BIGINT PRIMARY KEY AUTO_INCREMENT,
+                thread_id BIGINT NOT NULL,
+                message TEXT NOT NULL,
+                media TEXT,
+                FOREIGN KEY(thread_id) REFERENCES threads(id) ON DELETE CASCADE
+            )",
+        ],
+        code: None,
+    },
+    Migration {
+        version: 2,
+        name: "thumbnails",
+        steps: &[
+            "ALTER TABLE threads
+                ADD COLUMN thumb TEXT,
+                ADD COLUMN thumb_width INT UNSIGNED,
+                ADD COLUMN thumb_height INT UNSIGNED",
+            "ALTER TABLE posts
+                ADD COLUMN thumb TEXT,
+                ADD COLUMN thumb_width INT UNSIGNED,
+                ADD COLUMN thumb_height INT UNSIGNED",
+        ],
+        code: None,
+    },
+    Migration {
+        version: 3,
+        name: "board registry",
+        steps: &[
+            "CREATE TABLE boards (
+                slug VARCHAR(32) PRIMARY KEY,
+                name VARCHAR(128) NOT NULL,
+                description TEXT NOT NULL,
+                position INT NOT NULL DEFAULT 0,
+                hidden BOOLEAN NOT NULL DEFAULT FALSE,
+                locked BOOLEAN NOT NULL DEFAULT FALSE
+            )",
+            "CREATE INDEX threads_board_bumped ON threads (board, bumped)",
+        ],
+        code: Some(boards::seed_boards),
+    },
+    Migration {
+        version: 4,
+        name: "post timestamps",
+        // Older rows get their thread's last bump as the best available guess.
+        steps: &[
+            "ALTER TABLE threads ADD COLUMN created BIGINT NOT NULL DEFAULT 0",
+            "ALTER TABLE posts ADD COLUMN created BIGINT NOT NULL DEFAULT 0",
+            "UPDATE threads SET created = bumped",
+            "UPDATE posts p JOIN threads t ON p.thread_id = t.id SET p.created = t.bumped",
+            "CREATE INDEX threads_created ON threads (created)",
+            "CREATE INDEX posts_created ON posts (created)",
+        ],
+        code: None,
+    },
+    Migration {
+        version: 5,
+        name: "poster names, tripcodes and email",
+        steps: &[
+            "ALTER TABLE threads
+                ADD COLUMN name VARCHAR(64) NOT NULL DEFAULT 'Anonymous',
+                ADD COLUMN tripcode VARCHAR(16),
+                ADD COLUMN email VARCHAR(128)",
+            "ALTER TABLE posts
+                ADD COLUMN name VARCHAR(64) NOT NULL DEFAULT 'Anonymous',
+                ADD COLUMN tripcode VARCHAR(16),
+                ADD COLUMN email VARCHAR(128)",
+        ],
+        code: None,
+    },
+    Migration {
+        version: 6,
+        name: "shared post ids and quote links",
+        // Threads and replies used to number separately, so replies are moved
+        // above the highest thread id and both draw from post_ids from now on.
+        steps: &[
+            "SET @offset = (SELECT COALESCE(MAX(id), 0) FROM threads)",
+            "UPDATE posts SET id = id + @offset ORDER BY id DESC",
+            "CREATE TABLE post_ids (id BIGINT PRIMARY KEY AUTO_INCREMENT)",
+            "INSERT INTO post_ids (id)
+                SELECT MAX(id) FROM (SELECT id FROM threads UNION ALL SELECT id FROM posts) ids
+                HAVING MAX(id) IS NOT NULL",
+            "CREATE TABLE post_refs (
+                from_id BIGINT NOT NULL,
+                from_board VARCHAR(32) NOT NULL,
+                from_thread BIGINT NOT NULL,
+                to_id BIGINT NOT NULL,
+                PRIMARY KEY (from_id, to_id),
+                INDEX post_refs_to (to_id)
+            )",
+        ],
+        code: Some(models::backfill_post_refs),
+    },
+    Migration {
+        version: 7,
+        name: "moderator accounts",
+        steps: &[
+            "CREATE TABLE moderators (
+                id BIGINT PRIMARY KEY AUTO_INCREMENT,
+                username VARCHAR(64) NOT NULL UNIQUE,
+                password_hash VARCHAR(255) NOT NULL,
+                role VARCHAR(16) NOT NULL,
+                created BIGINT NOT NULL
+            )",
+            "CREATE TABLE moderator_boards (
+                moderator_id BIGINT NOT NULL,
+                board VARCHAR(32) NOT NULL,
+                PRIMARY KEY (moderator_id, board),
+                FOREIGN KEY(moderator_id) REFERENCES moderators(id) ON DELETE CASCADE
+            )",
+        ],
+        code: None,
+    },
+    Migration {
+        version: 8,
+        name: "poster addresses and bans",
+        steps: &[
+            "ALTER TABLE threads ADD COLUMN ip VARCHAR(45)",
+            "ALTER TABLE posts ADD COLUMN ip VARCHAR(45)",
+            "CREATE TABLE bans (
+                id BIGINT PRIMARY KEY AUTO_INCREMENT,
+                ip_range VARCHAR(49) NOT NULL,
+                board VARCHAR(32),
+                reason TEXT NOT NULL,
+                created BIGINT NOT NULL,
+                expires BIGINT,
+                moderator_id BIGINT,
+                INDEX bans_expires (expires)
+            )",
+        ],
+        code: None,
+    },
+    Migration {
+        version: 9,
+        name: "hashed poster addresses",
+        steps: &[
+            "CREATE TABLE ip_salts (
+                id BIGINT PRIMARY KEY AUTO_INCREMENT,
+                salt VARBINARY(32) NOT NULL,
+                created BIGINT NOT NULL
+            )",
+            "ALTER TABLE threads ADD COLUMN ip_hash VARCHAR(64), ADD COLUMN ip_salt BIGINT",
+            "ALTER TABLE posts ADD COLUMN ip_hash VARCHAR(64), ADD COLUMN ip_salt BIGINT",
+            "CREATE INDEX threads_ip_salt ON threads (ip_salt)",
+            "CREATE INDEX posts_ip_salt ON posts (ip_salt)",
+            "ALTER TABLE bans
+                MODIFY ip_range VARCHAR(49),
+                ADD COLUMN ip_hash VARCHAR(64),
+                ADD COLUMN ip_salt BIGINT",
+        ],
+        code: Some(iplog::backfill_hashes),
+    },
+    Migration {
+        version: 10,
+        name: "per-board flood control",
+        steps: &[
+            "ALTER TABLE boards
+                ADD COLUMN post_interval INT NOT NULL DEFAULT 10,
+                ADD COLUMN thread_interval INT NOT NULL DEFAULT 60,
+                ADD COLUMN duplicate_window INT NOT NULL DEFAULT 300",
+        ],
+        code: None,
+    },
+    Migration {
+        version: 11,
+        name: "per-board captcha",
+        steps: &["ALTER TABLE boards ADD COLUMN captcha VARCHAR(8) NOT NULL DEFAULT 'off'"],
+        code: None,
+    },
+    Migration {
+        version: 12,
+        name: "proof-of-work captcha",
+        steps: &[
+            "ALTER TABLE boards
+                ADD COLUMN captcha_kind VARCHAR(8) NOT NULL DEFAULT 'image',
+                ADD COLUMN pow_bits INT NOT NULL DEFAULT 16",
+        ],
+        code: None,
+    },
+    Migration {
+        version: 13,
+        name: "word filters",
+        steps: &[
+            "CREATE TABLE filters (
+                id BIGINT PRIMARY KEY AUTO_INCREMENT,
+                pattern TEXT NOT NULL,
+                is_regex BOOLEAN NOT NULL DEFAULT FALSE,
+                board VARCHAR(32),
+                action VARCHAR(8) NOT NULL,
+                action_text TEXT,
+                ban_hours BIGINT NOT NULL DEFAULT 0,
+                hits BIGINT NOT NULL DEFAULT 0,
+                created BIGINT NOT NULL,
+                moderator_id BIGINT
+            )",
+        ],
+        code: None,
+    },
+    Migration {
+        version: 14,
+        name: "post reports",
+        steps: &[
+            "CREATE TABLE reports (
+                id BIGINT PRIMARY KEY AUTO_INCREMENT,
+                post_id BIGINT NOT NULL,
+                board VARCHAR(32) NOT NULL,
+                category VARCHAR(16) NOT NULL,
+                comment TEXT NOT NULL,
+                ip_hash VARCHAR(64),
+                ip_salt BIGINT,
+                created BIGINT NOT NULL,
+                UNIQUE KEY reports_post_ip (post_id, ip_hash),
+                INDEX reports_board (board),
+                INDEX reports_ip_salt (ip_salt)
+            )",
+        ],
+        code: None,
+    },
+    Migration {
+        version: 15,
+        name: "moderation log",
+        steps: &[
+            "CREATE TABLE mod_log (
+                id BIGINT PRIMARY KEY AUTO_INCREMENT,
+                created BIGINT NOT NULL,
+                moderator_id BIGINT,
+                actor VARCHAR(64) NOT NULL,
+                action VARCHAR(32) NOT NULL,
+                board VARCHAR(32),
+                target BIGINT,
+                details TEXT NOT NULL,
+                snapshot MEDIUMTEXT,
+                INDEX mod_log_actor (actor),
+                INDEX mod_log_action (action),
+                INDEX mod_log_board (board)
+            )",
+        ],
+        code: None,
+    },
+    Migration {
+        version: 16,
+        name: "sticky and locked threads",
+        steps: &[
+            "ALTER TABLE threads
+                ADD COLUMN sticky BOOLEAN NOT NULL DEFAULT FALSE,
+                ADD COLUMN locked BOOLEAN NOT NULL DEFAULT FALSE",
+        ],
+        code: None,
+    },
+    Migration {
+        version: 17,
+        name: "bump limit and board size cap",
+        steps: &[
+            "ALTER TABLE boards
+                ADD COLUMN bump_limit INT NOT NULL DEFAULT 300,
+                ADD COLUMN max_threads INT NOT NULL DEFAULT 150",
+        ],
+        code: None,
+    },
+    Migration {
+        version: 18,
+        name: "thread archive",
+        steps: &[
+            "ALTER TABLE threads
+                ADD COLUMN archived BIGINT NULL,
+                ADD INDEX threads_archived (board, archived)",
+            "ALTER TABLE boards
+                ADD COLUMN archive BOOLEAN NOT NULL DEFAULT TRUE,
+                ADD COLUMN archive_days INT NOT NULL DEFAULT 0",
+        ],
+        code: None,
+    },
+    Migration {
+        version: 19,
+        name: "cyclical threads",
+        steps: &[
+            "ALTER TABLE threads ADD COLUMN cyclical BOOLEAN NOT NULL DEFAULT FALSE",
+        ],
+        code: None,
+    },
+    Migration {
+        version: 20,
+        name: "deletion passwords",
+        steps: &[
+            "ALTER TABLE threads ADD COLUMN password_hash VARCHAR(255) NULL",
+            "ALTER TABLE posts ADD COLUMN password_hash VARCHAR(255) NULL",
+        ],
+        code: None,
+    },
+    Migration {
+        version: 21,
+        name: "cyclical reply cap per board",
+        steps: &[
+            "ALTER TABLE boards ADD COLUMN cyclical_replies INT NOT NULL DEFAULT 500",
+        ],
+        code: None,
+    },
+];
+
+// Every table the migrations create, for --reset-db.
+const TABLES: &[&str] = &["bans", "boards", "filters", "ip_salts", "mod_log", "moderator_boards", "moderators", "post_ids", "post_refs", "posts", "reports", "threads", "schema_version"];
+
+fn ensure_version_table(db: &mut PooledConn) {
+    db.query_drop(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INT UNSIGNED PRIMARY KEY,
+            name VARCHAR(128) NOT NULL,
+            applied_at BIGINT NOT NULL
+        )"
+    ).unwrap();
+}
+
+pub fn applied_versions(db: &mut PooledConn) -> Vec<(u32, String, i64)> {
+    ensure_version_table(db);
+    db.query("SELECT version, name, applied_at FROM schema_version ORDER BY version").unwrap()
+}
+
+// Apply every migration newer than the database, in order. Returns how many ran.
+pub fn migrate(db: &mut PooledConn) -> usize {
+    let current = applied_versions(db).last().map_or(0, |(v, _, _)| *v);
+    let mut ran = 0;
+    for m in MIGRATIONS.iter().filter(|m| m.version > current) {
+        println!("Applying migration {} ({})", m.version, m.name);
+        for step in m.steps {
+            db.query_drop(step)
+                .unwrap_or_else(|e| panic!("Migration {} ({}) failed: {}", m.version, m.name, e));
+        }
+        if let Some(code) = m.code {
+            code(db);
+        }
+        db.exec_drop(
+            "INSERT INTO schema_version (version, name, applied_at) VALUES (:version, :name, :now)",
+            params! { "version" => m.version, "name" => m.name, "now" => chrono::Utc::now().timestamp() },
+        ).unwrap();
+        ran += 1;
+    }
+    ran
+}
+
+// Print applied and pending migrations.
+pub fn print_status(db: &mut PooledConn) {
+    let applied = applied_versions(db);
+    for (version, name, applied_at) in &applied {
+        let when = chrono::DateTime::from_timestamp(*applied_at, 0)
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+            .unwrap_or_default();
+        println!("  [applied] {:>3}  {}  ({})", version, name, when);
+    }
+    let current = applied.last().map_or(0, |(v, _, _)| *v);
+    let pending: Vec<_> = MIGRATIONS.iter().filter(|m| m.version > current).collect();
+    for m in &pending {
+        println!("  [pending] {:>3}  {}", m.version, m.name);
+    }
+    println!("Schema version {}, {} pending.", current, pending.len());
+}
+
+// Drop every table, data included. Only reachable through --reset-db.
+pub fn reset(db: &mut PooledConn) {
+    println!("--reset-db given. Dropping all tables.");
+    db.query_drop("SET FOREIGN_KEY_CHECKS = 0").unwrap();
+    for table in TABLES {
+        db.query_drop(format!("DROP TABLE IF EXISTS {table}")).unwrap();
+    }
+    db.query_drop("SET FOREIGN_KEY_CHECKS = 1").unwrap();
+}
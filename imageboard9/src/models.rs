@@ -3,171 +3,670 @@ pub const THREAD_PREVIEW_LENGTH: usize = 160;
 pub const REPLY_PREVIEW_LENGTH: usize = 80;
 pub const THREADS_PER_PAGE: usize = 8;
 pub const REPLIES_TO_SHOW: usize = 3;
-pub const RESET_DB_ON_START: bool = true;
+pub const RECENT_POSTS_ON_LANDING: usize = 10;
 // ===== End Board Settings =====
 
+use crate::boards::BoardDef;
+use crate::markup::{self, PostLocation};
 use mysql::*;
 use mysql::prelude::*;
 use serde::{Deserialize, Serialize};
-use chrono;
-use once_cell::sync::Lazy;
-use std::sync::Mutex;
-use dotenvy::dotenv;
-use std::env;
-
-pub static DB: Lazy<Mutex<PooledConn>> = Lazy::new(|| {
-    dotenv().ok();
-    let db_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set in .env or environment");
-    let pool = Pool::new(db_url.as_str()).expect("Failed to connect to MariaDB/MySQL");
-    Mutex::new(pool.get_conn().expect("Failed to get DB connection"))
-});
+use std::collections::HashMap;
+use unicode_segmentation::UnicodeSegmentation;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Thread {
     pub id: i64,
     pub board: String,
     pub subject: String,
+    pub name: String,
+    pub tripcode: Option<String>,
+    pub email: Option<String>,
     pub message: String,
     pub media: Option<String>,
+    pub thumb: Option<String>,
+    pub thumb_width: u32,
+    pub thumb_height: u32,
     pub preview: String,
+    // The message as HTML, filled in by the handler before rendering.
+    pub body_html: String,
+    pub backlinks: Vec<Backlink>,
+    pub created: i64,
     pub bumped: i64,
+    // Listed ahead of every other thread on the board.
+    pub sticky: bool,
+    // Closed to new replies.
+    pub locked: bool,
+    // Keeps only its board's newest `cyclical_replies` replies, and ignores
+    // the bump limit.
+    pub cyclical: bool,
+    // When the thread was pruned into the board's archive. Archived threads
+    // are read-only and left off the board pages.
+    pub archived: Option<i64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Post {
     pub id: i64,
     pub thread_id: i64,
+    pub name: String,
+    pub tripcode: Option<String>,
+    pub email: Option<String>,
     pub message: String,
     pub media: Option<String>,
+    pub thumb: Option<String>,
+    pub thumb_width: u32,
+    pub thumb_height: u32,
     pub preview: String,
+    pub body_html: String,
+    pub backlinks: Vec<Backlink>,
+    pub created: i64,
 }
 
-pub fn init_db() {
-    let mut db = DB.lock().unwrap();
-    if RESET_DB_ON_START {
-        println!("RESET_DB_ON_START is true. Dropping all posts and threads tables.");
-        db.query_drop("DROP TABLE IF EXISTS posts").unwrap();
-        db.query_drop("DROP TABLE IF EXISTS threads").unwrap();
-    }
-    db.query_drop(
-        "CREATE TABLE IF NOT EXISTS threads (
-            id BIGINT PRIMARY KEY AUTO_INCREMENT,
-            board VARCHAR(32) NOT NULL,
-            subject TEXT NOT NULL,
-            message TEXT NOT NULL,
-            media TEXT,
-            bumped BIGINT NOT NULL
-        )"
-    ).unwrap();
-    db.query_drop(
-        "CREATE TABLE IF NOT EXISTS posts (
-            id BIGINT PRIMARY KEY AUTO_INCREMENT,
-            thread_id BIGINT NOT NULL,
-            message TEXT NOT NULL,
-            media TEXT,
-            FOREIGN KEY(thread_id) REFERENCES threads(id) ON DELETE CASCADE
-        )"
-    ).unwrap();
+// A post that quotes another one, listed under the quoted post.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Backlink {
+    pub id: i64,
+    pub board: String,
+    pub thread_id: i64,
+}
+
+// Who a new post is from, as entered in the name and email fields.
+#[derive(Debug, Clone)]
+pub struct Poster {
+    pub name: String,
+    pub tripcode: Option<String>,
+    pub email: Option<String>,
+    // Argon2 hash of the deletion password. See selfdelete.
+    pub password_hash: Option<String>,
+}
+
+impl Poster {
+    // "sage" in the email field means reply without bumping.
+    pub fn sage(&self) -> bool {
+        self.email.as_deref().is_some_and(|e| e.eq_ignore_ascii_case("sage"))
+    }
+}
+
+// The poster's address as stored with a post. See iplog.
+#[derive(Debug, Clone)]
+pub struct StoredIp {
+    // None when only the hash is kept (IP_STORAGE=hashed).
+    pub raw: Option<String>,
+    pub hash: String,
+    pub salt_id: i64,
+}
+
+// An accepted upload: the original file plus its thumbnail, if one was made.
+#[derive(Debug, Clone)]
+pub struct Media {
+    pub file: String,
+    pub thumb: Option<String>,
+    pub thumb_width: u32,
+    pub thumb_height: u32,
+}
+
+const THREAD_COLUMNS: &str = "id, board, subject, name, tripcode, email, message, media, thumb, thumb_width, thumb_height, created, bumped, sticky, locked, cyclical, archived";
+const POST_COLUMNS: &str = "id, thread_id, name, tripcode, email, message, media, thumb, thumb_width, thumb_height, created";
+
+// A plain-text preview: markup removed, whitespace collapsed, and cut to at
+// most `len` graphemes (not bytes, which would split a multi-byte character).
+pub fn preview_of(message: &str, len: usize) -> String {
+    let text = markup::strip(message).split_whitespace().collect::<Vec<_>>().join(" ");
+    match text.grapheme_indices(true).nth(len) {
+        Some((cut, _)) => format!("{}…", text[..cut].trim_end()),
+        None => text,
+    }
+}
+
+fn thread_from_row(mut row: Row) -> Thread {
+    let message: String = row.take("message").unwrap();
+    Thread {
+        id: row.take("id").unwrap(),
+        board: row.take("board").unwrap(),
+        subject: row.take("subject").unwrap(),
+        name: row.take("name").unwrap(),
+        tripcode: row.take("tripcode").unwrap(),
+        email: row.take("email").unwrap(),
+        preview: preview_of(&message, THREAD_PREVIEW_LENGTH),
+        body_html: String::new(),
+        backlinks: Vec::new(),
+        message,
+        media: row.take("media").unwrap(),
+        thumb: row.take("thumb").unwrap(),
+        thumb_width: row.take::<Option<u32>, _>("thumb_width").unwrap().unwrap_or(0),
+        thumb_height: row.take::<Option<u32>, _>("thumb_height").unwrap().unwrap_or(0),
+        created: row.take("created").unwrap(),
+        bumped: row.take("bumped").unwrap(),
+        sticky: row.take("sticky").unwrap(),
+        locked: row.take("locked").unwrap(),
+        cyclical: row.take("cyclical").unwrap(),
+        archived: row.take("archived").unwrap(),
+    }
 }
 
-pub fn get_threads_paged(board: &str, threads_per_page: usize, page: usize) -> Vec<Thread> {
-    let mut db = DB.lock().unwrap();
+fn post_from_row(mut row: Row, preview_len: usize) -> Post {
+    let message: String = row.take("message").unwrap();
+    Post {
+        id: row.take("id").unwrap(),
+        thread_id: row.take("thread_id").unwrap(),
+        name: row.take("name").unwrap(),
+        tripcode: row.take("tripcode").unwrap(),
+        email: row.take("email").unwrap(),
+        preview: preview_of(&message, preview_len),
+        body_html: String::new(),
+        backlinks: Vec::new(),
+        message,
+        media: row.take("media").unwrap(),
+        thumb: row.take("thumb").unwrap(),
+        thumb_width: row.take::<Option<u32>, _>("thumb_width").unwrap().unwrap_or(0),
+        thumb_height: row.take::<Option<u32>, _>("thumb_height").unwrap().unwrap_or(0),
+        created: row.take("created").unwrap(),
+    }
+}
+
+// Stickies first, then the most recently bumped.
+pub fn get_threads_paged(db: &mut PooledConn, board: &str, threads_per_page: usize, page: usize) -> Vec<Thread> {
     db.exec_map(
-        "SELECT id, board, subject, message, media, bumped FROM threads WHERE board = :board ORDER BY bumped DESC LIMIT :limit OFFSET :offset",
+        format!(
+            "SELECT {THREAD_COLUMNS} FROM threads WHERE board = :board AND archived IS NULL
+             ORDER BY sticky DESC, bumped DESC LIMIT :limit OFFSET :offset"
+        ),
         params! {
             "board" => board,
             "limit" => threads_per_page as u64,
             "offset" => (threads_per_page * page) as u64
         },
-        |(id, board, subject, message, media, bumped): (i64, String, String, String, Option<String>, i64)| {
-            let preview = if message.len() > THREAD_PREVIEW_LENGTH {
-                format!("{}...", &message[..THREAD_PREVIEW_LENGTH])
-            } else {
-                message.clone()
-            };
-            Thread { id, board, subject, message, media, preview, bumped }
-        }
+        thread_from_row,
     ).unwrap_or_default()
 }
 
-pub fn get_total_thread_count(board: &str) -> usize {
-    let mut db = DB.lock().unwrap();
+pub fn get_total_thread_count(db: &mut PooledConn, board: &str) -> usize {
     db.exec_first::<u64, _, _>(
-        "SELECT COUNT(*) FROM threads WHERE board = :board",
+        "SELECT COUNT(*) FROM threads WHERE board = :board AND archived IS NULL",
         params! { "board" => board }
     ).unwrap_or(Some(0)).unwrap_or(0) as usize
 }
 
-pub fn get_last_n_replies(thread_id: i64, n: usize) -> Vec<Post> {
-    let mut db = DB.lock().unwrap();
+pub fn get_last_n_replies(db: &mut PooledConn, thread_id: i64, n: usize) -> Vec<Post> {
     let mut posts: Vec<Post> = db.exec_map(
-        "SELECT id, thread_id, message, media FROM posts WHERE thread_id = :tid ORDER BY id DESC LIMIT :n",
+        format!("SELECT {POST_COLUMNS} FROM posts WHERE thread_id = :tid ORDER BY id DESC LIMIT :n"),
         params! { "tid" => thread_id, "n" => n as u64 },
-        |(id, thread_id, message, media): (i64, i64, String, Option<String>)| {
-            let preview = if message.len() > REPLY_PREVIEW_LENGTH {
-                format!("{}...", &message[..REPLY_PREVIEW_LENGTH])
-            } else {
-                message.clone()
-            };
-            Post { id, thread_id, message, media, preview }
-        }
+        |row| post_from_row(row, REPLY_PREVIEW_LENGTH),
     ).unwrap_or_default();
     posts.reverse();
     posts
 }
 
-pub fn get_thread(id: i64) -> Option<(Thread, Vec<Post>)> {
-    let mut db = DB.lock().unwrap();
-    let thread = db.exec_first(
-        "SELECT id, board, subject, message, media, bumped FROM threads WHERE id = :id",
+pub fn get_thread(db: &mut PooledConn, id: i64) -> Option<(Thread, Vec<Post>)> {
+    let mut thread = db.exec_first(
+        format!("SELECT {THREAD_COLUMNS} FROM threads WHERE id = :id"),
         params! { "id" => id }
-    ).unwrap_or(None)
-     .map(|(id, board, subject, message, media, bumped): (i64, String, String, String, Option<String>, i64)| {
-        let preview = if message.len() > THREAD_PREVIEW_LENGTH {
-            format!("{}...", &message[..THREAD_PREVIEW_LENGTH])
-        } else {
-            message.clone()
-        };
-        Thread { id, board, subject, message, media, preview, bumped }
-    })?;
-
-    let posts = db.exec_map(
-        "SELECT id, thread_id, message, media FROM posts WHERE thread_id = :id ORDER BY id ASC",
+    ).unwrap_or(None).map(thread_from_row)?;
+
+    let mut posts: Vec<Post> = db.exec_map(
+        format!("SELECT {POST_COLUMNS} FROM posts WHERE thread_id = :id ORDER BY id ASC"),
         params! { "id" => id },
-        |(id, thread_id, message, media): (i64, i64, String, Option<String>)| {
-            Post { id, thread_id, message: message.clone(), media, preview: message }
-        }
+        |row| post_from_row(row, usize::MAX),
     ).unwrap_or_default();
 
+    let ids: Vec<i64> = std::iter::once(thread.id).chain(posts.iter().map(|p| p.id)).collect();
+    let mut backlinks = get_backlinks(db, &ids);
+    thread.backlinks = backlinks.remove(&thread.id).unwrap_or_default();
+    for post in &mut posts {
+        post.backlinks = backlinks.remove(&post.id).unwrap_or_default();
+    }
+
     Some((thread, posts))
 }
 
-pub fn insert_thread(board: &str, subject: &str, message: &str, media: Option<&str>) {
-    let mut db = DB.lock().unwrap();
-    let now = chrono::Utc::now().timestamp();
+// Which board a thread is on, whether it is locked and whether it is
+// archived, if it exists.
+pub fn get_thread_status(db: &mut PooledConn, id: i64) -> Option<(String, bool, bool)> {
+    db.exec_first(
+        "SELECT board, locked, archived IS NOT NULL FROM threads WHERE id = :id",
+        params! { "id" => id },
+    ).unwrap_or(None)
+}
+
+// A thread setting moderators switch on and off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadFlag {
+    Sticky,
+    Locked,
+    Cyclical,
+}
+
+impl ThreadFlag {
+    fn column(self) -> &'static str {
+        match self {
+            ThreadFlag::Sticky => "sticky",
+            ThreadFlag::Locked => "locked",
+            ThreadFlag::Cyclical => "cyclical",
+        }
+    }
+
+    // The moderation log action for switching it on or off.
+    pub fn action(self, on: bool) -> &'static str {
+        match (self, on) {
+            (ThreadFlag::Sticky, true) => "sticky",
+            (ThreadFlag::Sticky, false) => "unsticky",
+            (ThreadFlag::Locked, true) => "lock",
+            (ThreadFlag::Locked, false) => "unlock",
+            (ThreadFlag::Cyclical, true) => "cyclical",
+            (ThreadFlag::Cyclical, false) => "uncyclical",
+        }
+    }
+}
+
+pub fn set_thread_flag(db: &mut PooledConn, id: i64, flag: ThreadFlag, on: bool) {
     db.exec_drop(
-        "INSERT INTO threads (board, subject, message, media, bumped) VALUES (:board, :subject, :message, :media, :bumped)",
-        params! { "board" => board, "subject" => subject, "message" => message, "media" => media, "bumped" => now },
+        format!("UPDATE threads SET {} = :on WHERE id = :id", flag.column()),
+        params! { "on" => on, "id" => id },
     ).unwrap();
 }
 
-pub fn insert_post(thread_id: i64, message: &str, media: Option<&str>) {
-    let mut db = DB.lock().unwrap();
+// Threads and replies share one id sequence so that `>>id` means one post.
+// post_ids only ever holds the newest id.
+fn next_post_id(db: &mut PooledConn) -> i64 {
+    db.query_drop("INSERT INTO post_ids () VALUES ()").unwrap();
+    let id = db.last_insert_id() as i64;
+    db.exec_drop("DELETE FROM post_ids WHERE id < :id", params! { "id" => id }).unwrap();
+    id
+}
+
+pub fn insert_thread(
+    db: &mut PooledConn,
+    board: &str,
+    poster: &Poster,
+    subject: &str,
+    message: &str,
+    media: Option<&Media>,
+    ip: &StoredIp,
+) -> i64 {
+    let now = chrono::Utc::now().timestamp();
+    let id = next_post_id(db);
     db.exec_drop(
-        "INSERT INTO posts (thread_id, message, media) VALUES (:tid, :message, :media)",
-        params! { "tid" => thread_id, "message" => message, "media" => media },
+        "INSERT INTO threads (id, board, subject, name, tripcode, email, message, media, thumb, thumb_width, thumb_height, ip, ip_hash, ip_salt, password_hash, created, bumped)
+         VALUES (:id, :board, :subject, :name, :tripcode, :email, :message, :media, :thumb, :thumb_width, :thumb_height, :ip, :ip_hash, :ip_salt, :password_hash, :bumped, :bumped)",
+        params! {
+            "id" => id,
+            "board" => board,
+            "subject" => subject,
+            "name" => &poster.name,
+            "tripcode" => &poster.tripcode,
+            "email" => &poster.email,
+            "message" => message,
+            "media" => media.map(|m| &m.file),
+            "thumb" => media.and_then(|m| m.thumb.as_ref()),
+            "thumb_width" => media.map(|m| m.thumb_width),
+            "thumb_height" => media.map(|m| m.thumb_height),
+            "ip" => &ip.raw,
+            "ip_hash" => &ip.hash,
+            "ip_salt" => ip.salt_id,
+            "password_hash" => &poster.password_hash,
+            "bumped" => now,
+        },
     ).unwrap();
+    save_quotes(db, id, board, id, message);
+    id
+}
+
+// A reply bumps its thread unless it is a sage or the thread is past the
+// board's bump limit. Cyclical threads have no bump limit.
+pub fn insert_post(
+    db: &mut PooledConn,
+    board: &BoardDef,
+    thread_id: i64,
+    poster: &Poster,
+    message: &str,
+    media: Option<&Media>,
+    ip: &StoredIp,
+) -> i64 {
     let now = chrono::Utc::now().timestamp();
+    let id = next_post_id(db);
     db.exec_drop(
-        "UPDATE threads SET bumped = :bumped WHERE id = :id",
-        params! { "bumped" => now, "id" => thread_id },
+        "INSERT INTO posts (id, thread_id, name, tripcode, email, message, media, thumb, thumb_width, thumb_height, ip, ip_hash, ip_salt, password_hash, created)
+         VALUES (:id, :tid, :name, :tripcode, :email, :message, :media, :thumb, :thumb_width, :thumb_height, :ip, :ip_hash, :ip_salt, :password_hash, :created)",
+        params! {
+            "id" => id,
+            "tid" => thread_id,
+            "name" => &poster.name,
+            "tripcode" => &poster.tripcode,
+            "email" => &poster.email,
+            "message" => message,
+            "media" => media.map(|m| &m.file),
+            "thumb" => media.and_then(|m| m.thumb.as_ref()),
+            "thumb_width" => media.map(|m| m.thumb_width),
+            "thumb_height" => media.map(|m| m.thumb_height),
+            "ip" => &ip.raw,
+            "ip_hash" => &ip.hash,
+            "ip_salt" => ip.salt_id,
+            "password_hash" => &poster.password_hash,
+            "created" => now,
+        },
     ).unwrap();
+    save_quotes(db, id, &board.slug, thread_id, message);
+    let cyclical: bool = db
+        .exec_first("SELECT cyclical FROM threads WHERE id = :id", params! { "id" => thread_id })
+        .unwrap()
+        .unwrap_or(false);
+    let past_limit = !cyclical && board.bump_limit > 0 && get_post_count(db, thread_id) > board.bump_limit as usize;
+    if !poster.sage() && !past_limit {
+        db.exec_drop(
+            "UPDATE threads SET bumped = :bumped WHERE id = :id",
+            params! { "bumped" => now, "id" => thread_id },
+        ).unwrap();
+    }
+    id
 }
 
-pub fn get_post_count(thread_id: i64) -> usize {
-    let mut db = DB.lock().unwrap();
+// The address a thread or reply was posted from. None for posts made
+// before addresses were recorded.
+pub fn get_post_ip(db: &mut PooledConn, id: i64) -> Option<String> {
+    db.exec_first(
+        "SELECT ip FROM threads WHERE id = :id UNION ALL SELECT ip FROM posts WHERE id = :id",
+        params! { "id" => id },
+    ).unwrap().flatten()
+}
+
+// The salted address hash of a thread or reply, with the id of its salt.
+pub fn get_post_ip_hash(db: &mut PooledConn, id: i64) -> Option<(String, i64)> {
+    let row: Option<(Option<String>, Option<i64>)> = db.exec_first(
+        "SELECT ip_hash, ip_salt FROM threads WHERE id = :id UNION ALL SELECT ip_hash, ip_salt FROM posts WHERE id = :id",
+        params! { "id" => id },
+    ).unwrap();
+    match row {
+        Some((Some(hash), Some(salt_id))) => Some((hash, salt_id)),
+        _ => None,
+    }
+}
+
+// Delete a thread with all its replies, or a single reply. Returns the
+// upload files (originals and thumbnails) for the caller to remove, or None
+// if there is no such post.
+pub fn delete_post(db: &mut PooledConn, id: i64) -> Option<Vec<String>> {
+    let location = resolve_posts(db, &[id]).remove(&id)?;
+    // (id, media, thumb) of every post going away
+    let rows: Vec<(i64, Option<String>, Option<String>)> = if location.thread_id == id {
+        let rows = db.exec(
+            "SELECT id, media, thumb FROM threads WHERE id = :id
+             UNION ALL
+             SELECT id, media, thumb FROM posts WHERE thread_id = :id",
+            params! { "id" => id },
+        ).unwrap();
+        db.exec_drop("DELETE FROM threads WHERE id = :id", params! { "id" => id }).unwrap();
+        rows
+    } else {
+        let rows = db.exec("SELECT id, media, thumb FROM posts WHERE id = :id", params! { "id" => id }).unwrap();
+        db.exec_drop("DELETE FROM posts WHERE id = :id", params! { "id" => id }).unwrap();
+        rows
+    };
+    db.exec_batch(
+        "DELETE FROM post_refs WHERE from_id = :id OR to_id = :id",
+        rows.iter().map(|(id, _, _)| params! { "id" => id }),
+    ).unwrap();
+    db.exec_batch(
+        "DELETE FROM reports WHERE post_id = :id",
+        rows.iter().map(|(id, _, _)| params! { "id" => id }),
+    ).unwrap();
+    Some(rows.into_iter().flat_map(|(_, m, t)| m.into_iter().chain(t)).collect())
+}
+
+// Take the least recently bumped threads off a board until at most
+// `max_threads` are left, not counting stickies. They move to the archive
+// if the board keeps one, and are deleted otherwise. Returns the upload
+// files to remove.
+pub fn prune_threads(db: &mut PooledConn, board: &BoardDef) -> Vec<String> {
+    if board.max_threads <= 0 {
+        return Vec::new();
+    }
+    let ids: Vec<i64> = db.exec(
+        "SELECT id FROM threads WHERE board = :board AND NOT sticky AND archived IS NULL
+         ORDER BY bumped DESC LIMIT 18446744073709551615 OFFSET :keep",
+        params! { "board" => &board.slug, "keep" => board.max_threads as u64 },
+    ).unwrap();
+    if board.archive {
+        let now = chrono::Utc::now().timestamp();
+        db.exec_batch(
+            "UPDATE threads SET archived = :now WHERE id = :id",
+            ids.iter().map(|id| params! { "now" => now, "id" => id }),
+        ).unwrap();
+        return Vec::new();
+    }
+    ids.into_iter().flat_map(|id| delete_post(db, id).unwrap_or_default()).collect()
+}
+
+// Delete the oldest replies of a cyclical thread until the board's
+// `cyclical_replies` are left. Returns the upload files to remove.
+pub fn cycle_thread(db: &mut PooledConn, board: &BoardDef, thread_id: i64) -> Vec<String> {
+    if board.cyclical_replies <= 0 {
+        return Vec::new();
+    }
+    let ids: Vec<i64> = db.exec(
+        "SELECT p.id FROM posts p JOIN threads t ON p.thread_id = t.id
+         WHERE t.id = :tid AND t.cyclical
+         ORDER BY p.id DESC LIMIT 18446744073709551615 OFFSET :keep",
+        params! { "tid" => thread_id, "keep" => board.cyclical_replies as u64 },
+    ).unwrap();
+    ids.into_iter().flat_map(|id| delete_post(db, id).unwrap_or_default()).collect()
+}
+
+// Remove a post's upload but keep the post. Returns the files to remove.
+pub fn delete_media(db: &mut PooledConn, id: i64) -> Vec<String> {
+    let mut files = Vec::new();
+    for table in ["threads", "posts"] {
+        let row: Option<(Option<String>, Option<String>)> = db.exec_first(
+            format!("SELECT media, thumb FROM {table} WHERE id = :id"),
+            params! { "id" => id },
+        ).unwrap();
+        if let Some((media, thumb)) = row {
+            db.exec_drop(
+                format!("UPDATE {table} SET media = NULL, thumb = NULL, thumb_width = NULL, thumb_height = NULL WHERE id = :id"),
+                params! { "id" => id },
+            ).unwrap();
+            files.extend(media.into_iter().chain(thumb));
+        }
+    }
+    files
+}
+
+// Where each of the given posts (threads or replies) lives. Ids that don't
+// exist are left out.
+pub fn resolve_posts(db: &mut PooledConn, ids: &[i64]) -> HashMap<i64, PostLocation> {
+    if ids.is_empty() {
+        return HashMap::new();
+    }
+    let placeholders = vec!["?"; ids.len()].join(", ");
+    let mut params: Vec<Value> = ids.iter().map(|&id| id.into()).collect();
+    params.extend(params.clone());
+    db.exec_map(
+        format!(
+            "SELECT id, board, id FROM threads WHERE id IN ({placeholders})
+             UNION ALL
+             SELECT p.id, t.board, t.id FROM posts p JOIN threads t ON p.thread_id = t.id WHERE p.id IN ({placeholders})"
+        ),
+        params,
+        |(id, board, thread_id): (i64, String, i64)| (id, PostLocation { board, thread_id }),
+    ).unwrap_or_default().into_iter().collect()
+}
+
+// Record which existing posts a new post quotes, for backlinks.
+fn save_quotes(db: &mut PooledConn, from_id: i64, board: &str, thread_id: i64, message: &str) {
+    let quoted = markup::quoted_posts(message, board);
+    let ids: Vec<i64> = quoted.iter().map(|(_, id)| *id).collect();
+    let found = resolve_posts(db, &ids);
+    let mut targets: Vec<i64> = quoted
+        .into_iter()
+        .filter(|(b, id)| *id != from_id && found.get(id).is_some_and(|loc| &loc.board == b))
+        .map(|(_, id)| id)
+        .collect();
+    targets.sort_unstable();
+    targets.dedup();
+    db.exec_batch(
+        "INSERT IGNORE INTO post_refs (from_id, from_board, from_thread, to_id) VALUES (:from_id, :board, :thread_id, :to_id)",
+        targets.iter().map(|to_id| params! { "from_id" => from_id, "board" => board, "thread_id" => thread_id, "to_id" => to_id }),
+    ).unwrap();
+}
+
+// Posts quoting each of the given posts, oldest first.
+fn get_backlinks(db: &mut PooledConn, ids: &[i64]) -> HashMap<i64, Vec<Backlink>> {
+    let placeholders = vec!["?"; ids.len()].join(", ");
+    let rows: Vec<(i64, i64, String, i64)> = db.exec(
+        format!("SELECT to_id, from_id, from_board, from_thread FROM post_refs WHERE to_id IN ({placeholders}) ORDER BY from_id"),
+        ids.to_vec(),
+    ).unwrap_or_default();
+    let mut backlinks: HashMap<i64, Vec<Backlink>> = HashMap::new();
+    for (to_id, id, board, thread_id) in rows {
+        backlinks.entry(to_id).or_default().push(Backlink { id, board, thread_id });
+    }
+    backlinks
+}
+
+// Run by the migration that creates post_refs, after ids were renumbered.
+pub fn backfill_post_refs(db: &mut PooledConn) {
+    let all: Vec<(i64, String, i64, String)> = db.query(
+        "SELECT id, board, id, message FROM threads
+         UNION ALL
+         SELECT p.id, t.board, t.id, p.message FROM posts p JOIN threads t ON p.thread_id = t.id",
+    ).unwrap();
+    for (id, board, thread_id, message) in all {
+        save_quotes(db, id, &board, thread_id, &message);
+    }
+}
+
+pub fn get_post_count(db: &mut PooledConn, thread_id: i64) -> usize {
     db.exec_first::<u64, _, _>(
         "SELECT COUNT(*) FROM posts WHERE thread_id = :tid",
         params! { "tid" => thread_id }
     ).unwrap_or(Some(0)).unwrap_or(0) as usize
 }
+
+#[derive(Debug, Clone, Default)]
+pub struct BoardStats {
+    pub threads: u64,
+    pub posts: u64,
+    pub last_post: Option<i64>,
+}
+
+// Thread count, post count (OPs included) and last post time for every board
+// with threads. Archived threads don't count.
+pub fn get_board_stats(db: &mut PooledConn) -> HashMap<String, BoardStats> {
+    db.query_map(
+        "SELECT t.board, COUNT(*), CAST(COUNT(*) + COALESCE(SUM(r.n), 0) AS UNSIGNED),
+                GREATEST(MAX(t.created), COALESCE(MAX(r.latest), 0))
+         FROM threads t
+         LEFT JOIN (SELECT thread_id, COUNT(*) AS n, MAX(created) AS latest FROM posts GROUP BY thread_id) r
+           ON r.thread_id = t.id
+         WHERE t.archived IS NULL
+         GROUP BY t.board",
+        |(board, threads, posts, last_post): (String, u64, u64, Option<i64>)| {
+            (board, BoardStats { threads, posts, last_post })
+        },
+    ).unwrap_or_default().into_iter().collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct RecentPost {
+    pub board: String,
+    pub thread_id: i64,
+    // None when the post is the thread's opening post.
+    pub post_id: Option<i64>,
+    pub subject: String,
+    pub preview: String,
+    pub media: Option<String>,
+    pub created: i64,
+}
+
+impl RecentPost {
+    // The post's own number: the thread id for an opening post.
+    pub fn id(&self) -> i64 {
+        self.post_id.unwrap_or(self.thread_id)
+    }
+}
+
+// The newest threads and replies across the given boards, newest first.
+// Archived threads are left out.
+pub fn get_recent_posts(db: &mut PooledConn, boards: &[String], n: usize) -> Vec<RecentPost> {
+    if boards.is_empty() {
+        return Vec::new();
+    }
+    let placeholders = vec!["?"; boards.len()].join(", ");
+    let mut params: Vec<Value> = boards.iter().map(|b| b.as_str().into()).collect();
+    params.extend(params.clone());
+    params.push((n as u64).into());
+    db.exec_map(
+        format!(
+            "SELECT board, id, NULL, subject, message, media, created FROM threads
+             WHERE board IN ({placeholders}) AND archived IS NULL
+             UNION ALL
+             SELECT t.board, t.id, p.id, t.subject, p.message, p.media, p.created
+             FROM posts p JOIN threads t ON p.thread_id = t.id
+             WHERE t.board IN ({placeholders}) AND t.archived IS NULL
+             ORDER BY created DESC LIMIT ?"
+        ),
+        params,
+        |(board, thread_id, post_id, subject, message, media, created): (String, i64, Option<i64>, String, String, Option<String>, i64)| {
+            RecentPost { board, thread_id, post_id, subject, preview: preview_of(&message, REPLY_PREVIEW_LENGTH), media, created }
+        },
+    ).unwrap_or_default()
+}
+
+// The given threads and replies, in no particular order. Ids that don't
+// exist are skipped.
+pub fn get_posts_by_id(db: &mut PooledConn, ids: &[i64]) -> Vec<RecentPost> {
+    if ids.is_empty() {
+        return Vec::new();
+    }
+    let placeholders = vec!["?"; ids.len()].join(", ");
+    let mut params: Vec<Value> = ids.iter().map(|id| (*id).into()).collect();
+    params.extend(params.clone());
+    db.exec_map(
+        format!(
+            "SELECT board, id, NULL, subject, message, media, created FROM threads WHERE id IN ({placeholders})
+             UNION ALL
+             SELECT t.board, t.id, p.id, t.subject, p.message, p.media, p.created
+             FROM posts p JOIN threads t ON p.thread_id = t.id WHERE p.id IN ({placeholders})"
+        ),
+        params,
+        |(board, thread_id, post_id, subject, message, media, created): (String, i64, Option<i64>, String, String, Option<String>, i64)| {
+            RecentPost { board, thread_id, post_id, subject, preview: preview_of(&message, REPLY_PREVIEW_LENGTH), media, created }
+        },
+    ).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::preview_of;
+
+    #[test]
+    fn short_messages_are_unchanged() {
+        assert_eq!(preview_of("1. e4 e5", 10), "1. e4 e5");
+        assert_eq!(preview_of("", 10), "");
+    }
+
+    #[test]
+    fn cuts_on_grapheme_boundaries() {
+        assert_eq!(preview_of("♔♕♖♗♘♙", 3), "♔♕♖…");
+        assert_eq!(preview_of("Réti, Nimzowitsch", 3), "Rét…");
+        // e + combining acute accent is one grapheme
+        assert_eq!(preview_of("Re\u{301}ti", 2), "Re\u{301}…");
+        assert_eq!(preview_of("👨‍👩‍👧👍🏽🇳🇴 family", 3), "👨‍👩‍👧👍🏽🇳🇴…");
+        assert_eq!(preview_of("国际象棋很有趣", 4), "国际象棋…");
+        assert_eq!(preview_of("チェスの定跡", 1), "チ…");
+    }
+
+    #[test]
+    fn collapses_whitespace() {
+        assert_eq!(preview_of("  Nf3\n\n\td5 \r\n c4  ", 100), "Nf3 d5 c4");
+        assert_eq!(preview_of("a    b", 2), "a…");
+    }
+
+    #[test]
+    fn strips_markup() {
+        assert_eq!(preview_of("**Bold** and *italic*", 100), "Bold and italic");
+        assert_eq!(preview_of("[spoiler]Qxh7#[/spoiler] wins", 100), "(spoiler) wins");
+        assert_eq!(preview_of("```\n1. d4 d5\n```\n>>12 agreed", 100), "1. d4 d5 >>12 agreed");
+        assert_eq!(preview_of("**国际象棋** 😀😀", 6), "国际象棋 😀…");
+    }
+}
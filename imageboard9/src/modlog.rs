@@ -0,0 +1,206 @@
+// ===== Moderation Log Settings =====
+pub const LOG_ENTRIES_PER_PAGE: usize = 50;
+// What can appear in the log: (action, label).
+pub const LOG_ACTIONS: &[(&str, &str)] = &[
+    ("delete", "Deleted a post"),
+    ("delete_media", "Deleted a file"),
+    ("ban", "Banned"),
+    ("unban", "Lifted a ban"),
+    ("dismiss_reports", "Dismissed reports"),
+    ("sticky", "Stickied a thread"),
+    ("unsticky", "Unstickied a thread"),
+    ("lock", "Locked a thread"),
+    ("unlock", "Unlocked a thread"),
+    ("cyclical", "Made a thread cyclical"),
+    ("uncyclical", "Made a thread non-cyclical"),
+    ("filter_add", "Added a filter"),
+    ("filter_delete", "Deleted a filter"),
+    ("board_add", "Added a board"),
+    ("board_edit", "Edited a board"),
+    ("board_delete", "Deleted a board"),
+];
+// ===== End Moderation Log Settings =====
+
+use crate::auth::Moderator;
+use crate::markup::PostLocation;
+use crate::models::{self, Post};
+use crate::templates::url_encode;
+use mysql::*;
+use mysql::prelude::*;
+use serde::Deserialize;
+
+// Every moderation action is appended to mod_log, with who did it and, for
+// deletions, a copy of what was deleted. Nothing here updates or deletes a
+// row. Entries never hold posters' addresses: the log outlives the IP
+// retention purge.
+
+// Who did it.
+pub enum Actor<'a> {
+    Moderator(&'a Moderator),
+    // `chess board ...` and other commands run on the server.
+    Console,
+    // A filter rule acting on its own.
+    Filter,
+}
+
+pub struct NewEntry<'a> {
+    pub action: &'a str,
+    pub board: Option<&'a str>,
+    // The post, ban or filter acted on.
+    pub target: Option<i64>,
+    // One line on what was done. Shown on the public log too, so keep
+    // names and addresses out of it.
+    pub details: String,
+    // What was deleted, for moderators only.
+    pub snapshot: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    pub id: i64,
+    pub created: i64,
+    // The moderator's name when they did it, "console" or "filter".
+    pub actor: String,
+    pub action: String,
+    pub board: Option<String>,
+    pub details: String,
+    pub snapshot: Option<String>,
+}
+
+impl LogEntry {
+    pub fn action_label(&self) -> &str {
+        LOG_ACTIONS
+            .iter()
+            .find(|(action, _)| *action == self.action)
+            .map_or(self.action.as_str(), |(_, label)| label)
+    }
+
+    // What the public log shows of the details: filter patterns are left
+    // out, since spammers would read them to get around the filters.
+    pub fn public_details(&self) -> Option<&str> {
+        (!self.action.starts_with("filter_")).then_some(self.details.as_str())
+    }
+}
+
+// Narrows the log view (from the query string); empty fields match
+// everything.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct LogFilter {
+    pub actor: String,
+    pub action: String,
+    pub board: String,
+    pub page: usize,
+}
+
+impl LogFilter {
+    // The filter and a page number as a URL query string, for page links.
+    pub fn query(&self, page: usize) -> String {
+        format!(
+            "?actor={}&action={}&board={}&page={page}",
+            url_encode(&self.actor),
+            url_encode(&self.action),
+            url_encode(&self.board)
+        )
+    }
+}
+
+pub fn record(db: &mut PooledConn, actor: Actor, entry: NewEntry) {
+    let (moderator_id, actor) = match actor {
+        Actor::Moderator(m) => (Some(m.id), m.username.as_str()),
+        Actor::Console => (None, "console"),
+        Actor::Filter => (None, "filter"),
+    };
+    db.exec_drop(
+        "INSERT INTO mod_log (created, moderator_id, actor, action, board, target, details, snapshot)
+         VALUES (:now, :moderator_id, :actor, :action, :board, :target, :details, :snapshot)",
+        params! {
+            "now" => chrono::Utc::now().timestamp(),
+            "moderator_id" => moderator_id,
+            "actor" => actor,
+            "action" => entry.action,
+            "board" => entry.board,
+            "target" => entry.target,
+            "details" => &entry.details,
+            "snapshot" => &entry.snapshot,
+        },
+    ).unwrap();
+}
+
+type LogRow = (i64, i64, String, String, Option<String>, String, Option<String>);
+
+// One page of the log, newest first, with the number of entries that match.
+// `boards` limits it to entries on those boards (for janitors); None shows
+// everything.
+pub fn get_log(db: &mut PooledConn, filter: &LogFilter, boards: Option<&[String]>) -> (Vec<LogEntry>, usize) {
+    let mut conditions = Vec::new();
+    let mut params: Vec<Value> = Vec::new();
+    for (column, value) in [("actor", &filter.actor), ("action", &filter.action), ("board", &filter.board)] {
+        if !value.is_empty() {
+            conditions.push(format!("{column} = ?"));
+            params.push(value.as_str().into());
+        }
+    }
+    if let Some(boards) = boards {
+        if boards.is_empty() {
+            return (Vec::new(), 0);
+        }
+        conditions.push(format!("board IN ({})", vec!["?"; boards.len()].join(", ")));
+        params.extend(boards.iter().map(|b| Value::from(b.as_str())));
+    }
+    let clause = if conditions.is_empty() { String::new() } else { format!("WHERE {}", conditions.join(" AND ")) };
+    let total: usize = db
+        .exec_first(format!("SELECT COUNT(*) FROM mod_log {clause}"), params.clone())
+        .unwrap()
+        .unwrap_or(0);
+    params.push((LOG_ENTRIES_PER_PAGE as u64).into());
+    params.push(((filter.page * LOG_ENTRIES_PER_PAGE) as u64).into());
+    let entries = db.exec_map(
+        format!(
+            "SELECT id, created, actor, action, board, details, snapshot FROM mod_log {clause}
+             ORDER BY id DESC LIMIT ? OFFSET ?"
+        ),
+        params,
+        |(id, created, actor, action, board, details, snapshot): LogRow| LogEntry {
+            id,
+            created,
+            actor,
+            action,
+            board,
+            details,
+            snapshot,
+        },
+    ).unwrap();
+    (entries, total)
+}
+
+// Everyone who appears in the log, for the filter form.
+pub fn get_actors(db: &mut PooledConn) -> Vec<String> {
+    db.query("SELECT DISTINCT actor FROM mod_log ORDER BY actor").unwrap()
+}
+
+fn post_text(post: &Post) -> String {
+    let trip = post.tripcode.as_deref().unwrap_or("");
+    let file = post.media.as_ref().map_or(String::new(), |m| format!("\n[file: {m}]"));
+    format!(">>{} {}{}\n{}{}", post.id, post.name, trip, post.message, file)
+}
+
+// A plain-text copy of post `id` as it is now: for a thread, the opening
+// post and every reply.
+pub fn snapshot_post(db: &mut PooledConn, location: &PostLocation, id: i64) -> Option<String> {
+    let (thread, posts) = models::get_thread(db, location.thread_id)?;
+    if location.thread_id != id {
+        return posts.iter().find(|p| p.id == id).map(post_text);
+    }
+    let trip = thread.tripcode.as_deref().unwrap_or("");
+    let file = thread.media.as_ref().map_or(String::new(), |m| format!("\n[file: {m}]"));
+    let mut text = format!(
+        ">>{} {}{} — {}\n{}{}",
+        thread.id, thread.name, trip, thread.subject, thread.message, file
+    );
+    for post in &posts {
+        text.push_str("\n\n");
+        text.push_str(&post_text(post));
+    }
+    Some(text)
+}
@@ -0,0 +1,228 @@
+// ===== Proof-of-Work Settings =====
+// How long a challenge can be solved and used after the page loads.
+pub const POW_TTL_SECS: i64 = 1800;
+// Posts per minute on a board before its difficulty starts to rise.
+pub const POW_BASELINE_POSTS_PER_MINUTE: usize = 10;
+// Extra bits added at most when posting spikes (each one doubles the work).
+pub const POW_MAX_EXTRA_BITS: u32 = 6;
+// The most a challenge ever asks for, base and extra bits together. Past
+// this a browser running static/pow.js would take minutes.
+pub const POW_MAX_TOTAL_BITS: u32 = 24;
+// ===== End Proof-of-Work Settings =====
+
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// A hashcash-style alternative to the image CAPTCHA. The server hands out
+// "<board>.<expires>.<bits>.<random>.<signature>", signed with
+// SESSION_SECRET so nothing is stored until it comes back. The browser
+// (static/pow.js) looks for a nonce such that SHA-256("<challenge>:<nonce>")
+// starts with `bits` zero bits. Each challenge is accepted once.
+
+pub struct PowChallenge {
+    pub token: String,
+    pub bits: u32,
+}
+
+#[derive(Default)]
+pub struct PowGuard {
+    // Challenges already used, until they would have expired anyway.
+    used: Mutex<HashMap<String, i64>>,
+    // Recent post times per board, for raising the difficulty.
+    recent: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+fn sign(secret: &str, payload: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(b"pow:");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+impl PowGuard {
+    // Extra bits for `board` from how many posts it had in the last minute:
+    // one more each time the rate doubles past the baseline.
+    fn extra_bits(&self, board: &str) -> u32 {
+        let mut recent = self.recent.lock().unwrap();
+        let Some(times) = recent.get_mut(board) else {
+            return 0;
+        };
+        while times.front().is_some_and(|t| t.elapsed() > Duration::from_secs(60)) {
+            times.pop_front();
+        }
+        let ratio = times.len() / POW_BASELINE_POSTS_PER_MINUTE;
+        if ratio == 0 {
+            0
+        } else {
+            (ratio.ilog2() + 1).min(POW_MAX_EXTRA_BITS)
+        }
+    }
+
+    // The difficulty of a new challenge for `board`: its base plus whatever
+    // the current posting rate adds, up to POW_MAX_TOTAL_BITS.
+    fn difficulty(&self, board: &str, base_bits: u32) -> u32 {
+        (base_bits + self.extra_bits(board)).min(POW_MAX_TOTAL_BITS)
+    }
+
+    // Count a post on `board` towards its rate.
+    pub fn record_post(&self, board: &str) {
+        let mut recent = self.recent.lock().unwrap();
+        let times = recent.entry(board.to_string()).or_default();
+        times.push_back(Instant::now());
+        // Enough to reach the top difficulty; older entries add nothing.
+        let cap = POW_BASELINE_POSTS_PER_MINUTE << POW_MAX_EXTRA_BITS;
+        while times.len() > cap {
+            times.pop_front();
+        }
+    }
+
+    // A new challenge for `board`, at the difficulty its base and current
+    // posting rate call for.
+    pub fn issue(&self, secret: &str, board: &str, base_bits: u32) -> PowChallenge {
+        let bits = self.difficulty(board, base_bits);
+        let expires = chrono::Utc::now().timestamp() + POW_TTL_SECS;
+        let random: [u8; 12] = rand::thread_rng().gen();
+        let random = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(random);
+        let payload = format!("{board}.{expires}.{bits}.{random}");
+        let sig = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(sign(secret, &payload).finalize().into_bytes());
+        PowChallenge { token: format!("{payload}.{sig}"), bits }
+    }
+
+    // Whether `nonce` solves a challenge this server issued for `board`
+    // that hasn't expired or been used. A good answer uses it up.
+    pub fn verify(&self, secret: &str, board: &str, token: &str, nonce: &str) -> bool {
+        let Some((payload, sig)) = token.rsplit_once('.') else {
+            return false;
+        };
+        let mut parts = payload.split('.');
+        let (Some(for_board), Some(expires), Some(bits)) = (parts.next(), parts.next(), parts.next()) else {
+            return false;
+        };
+        let (Ok(expires), Ok(bits)) = (expires.parse::<i64>(), bits.parse::<u32>()) else {
+            return false;
+        };
+        let Ok(sig) = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(sig) else {
+            return false;
+        };
+        let now = chrono::Utc::now().timestamp();
+        if for_board != board || expires < now || sign(secret, payload).verify_slice(&sig).is_err() {
+            return false;
+        }
+        if nonce.is_empty() || nonce.len() > 32 {
+            return false;
+        }
+        let hash = Sha256::digest(format!("{token}:{nonce}").as_bytes());
+        if leading_zero_bits(&hash) < bits {
+            return false;
+        }
+        let mut used = self.used.lock().unwrap();
+        used.retain(|_, expires| *expires >= now);
+        used.insert(token.to_string(), expires).is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "test secret";
+
+    // A nonce whose hash with `token` has at least (or, with `enough`
+    // false, fewer than) `bits` leading zero bits.
+    fn nonce(token: &str, bits: u32, enough: bool) -> String {
+        (0u64..)
+            .map(|n| n.to_string())
+            .find(|n| {
+                let hash = Sha256::digest(format!("{token}:{n}").as_bytes());
+                (leading_zero_bits(&hash) >= bits) == enough
+            })
+            .unwrap()
+    }
+
+    // A challenge signed with SECRET, expiring at `expires`.
+    fn token(board: &str, expires: i64, bits: u32) -> String {
+        let payload = format!("{board}.{expires}.{bits}.random");
+        let sig = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(sign(SECRET, &payload).finalize().into_bytes());
+        format!("{payload}.{sig}")
+    }
+
+    #[test]
+    fn solved_challenge_is_accepted_once() {
+        let guard = PowGuard::default();
+        let challenge = guard.issue(SECRET, "chess", 4);
+        assert_eq!(challenge.bits, 4);
+        let answer = nonce(&challenge.token, 4, true);
+        assert!(guard.verify(SECRET, "chess", &challenge.token, &answer));
+        assert!(!guard.verify(SECRET, "chess", &challenge.token, &answer));
+    }
+
+    #[test]
+    fn too_little_work_is_rejected() {
+        let guard = PowGuard::default();
+        let challenge = guard.issue(SECRET, "chess", 8);
+        let answer = nonce(&challenge.token, 8, false);
+        assert!(!guard.verify(SECRET, "chess", &challenge.token, &answer));
+        assert!(!guard.verify(SECRET, "chess", &challenge.token, ""));
+    }
+
+    #[test]
+    fn challenge_is_bound_to_its_board() {
+        let guard = PowGuard::default();
+        let challenge = guard.issue(SECRET, "chess", 4);
+        let answer = nonce(&challenge.token, 4, true);
+        assert!(!guard.verify(SECRET, "other", &challenge.token, &answer));
+        // Turned away there, it still works where it was issued.
+        assert!(guard.verify(SECRET, "chess", &challenge.token, &answer));
+    }
+
+    #[test]
+    fn expired_challenge_is_rejected() {
+        let guard = PowGuard::default();
+        let now = chrono::Utc::now().timestamp();
+        let expired = token("chess", now - 1, 1);
+        assert!(!guard.verify(SECRET, "chess", &expired, &nonce(&expired, 1, true)));
+        let live = token("chess", now + 60, 1);
+        assert!(guard.verify(SECRET, "chess", &live, &nonce(&live, 1, true)));
+    }
+
+    #[test]
+    fn forged_or_altered_challenge_is_rejected() {
+        let guard = PowGuard::default();
+        let challenge = guard.issue(SECRET, "chess", 4);
+        let answer = nonce(&challenge.token, 4, true);
+        assert!(!guard.verify("other secret", "chess", &challenge.token, &answer));
+        // Lowering the difficulty breaks the signature.
+        let easier = challenge.token.replacen(".4.", ".0.", 1);
+        assert!(!guard.verify(SECRET, "chess", &easier, &nonce(&easier, 0, true)));
+        assert!(!guard.verify(SECRET, "chess", "not a challenge", &answer));
+    }
+
+    #[test]
+    fn difficulty_is_capped() {
+        let guard = PowGuard::default();
+        assert_eq!(guard.difficulty("chess", 16), 16);
+        assert_eq!(guard.difficulty("chess", 32), POW_MAX_TOTAL_BITS);
+        for _ in 0..POW_BASELINE_POSTS_PER_MINUTE << POW_MAX_EXTRA_BITS {
+            guard.record_post("chess");
+        }
+        assert_eq!(guard.difficulty("chess", 16), 16 + POW_MAX_EXTRA_BITS);
+        assert_eq!(guard.difficulty("chess", POW_MAX_TOTAL_BITS - 1), POW_MAX_TOTAL_BITS);
+        assert_eq!(guard.difficulty("other", 16), 16);
+    }
+}
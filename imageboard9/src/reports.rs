@@ -0,0 +1,135 @@
+// ===== Report Settings =====
+pub const MAX_REPORT_COMMENT_LEN: usize = 1000;
+// What a report can be about: (value, label).
+pub const REPORT_CATEGORIES: &[(&str, &str)] = &[
+    ("spam", "Spam or advertising"),
+    ("rules", "Breaks the board rules"),
+    ("offtopic", "Off topic"),
+    ("abuse", "Harassment or abuse"),
+    ("illegal", "Illegal content"),
+    ("other", "Something else"),
+];
+// ===== End Report Settings =====
+
+use crate::config::Config;
+use crate::iplog;
+use crate::models::{self, RecentPost};
+use mysql::*;
+use mysql::prelude::*;
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+// Reports keep the reporter's salted address hash (see iplog), only to stop
+// one address reporting the same post twice.
+
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub category: String,
+    pub comment: String,
+    pub created: i64,
+}
+
+impl Report {
+    pub fn category_label(&self) -> &'static str {
+        REPORT_CATEGORIES
+            .iter()
+            .find(|(value, _)| *value == self.category)
+            .map_or("Something else", |(_, label)| label)
+    }
+}
+
+// The open reports on one post.
+pub struct ReportGroup {
+    pub post: RecentPost,
+    pub reports: Vec<Report>,
+}
+
+pub fn valid_category(category: &str) -> bool {
+    REPORT_CATEGORIES.iter().any(|(value, _)| *value == category)
+}
+
+// File a report on post `post_id` from `ip`. Returns false if that address
+// has already reported it.
+pub fn insert_report(
+    db: &mut PooledConn,
+    config: &Config,
+    post_id: i64,
+    board: &str,
+    category: &str,
+    comment: &str,
+    ip: IpAddr,
+) -> bool {
+    // The address may have been hashed with an older salt last time.
+    let salts = iplog::get_salts(db);
+    let earlier: Vec<(String, i64)> = db.exec(
+        "SELECT ip_hash, ip_salt FROM reports WHERE post_id = :post_id AND ip_hash IS NOT NULL",
+        params! { "post_id" => post_id },
+    ).unwrap();
+    let already = earlier
+        .iter()
+        .any(|(hash, salt_id)| iplog::hash_matches(&salts, ip, hash, *salt_id));
+    if already {
+        return false;
+    }
+    let stored = iplog::stored_ip(db, config, ip);
+    db.exec_drop(
+        "INSERT IGNORE INTO reports (post_id, board, category, comment, ip_hash, ip_salt, created)
+         VALUES (:post_id, :board, :category, :comment, :hash, :salt_id, :now)",
+        params! {
+            "post_id" => post_id,
+            "board" => board,
+            "category" => category,
+            "comment" => comment,
+            "hash" => &stored.hash,
+            "salt_id" => stored.salt_id,
+            "now" => chrono::Utc::now().timestamp(),
+        },
+    ).unwrap();
+    db.affected_rows() > 0
+}
+
+// Open reports on the given boards, grouped by post: most reported first,
+// then most recently reported.
+pub fn get_report_groups(db: &mut PooledConn, boards: &[String]) -> Vec<ReportGroup> {
+    if boards.is_empty() {
+        return Vec::new();
+    }
+    let placeholders = vec!["?"; boards.len()].join(", ");
+    let params: Vec<Value> = boards.iter().map(|b| b.as_str().into()).collect();
+    let rows: Vec<(i64, String, String, i64)> = db.exec(
+        format!(
+            "SELECT post_id, category, comment, created FROM reports
+             WHERE board IN ({placeholders}) ORDER BY created DESC"
+        ),
+        params,
+    ).unwrap();
+    let mut by_post: HashMap<i64, Vec<Report>> = HashMap::new();
+    for (post_id, category, comment, created) in rows {
+        by_post.entry(post_id).or_default().push(Report { category, comment, created });
+    }
+    let ids: Vec<i64> = by_post.keys().copied().collect();
+    let mut groups: Vec<ReportGroup> = models::get_posts_by_id(db, &ids)
+        .into_iter()
+        .filter_map(|post| Some(ReportGroup { reports: by_post.remove(&post.id())?, post }))
+        .collect();
+    groups.sort_by_key(|g| (std::cmp::Reverse(g.reports.len()), std::cmp::Reverse(g.reports[0].created)));
+    groups
+}
+
+// How many posts on the given boards have open reports.
+pub fn count_reported_posts(db: &mut PooledConn, boards: &[String]) -> usize {
+    if boards.is_empty() {
+        return 0;
+    }
+    let placeholders = vec!["?"; boards.len()].join(", ");
+    let params: Vec<Value> = boards.iter().map(|b| b.as_str().into()).collect();
+    db.exec_first::<usize, _, _>(
+        format!("SELECT COUNT(DISTINCT post_id) FROM reports WHERE board IN ({placeholders})"),
+        params,
+    ).unwrap().unwrap_or(0)
+}
+
+// Close every report on a post.
+pub fn dismiss_reports(db: &mut PooledConn, post_id: i64) {
+    db.exec_drop("DELETE FROM reports WHERE post_id = :post_id", params! { "post_id" => post_id }).unwrap();
+}
@@ -0,0 +1,102 @@
+// ===== Self-Deletion Settings =====
+// Remembers the poster's deletion password between posts, as TinyIB does.
+pub const PASSWORD_COOKIE: &str = "post_password";
+pub const PASSWORD_COOKIE_DAYS: i64 = 365;
+pub const MAX_PASSWORD_LEN: usize = 128;
+// ===== End Self-Deletion Settings =====
+
+use crate::auth;
+use crate::markup::PostLocation;
+use crate::models;
+use crate::state::AppState;
+use axum::http::HeaderMap;
+use base64::Engine;
+use mysql::*;
+use mysql::prelude::*;
+
+// Every post is stored with an Argon2 hash of a deletion password. The poster
+// can type one into the post form; otherwise the one in their cookie is
+// used, and a browser without the cookie is given a random one. Posters can
+// delete their own posts (or just the files) with it for
+// SELF_DELETE_MINUTES after posting.
+
+// The password from a cookie. It is stored base64-encoded, since a typed one
+// can hold characters a cookie can't.
+fn from_cookie(headers: &HeaderMap) -> Option<String> {
+    let encoded = auth::cookie(headers, PASSWORD_COOKIE)?;
+    let bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(encoded).ok()?;
+    String::from_utf8(bytes).ok().filter(|p| !p.is_empty())
+}
+
+// The password for a new post: the one typed in, or the cookie's, or a new
+// random one.
+pub fn choose_password(headers: &HeaderMap, typed: &str) -> String {
+    let typed = typed.trim();
+    if !typed.is_empty() {
+        return typed.to_string();
+    }
+    from_cookie(headers).unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string())
+}
+
+// The password a delete form gives: the one typed in, or the cookie's.
+pub fn given_password(headers: &HeaderMap, typed: &str) -> Option<String> {
+    let typed = typed.trim();
+    if typed.is_empty() {
+        from_cookie(headers)
+    } else {
+        Some(typed.to_string())
+    }
+}
+
+// The Set-Cookie value that remembers `password` for the next post.
+pub fn password_cookie(state: &AppState, password: &str) -> String {
+    let encoded = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(password);
+    let max_age = PASSWORD_COOKIE_DAYS * 86400;
+    let secure = if state.config.secure_cookies { "; Secure" } else { "" };
+    format!("{PASSWORD_COOKIE}={encoded}; Path=/; Max-Age={max_age}; HttpOnly; SameSite=Lax{secure}")
+}
+
+// The deletion password hash and creation time of a thread or reply.
+fn get_post_password(db: &mut PooledConn, id: i64) -> Option<(Option<String>, i64)> {
+    db.exec_first(
+        "SELECT password_hash, created FROM threads WHERE id = :id
+         UNION ALL
+         SELECT password_hash, created FROM posts WHERE id = :id",
+        params! { "id" => id },
+    ).unwrap()
+}
+
+// Delete post `id` on `board`, or only its file, if the password matches, it
+// was made less than `window_minutes` ago and its thread isn't archived.
+// Returns where the post was and the upload files to remove, or why it
+// wasn't deleted.
+pub fn delete_own(
+    db: &mut PooledConn,
+    board: &str,
+    id: i64,
+    password: &str,
+    file_only: bool,
+    window_minutes: i64,
+) -> Result<(PostLocation, Vec<String>), &'static str> {
+    let location = models::resolve_posts(db, &[id])
+        .remove(&id)
+        .filter(|loc| loc.board == board)
+        .ok_or("No such post.")?;
+    let archived = models::get_thread_status(db, location.thread_id).is_some_and(|(_, _, archived)| archived);
+    if archived {
+        return Err("This thread is archived. Its posts can't be deleted.");
+    }
+    let (hash, created) = get_post_password(db, id).ok_or("No such post.")?;
+    if created < chrono::Utc::now().timestamp() - window_minutes * 60 {
+        return Err("This post is too old to delete yourself. Ask a moderator.");
+    }
+    if !hash.is_some_and(|h| auth::verify_password(&h, password)) {
+        return Err("Wrong password.");
+    }
+    let files = if file_only {
+        models::delete_media(db, id)
+    } else {
+        models::delete_post(db, id).unwrap_or_default()
+    };
+    Ok((location, files))
+}
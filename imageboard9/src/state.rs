@@ -0,0 +1,18 @@
+use crate::boards::BoardRegistry;
+use crate::captcha::CaptchaStore;
+use crate::config::Config;
+use crate::db::Db;
+use crate::flood::FloodControl;
+use crate::pow::PowGuard;
+use std::sync::Arc;
+
+// Shared state handed to every handler through axum's `State` extractor.
+#[derive(Clone)]
+pub struct AppState {
+    pub db: Db,
+    pub boards: Arc<BoardRegistry>,
+    pub config: Arc<Config>,
+    pub flood: Arc<FloodControl>,
+    pub captcha: Arc<CaptchaStore>,
+    pub pow: Arc<PowGuard>,
+}
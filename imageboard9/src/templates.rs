@@ -1,6 +1,14 @@
 use askama::Template;
-use crate::models::{Thread, Post};
+use crate::models::{BoardStats, Post, RecentPost, Thread};
+use crate::archive::{ArchiveQuery, ArchivedThread};
+use crate::auth::Moderator;
+use crate::bans::Ban;
 use crate::boards::BoardDef;
+use crate::filters::{Filter, FilterAction};
+use crate::modlog::{LogEntry, LogFilter};
+use crate::pow::PowChallenge;
+use crate::reports::ReportGroup;
+use std::borrow::Borrow;
 use std::collections::HashMap;
 
 #[derive(Template)]
@@ -12,6 +20,8 @@ pub struct Board {
     pub last_replies: HashMap<i64, Vec<Post>>,
     pub page: usize,
     pub page_count: usize,
+    // The new-thread CAPTCHA, when this board asks for one.
+    pub challenge: PostChallenge,
 }
 
 #[derive(Template)]
@@ -19,6 +29,43 @@ pub struct Board {
 pub struct ThreadView {
     pub thread: Thread,
     pub posts: Vec<Post>,
+    // Show delete buttons: a moderator of this board is logged in.
+    pub moderator: bool,
+    pub can_ban: bool,
+    // Posts made after this time can still be deleted by their poster.
+    pub self_delete_after: i64,
+    // How many replies the board lets a cyclical thread keep.
+    pub cyclical_replies: i32,
+    // The reply CAPTCHA, when this board asks for one.
+    pub challenge: PostChallenge,
+}
+
+// A board's archive index: one page of archived threads, and the search
+// that found them.
+#[derive(Template)]
+#[template(path = "archive.html")]
+pub struct ArchiveIndex {
+    pub board: BoardDef,
+    pub threads: Vec<ArchivedThread>,
+    pub query: ArchiveQuery,
+    pub page_count: usize,
+}
+
+// What a post form must answer: an image CAPTCHA token, a proof-of-work
+// challenge, or (where the board offers both) either one.
+#[derive(Default)]
+pub struct PostChallenge {
+    pub captcha: Option<String>,
+    pub pow: Option<PowChallenge>,
+}
+
+#[derive(Template)]
+#[template(path = "banned.html")]
+pub struct Banned {
+    pub ban: Ban,
+    pub ip: String,
+    pub created: String,
+    pub expires: String,
 }
 
 #[derive(Template)]
@@ -27,3 +74,130 @@ pub struct ErrorPage {
     pub message: String,
     pub back_url: String,
 }
+
+pub struct BoardSummary {
+    pub board: BoardDef,
+    pub stats: BoardStats,
+    pub last_post: String,
+}
+
+pub struct RecentItem {
+    pub post: RecentPost,
+    pub when: String,
+}
+
+#[derive(Template)]
+#[template(path = "landing.html")]
+pub struct Landing {
+    pub boards: Vec<BoardSummary>,
+    pub recent: Vec<RecentItem>,
+}
+
+#[derive(Template)]
+#[template(path = "admin_login.html")]
+pub struct AdminLogin {
+    pub error: Option<String>,
+}
+
+pub struct BanItem {
+    pub ban: Ban,
+    pub created: String,
+    pub expires: String,
+}
+
+#[derive(Template)]
+#[template(path = "admin.html")]
+pub struct AdminDashboard {
+    pub moderator: Moderator,
+    pub boards: Vec<BoardDef>,
+    pub recent: Vec<RecentItem>,
+    pub bans: Vec<BanItem>,
+    // Posts with open reports on the moderator's boards.
+    pub reported_posts: usize,
+}
+
+#[derive(Template)]
+#[template(path = "admin_ban.html")]
+pub struct AdminBan {
+    pub post_id: i64,
+    pub board: String,
+    pub thread_id: i64,
+    // The post's recorded address, to fill in the form.
+    pub ip: Option<String>,
+    // Whether the post's hashed address is still kept, so the range can be left empty.
+    pub has_hash: bool,
+}
+
+#[derive(Template)]
+#[template(path = "report.html")]
+pub struct ReportPage {
+    pub post: RecentPost,
+    pub categories: &'static [(&'static str, &'static str)],
+    // Set once the report has been sent: what to tell the reporter.
+    pub done: Option<String>,
+}
+
+pub struct ReportGroupItem {
+    pub group: ReportGroup,
+    pub latest: String,
+}
+
+#[derive(Template)]
+#[template(path = "admin_reports.html")]
+pub struct AdminReports {
+    pub moderator: Moderator,
+    pub groups: Vec<ReportGroupItem>,
+}
+
+pub struct FilterItem {
+    pub filter: Filter,
+    pub created: String,
+    // Whether the logged-in moderator may delete it.
+    pub can_delete: bool,
+}
+
+#[derive(Template)]
+#[template(path = "admin_filters.html")]
+pub struct AdminFilters {
+    pub moderator: Moderator,
+    // Boards the moderator can add rules to.
+    pub boards: Vec<BoardDef>,
+    pub filters: Vec<FilterItem>,
+}
+
+pub struct LogItem {
+    pub entry: LogEntry,
+    pub created: String,
+}
+
+// The moderation log: for a logged-in moderator, or the public view (no
+// moderator), which leaves out who did what and what was deleted.
+#[derive(Template)]
+#[template(path = "mod_log.html")]
+pub struct ModLog {
+    pub moderator: Option<Moderator>,
+    pub entries: Vec<LogItem>,
+    pub filter: LogFilter,
+    // Choices for the filter form.
+    pub actors: Vec<String>,
+    pub actions: &'static [(&'static str, &'static str)],
+    pub boards: Vec<String>,
+    pub page_count: usize,
+}
+
+// Takes a reference too, since that is what templates hand to functions.
+pub fn format_time(ts: impl Borrow<i64>) -> String {
+    chrono::DateTime::from_timestamp(*ts.borrow(), 0)
+        .map(|t| t.format("%Y-%m-%d %H:%M UTC").to_string())
+        .unwrap_or_default()
+}
+
+// Percent-encode a value for a URL query string.
+pub fn url_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
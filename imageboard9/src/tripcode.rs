@@ -0,0 +1,64 @@
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub const DEFAULT_NAME: &str = "Anonymous";
+pub const MAX_NAME_LEN: usize = 64;
+
+// Split a name field into the display name and its tripcode.
+//   "Name#password"  -> classic tripcode "!xxxxxxxxxx" (crypt(3), same as other imageboards)
+//   "Name##password" -> secure tripcode "!!xxxxxxxxxx" (HMAC-SHA256 with the server secret)
+// An empty name becomes DEFAULT_NAME.
+pub fn parse_name(input: &str, secret: &str) -> (String, Option<String>) {
+    let (name, trip) = match input.split_once('#') {
+        Some((name, rest)) => {
+            let trip = match rest.strip_prefix('#') {
+                Some(pass) if !pass.is_empty() => Some(secure_tripcode(pass, secret)),
+                Some(_) => None,
+                None if !rest.is_empty() => classic_tripcode(rest),
+                None => None,
+            };
+            (name, trip)
+        }
+        None => (input, None),
+    };
+    let name = name.trim();
+    let name = if name.is_empty() { DEFAULT_NAME } else { name };
+    (name.to_string(), trip)
+}
+
+fn classic_tripcode(pass: &str) -> Option<String> {
+    let salt: String = format!("{pass}H.")
+        .chars()
+        .skip(1)
+        .take(2)
+        .map(|c| match c {
+            ':' => 'A',
+            ';' => 'B',
+            '<' => 'C',
+            '=' => 'D',
+            '>' => 'E',
+            '?' => 'F',
+            '@' => 'G',
+            '[' => 'a',
+            '\\' => 'b',
+            ']' => 'c',
+            '^' => 'd',
+            '_' => 'e',
+            '`' => 'f',
+            '.'..='z' => c,
+            _ => '.',
+        })
+        .collect();
+    // Deprecated for password storage, but classic tripcodes are defined by it.
+    #[allow(deprecated)]
+    let hash = pwhash::unix_crypt::hash_with(&salt, pass).ok()?;
+    Some(format!("!{}", &hash[hash.len() - 10..]))
+}
+
+fn secure_tripcode(pass: &str, secret: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(pass.as_bytes());
+    let digest = base64::engine::general_purpose::STANDARD.encode(mac.finalize().into_bytes());
+    format!("!!{}", &digest[..10])
+}